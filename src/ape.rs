@@ -0,0 +1,352 @@
+use std::convert::TryInto;
+
+use crate::backend::{AudioTag, Backend, Field};
+use crate::tagger::{MusicFileType, TaggerConfig};
+use crate::mapping::TagKeys;
+use crate::art::Art;
+use crate::properties::{self, AudioProperties};
+
+const PREAMBLE: &[u8] = b"APETAGEX";
+const COVER_KEY: &str = "Cover Art (Front)";
+//Item flags
+const FLAG_BINARY: u32 = 1 << 1;
+const FLAG_HAS_HEADER: u32 = 1 << 31;
+const FLAG_IS_HEADER: u32 = 1 << 29;
+
+//Vorbis name to APE item key, others are kept as is
+const KEYS: [(&str, &str); 6] = [
+    ("TITLE", "Title"),
+    ("ARTIST", "Artist"),
+    ("ALBUM", "Album"),
+    ("DATE", "Year"),
+    ("GENRE", "Genre"),
+    ("TRACKNUMBER", "Track")
+];
+
+//What to do with APEv2 tags at end of MP3 files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApeMode {
+    Ignore,
+    //Prefer APE title/artist for matching
+    Read,
+    //Read and write Discogs fields also to existing APE tag
+    Update,
+    Strip
+}
+
+impl ApeMode {
+    pub fn parse(value: &str) -> Result<ApeMode, String> {
+        match value.to_lowercase().as_str() {
+            "" | "ignore" => Ok(ApeMode::Ignore),
+            "read" => Ok(ApeMode::Read),
+            "update" => Ok(ApeMode::Update),
+            "strip" => Ok(ApeMode::Strip),
+            _ => Err(format!("Invalid APE mode: {}", value))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ApeItem {
+    key: String,
+    flags: u32,
+    value: Vec<u8>
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ApeTag {
+    items: Vec<ApeItem>
+}
+
+impl ApeTag {
+    //Read APEv2 tag from end of file (before ID3v1), None if there is no tag
+    pub fn read_from_path(path: &str) -> Result<Option<ApeTag>, Box<dyn std::error::Error>> {
+        let data = std::fs::read(path)?;
+        let (start, end) = match locate(&data)? {
+            Some(r) => r,
+            None => return Ok(None)
+        };
+        let mut tag = ApeTag::default();
+        //Skip header
+        let mut pos = match start + 32 <= end && data[start..].starts_with(PREAMBLE) {
+            true => start + 32,
+            false => start
+        };
+        let items_end = end - 32;
+        while pos + 8 < items_end {
+            let size = read_u32(&data, pos) as usize;
+            let flags = read_u32(&data, pos + 4);
+            let key_end = data[pos+8..items_end].iter().position(|b| *b == 0).ok_or("Invalid APE item!")? + pos + 8;
+            let value_end = key_end + 1 + size;
+            if value_end > items_end {
+                return Err("Invalid APE item size!".into());
+            }
+            tag.items.push(ApeItem {
+                key: String::from_utf8_lossy(&data[pos+8..key_end]).to_string(),
+                flags,
+                value: data[key_end+1..value_end].to_vec()
+            });
+            pos = value_end;
+        }
+        Ok(Some(tag))
+    }
+
+    //Replace existing tag, keeps ID3v1
+    pub fn write_to_path(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let data = std::fs::read(path)?;
+        let (start, end) = locate(&data)?.unwrap_or_else(|| {
+            let end = id3v1_start(&data);
+            (end, end)
+        });
+        let mut out = data[..start].to_vec();
+        out.extend(self.to_bytes());
+        out.extend(&data[end..]);
+        std::fs::write(path, out)?;
+        Ok(())
+    }
+
+    //Strip APE tag from file, returns false if there was none
+    pub fn remove_from_path(path: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let data = std::fs::read(path)?;
+        match locate(&data)? {
+            Some((start, end)) => {
+                let mut out = data[..start].to_vec();
+                out.extend(&data[end..]);
+                std::fs::write(path, out)?;
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+
+    //Text values, keys are case insensitive
+    pub fn get(&self, key: &str) -> Option<Vec<String>> {
+        self.items.iter()
+            .find(|i| i.key.eq_ignore_ascii_case(key) && i.flags & 0b110 == 0)
+            .map(|i| String::from_utf8_lossy(&i.value).split('\0').map(String::from).collect())
+    }
+
+    //Empty values = remove item
+    pub fn set(&mut self, key: &str, values: Vec<String>) {
+        match values.is_empty() {
+            true => self.items.retain(|i| !i.key.eq_ignore_ascii_case(key)),
+            false => self.set_item(key, 0, values.join("\0").into_bytes())
+        }
+    }
+
+    fn set_item(&mut self, key: &str, flags: u32, value: Vec<u8>) {
+        match self.items.iter_mut().find(|i| i.key.eq_ignore_ascii_case(key)) {
+            Some(item) => {
+                item.flags = flags;
+                item.value = value;
+            },
+            None => self.items.push(ApeItem { key: key.to_owned(), flags, value })
+        }
+    }
+
+    //Field using Vorbis names, common ones have own APE key
+    pub fn get_field(&self, field: &Field, keys: &TagKeys) -> Option<Vec<String>> {
+        self.get(&ape_key(field, keys)?)
+    }
+
+    pub fn set_field(&mut self, field: &Field, values: Vec<String>, keys: &TagKeys) {
+        if let Some(key) = ape_key(field, keys) {
+            self.set(&key, values);
+        }
+    }
+
+    pub fn has_art(&self) -> bool {
+        self.items.iter().any(|i| i.key.eq_ignore_ascii_case(COVER_KEY))
+    }
+
+    pub fn remove_art(&mut self) {
+        self.items.retain(|i| !i.key.eq_ignore_ascii_case(COVER_KEY));
+    }
+
+    //Binary item with filename
+    pub fn set_art(&mut self, art: Art) {
+        let mut value = format!("cover.{}\0", art.mime.replace("image/", "")).into_bytes();
+        value.extend(art.data);
+        self.set_item(COVER_KEY, FLAG_BINARY, value);
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut items = vec![];
+        for item in &self.items {
+            items.extend(&(item.value.len() as u32).to_le_bytes());
+            items.extend(&item.flags.to_le_bytes());
+            items.extend(item.key.as_bytes());
+            items.push(0);
+            items.extend(&item.value);
+        }
+        //Size includes footer, but not header
+        let size = items.len() as u32 + 32;
+        let mut out = header(size, self.items.len() as u32, FLAG_HAS_HEADER | FLAG_IS_HEADER);
+        out.extend(items);
+        out.extend(header(size, self.items.len() as u32, FLAG_HAS_HEADER));
+        out
+    }
+}
+
+fn header(size: u32, count: u32, flags: u32) -> Vec<u8> {
+    let mut out = PREAMBLE.to_vec();
+    out.extend(&2000u32.to_le_bytes());
+    out.extend(&size.to_le_bytes());
+    out.extend(&count.to_le_bytes());
+    out.extend(&flags.to_le_bytes());
+    out.extend(&[0u8; 8]);
+    out
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos+4].try_into().unwrap())
+}
+
+//Start of ID3v1 tag or end of file
+pub fn id3v1_start(data: &[u8]) -> usize {
+    match data.len() >= 128 && data[data.len()-128..].starts_with(b"TAG") {
+        true => data.len() - 128,
+        false => data.len()
+    }
+}
+
+//Range of APE tag including header and footer
+pub fn locate(data: &[u8]) -> Result<Option<(usize, usize)>, Box<dyn std::error::Error>> {
+    let end = id3v1_start(data);
+    if end < 32 || !data[end-32..].starts_with(PREAMBLE) {
+        return Ok(None);
+    }
+    let footer = end - 32;
+    let size = read_u32(data, footer + 12) as usize;
+    let flags = read_u32(data, footer + 20);
+    let header = if flags & FLAG_HAS_HEADER != 0 { 32 } else { 0 };
+    if size < 32 || size + header > end {
+        return Err("Invalid APE tag size!".into());
+    }
+    Ok(Some((end - size - header, end)))
+}
+
+fn ape_key(field: &Field, keys: &TagKeys) -> Option<String> {
+    let key = field.vorbis_key(keys)?;
+    Some(KEYS.iter().find(|(v, _)| *v == key).map(|(_, a)| a.to_string()).unwrap_or(key))
+}
+
+//WavPack / Monkey's Audio
+pub struct ApeBackend;
+
+impl Backend for ApeBackend {
+    fn file_type(&self) -> MusicFileType {
+        MusicFileType::APE
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &[".wv", ".ape"]
+    }
+
+    fn detect(&self, header: &[u8]) -> bool {
+        header.starts_with(b"wvpk") || header.starts_with(b"MAC ")
+    }
+
+    fn read(&self, path: &str, config: &TaggerConfig) -> Result<Box<dyn AudioTag>, Box<dyn std::error::Error>> {
+        Ok(Box::new(ApeFile {
+            tag: ApeTag::read_from_path(path)?.unwrap_or_default(),
+            keys: config.keys.clone()
+        }))
+    }
+
+    fn properties(&self, path: &str) -> Result<AudioProperties, Box<dyn std::error::Error>> {
+        properties::ape(path)
+    }
+}
+
+struct ApeFile {
+    tag: ApeTag,
+    keys: TagKeys
+}
+
+impl AudioTag for ApeFile {
+    fn get(&self, field: &Field) -> Option<Vec<String>> {
+        self.tag.get_field(field, &self.keys)
+    }
+
+    fn set(&mut self, field: &Field, values: Vec<String>) {
+        self.tag.set_field(field, values, &self.keys);
+    }
+
+    fn has_art(&self) -> bool {
+        self.tag.has_art()
+    }
+
+    fn set_art(&mut self, art: Art) {
+        self.tag.set_art(art);
+    }
+
+    fn remove_art(&mut self) {
+        self.tag.remove_art();
+    }
+
+    fn save(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.tag.write_to_path(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn id3v1() -> Vec<u8> {
+        let mut data = b"TAG".to_vec();
+        data.resize(128, 0);
+        data
+    }
+
+    #[test]
+    fn locate_tag_before_id3v1() {
+        let mut tag = ApeTag::default();
+        tag.set("Title", vec![String::from("Song")]);
+        let bytes = tag.to_bytes();
+        let mut data = b"AUDIO".to_vec();
+        data.extend(&bytes);
+        data.extend(id3v1());
+        assert_eq!(locate(&data).unwrap(), Some((5, 5 + bytes.len())));
+        assert_eq!(locate(b"AUDIO").unwrap(), None);
+    }
+
+    #[test]
+    fn locate_rejects_invalid_size() {
+        let mut data = b"AUDIO".to_vec();
+        data.extend(header(1000, 0, 0));
+        assert!(locate(&data).is_err());
+        let mut data = b"AUDIO".to_vec();
+        data.extend(header(8, 0, 0));
+        assert!(locate(&data).is_err());
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let dir = TempDir::new("ape");
+        let mut data = b"AUDIO".to_vec();
+        data.extend(id3v1());
+        let path = &dir.write("song.wv", &data);
+
+        let mut tag = ApeTag::default();
+        tag.set("Artist", vec![String::from("A"), String::from("B")]);
+        tag.set("Album", vec![String::from("Album")]);
+        tag.write_to_path(path).unwrap();
+        //Replaces existing tag
+        tag.set("Album", vec![]);
+        tag.write_to_path(path).unwrap();
+
+        let read = ApeTag::read_from_path(path).unwrap().unwrap();
+        assert_eq!(read.get("ARTIST"), Some(vec![String::from("A"), String::from("B")]));
+        assert_eq!(read.get("Album"), None);
+        let written = std::fs::read(path).unwrap();
+        assert!(written.starts_with(b"AUDIO"));
+        assert!(written.ends_with(&id3v1()));
+
+        assert!(ApeTag::remove_from_path(path).unwrap());
+        assert_eq!(std::fs::read(path).unwrap(), data);
+        assert!(ApeTag::read_from_path(path).unwrap().is_none());
+    }
+}
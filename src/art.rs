@@ -0,0 +1,161 @@
+use image::ImageFormat;
+use image::io::Reader;
+use image::imageops::FilterType;
+use image::codecs::jpeg::JpegEncoder;
+use serde_json::Value;
+use std::io::Cursor;
+
+use crate::tagger::{json_i64, json_range};
+
+#[derive(Debug, Clone)]
+pub struct ArtConfig {
+    //Downscale if larger, None = keep size
+    pub max_size: Option<u32>,
+    //Skip art smaller than this
+    pub min_size: u32,
+    //Re-encode PNG/GIF/WebP as JPEG even if not resized
+    pub convert_jpeg: bool,
+    pub jpeg_quality: u8
+}
+
+impl ArtConfig {
+    pub fn from_json(json: &Value) -> Result<ArtConfig, String> {
+        Ok(ArtConfig {
+            //0 = keep size
+            max_size: Some(json_range(&json["artMaxSize"], "art max size", 0, 0, u32::MAX as i64)? as u32).filter(|s| *s > 0),
+            min_size: json_range(&json["artMinSize"], "art min size", 0, 0, u32::MAX as i64)? as u32,
+            convert_jpeg: json["artConvertJpeg"].as_bool().unwrap_or(false),
+            jpeg_quality: json_i64(&json["artQuality"]).unwrap_or(90).clamp(1, 100) as u8
+        })
+    }
+}
+
+//Processed image ready to embed
+#[derive(Debug, Clone)]
+pub struct Art {
+    pub data: Vec<u8>,
+    pub mime: String,
+    pub width: u32,
+    pub height: u32,
+    //Bits per pixel
+    pub depth: u32
+}
+
+//Detect format, resize and recompress, None if image is too small
+pub fn process_art(data: Vec<u8>, config: &ArtConfig) -> Result<Option<Art>, Box<dyn std::error::Error>> {
+    let format = image::guess_format(&data)?;
+    let (width, height) = Reader::with_format(Cursor::new(&data), format).into_dimensions()?;
+    if width.min(height) < config.min_size {
+        return Ok(None);
+    }
+
+    let resize = config.max_size.map(|m| width.max(height) > m).unwrap_or(false);
+    let convert = config.convert_jpeg && format != ImageFormat::Jpeg;
+    //Keep original
+    if !resize && !convert {
+        let depth = match format {
+            ImageFormat::Jpeg => 24,
+            _ => image::load_from_memory_with_format(&data, format)?.color().bits_per_pixel() as u32
+        };
+        return Ok(Some(Art {
+            mime: mime_type(format).to_owned(),
+            data,
+            width,
+            height,
+            depth
+        }));
+    }
+
+    //Resize and encode as JPEG
+    let mut img = image::load_from_memory_with_format(&data, format)?;
+    if resize {
+        let max_size = config.max_size.unwrap();
+        img = img.resize(max_size, max_size, FilterType::Lanczos3);
+    }
+    let rgb = img.to_rgb8();
+    let mut out = vec![];
+    JpegEncoder::new_with_quality(&mut out, config.jpeg_quality).encode_image(&rgb)?;
+    Ok(Some(Art {
+        data: out,
+        mime: String::from("image/jpeg"),
+        width: rgb.width(),
+        height: rgb.height(),
+        depth: 24
+    }))
+}
+
+//Width and height of image
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let format = image::guess_format(data).ok()?;
+    Reader::with_format(Cursor::new(data), format).into_dimensions().ok()
+}
+
+fn mime_type(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Png => "image/png",
+        ImageFormat::Gif => "image/gif",
+        ImageFormat::WebP => "image/webp",
+        ImageFormat::Bmp => "image/bmp",
+        _ => "image/jpeg"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use image::{DynamicImage, ImageOutputFormat};
+
+    fn image(width: u32, height: u32, format: ImageOutputFormat) -> Vec<u8> {
+        let mut out = vec![];
+        DynamicImage::new_rgb8(width, height).write_to(&mut Cursor::new(&mut out), format).unwrap();
+        out
+    }
+
+    fn config(json: Value) -> ArtConfig {
+        ArtConfig::from_json(&json).unwrap()
+    }
+
+    #[test]
+    fn size_options() {
+        let c = config(json!({}));
+        assert_eq!((c.max_size, c.min_size, c.jpeg_quality), (None, 0, 90));
+        let c = config(json!({"artMaxSize": 1000, "artMinSize": "300"}));
+        assert_eq!((c.max_size, c.min_size), (Some(1000), 300));
+        assert_eq!(config(json!({"artMaxSize": 0})).max_size, None);
+        assert!(ArtConfig::from_json(&json!({"artMinSize": -1})).is_err());
+        assert!(ArtConfig::from_json(&json!({"artMaxSize": -500})).is_err());
+        assert!(ArtConfig::from_json(&json!({"artMaxSize": u32::MAX as i64 + 1})).is_err());
+    }
+
+    #[test]
+    fn keeps_original() {
+        let data = image(20, 10, ImageOutputFormat::Png);
+        let art = process_art(data.clone(), &config(json!({}))).unwrap().unwrap();
+        assert_eq!(art.mime, "image/png");
+        assert_eq!((art.width, art.height, art.depth), (20, 10, 24));
+        assert_eq!(art.data, data);
+        assert_eq!(dimensions(&data), Some((20, 10)));
+        let art = process_art(image(8, 8, ImageOutputFormat::Jpeg(90)), &config(json!({}))).unwrap().unwrap();
+        assert_eq!(art.mime, "image/jpeg");
+    }
+
+    #[test]
+    fn min_and_max_size() {
+        let data = image(40, 20, ImageOutputFormat::Png);
+        //Smaller side is checked
+        assert!(process_art(data.clone(), &config(json!({"artMinSize": 21}))).unwrap().is_none());
+        assert!(process_art(data.clone(), &config(json!({"artMinSize": 20}))).unwrap().is_some());
+        let art = process_art(data.clone(), &config(json!({"artMaxSize": 10}))).unwrap().unwrap();
+        assert_eq!(art.mime, "image/jpeg");
+        assert_eq!((art.width, art.height), (10, 5));
+        let art = process_art(data, &config(json!({"artConvertJpeg": true}))).unwrap().unwrap();
+        assert_eq!((art.mime.as_str(), art.width), ("image/jpeg", 40));
+    }
+
+    #[test]
+    fn unknown_format() {
+        assert!(process_art(b"not an image".to_vec(), &config(json!({}))).is_err());
+        assert_eq!(dimensions(b"not an image"), None);
+    }
+}
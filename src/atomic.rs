@@ -0,0 +1,115 @@
+use std::fs::{File, FileTimes, OpenOptions};
+use std::path::{Path, PathBuf};
+
+//Write to temporary copy in the same folder, then replace original by rename,
+//so crash during write can't leave half written file
+pub fn write<F>(path: &str, preserve_times: bool, write: F) -> Result<(), Box<dyn std::error::Error>>
+where F: FnOnce(&str) -> Result<(), Box<dyn std::error::Error>> {
+    let meta = std::fs::metadata(path)?;
+    let temp = temp_path(Path::new(path))?;
+    let temp_str = temp.to_str().ok_or("Invalid path!")?;
+    //Copies permissions too
+    std::fs::copy(path, &temp)?;
+
+    let result = write(temp_str).and_then(|_| {
+        let file = OpenOptions::new().write(true).open(&temp)?;
+        //DJ software re-analyzes files with changed modification time
+        if preserve_times {
+            file.set_times(FileTimes::new().set_accessed(meta.accessed()?).set_modified(meta.modified()?))?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&temp, path)?;
+        Ok(())
+    });
+    if result.is_err() {
+        std::fs::remove_file(&temp).ok();
+        return result;
+    }
+
+    //Persist rename, not possible on Windows
+    if let Some(dir) = Path::new(path).parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(dir).and_then(|d| d.sync_all()).ok();
+    }
+    Ok(())
+}
+
+//Rename doesn't work across drives, copy keeps times so moved file isn't seen as changed
+pub fn rename(from: &str, to: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    copy_move(from, to)
+}
+
+fn copy_move(from: &str, to: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let meta = std::fs::metadata(from)?;
+    std::fs::copy(from, to)?;
+    let file = OpenOptions::new().write(true).open(to)?;
+    file.set_times(FileTimes::new().set_accessed(meta.accessed()?).set_modified(meta.modified()?))?;
+    file.sync_all()?;
+    std::fs::remove_file(from)?;
+    Ok(())
+}
+
+//Hidden file next to original
+fn temp_path(path: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let name = path.file_name().ok_or("Invalid path!")?.to_string_lossy();
+    Ok(path.with_file_name(format!(".{}.{}.tmp", name, std::process::id())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn replaces_file() {
+        let dir = TempDir::new("atomic");
+        let path = dir.write("song.mp3", b"original");
+        let result = write(&path, false, |temp| {
+            //Temp starts as copy
+            assert_eq!(std::fs::read(temp)?, b"original");
+            Ok(std::fs::write(temp, b"new")?)
+        });
+        assert!(result.is_ok());
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert!(!temp_path(Path::new(&path)).unwrap().exists());
+    }
+
+    #[test]
+    fn failed_write_keeps_original() {
+        let dir = TempDir::new("atomic");
+        let path = dir.write("song.mp3", b"original");
+        let result = write(&path, false, |temp| {
+            std::fs::write(temp, b"half")?;
+            Err("Failed!".into())
+        });
+        assert!(result.is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"original");
+        assert!(!temp_path(Path::new(&path)).unwrap().exists());
+    }
+
+    #[test]
+    fn preserves_times() {
+        let dir = TempDir::new("atomic");
+        let path = dir.write("song.mp3", b"original");
+        let old = SystemTime::now() - Duration::from_secs(3600 * 24);
+        File::options().write(true).open(&path).unwrap().set_modified(old).unwrap();
+        write(&path, true, |temp| Ok(std::fs::write(temp, b"new")?)).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), old);
+    }
+
+    #[test]
+    fn copy_move_keeps_times() {
+        let dir = TempDir::new("atomic");
+        let path = dir.write("song.mp3", b"original");
+        let old = SystemTime::now() - Duration::from_secs(3600 * 24);
+        File::options().write(true).open(&path).unwrap().set_modified(old).unwrap();
+        let target = dir.file("moved.mp3");
+        copy_move(&path, &target).unwrap();
+        assert!(!Path::new(&path).exists());
+        assert_eq!(std::fs::read(&target).unwrap(), b"original");
+        assert_eq!(std::fs::metadata(&target).unwrap().modified().unwrap(), old);
+    }
+}
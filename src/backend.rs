@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use crate::tagger::{MusicFileType, TaggerConfig};
+use crate::mapping::TagKeys;
+use crate::art::Art;
+use crate::cue::CueFile;
+use crate::diff::FieldChange;
+use crate::properties::{self, AudioProperties};
+use crate::{ape, atomic, flac, flacid3, id3tag, integrity, mp4, oggtag};
+use crate::ui;
+
+//Format-neutral tag field
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    Title,
+    Artist,
+    Album,
+    Label,
+    //YYYY or YYYY-MM-DD
+    Date,
+    Track,
+    Genre,
+    Style,
+    //Template tag, None = not written to this kind of tag
+    Custom {
+        id3: Option<String>,
+        vorbis: Option<String>
+    }
+}
+
+impl Field {
+    //For diffs and reports
+    pub fn name(&self) -> String {
+        match self {
+            Field::Custom { vorbis: Some(key), .. } => key.to_owned(),
+            Field::Custom { id3: Some(frame), .. } => frame.to_owned(),
+            Field::Custom { .. } => String::from("Custom"),
+            _ => format!("{:?}", self)
+        }
+    }
+
+    //Vorbis comment name, also used as APE and MP4 freeform name
+    pub fn vorbis_key(&self, keys: &TagKeys) -> Option<String> {
+        match self {
+            Field::Title => Some(String::from("TITLE")),
+            Field::Artist => Some(String::from("ARTIST")),
+            Field::Album => Some(String::from("ALBUM")),
+            Field::Label => Some(keys.vorbis_label.to_owned()),
+            Field::Date => Some(String::from("DATE")),
+            Field::Track => Some(String::from("TRACKNUMBER")),
+            Field::Genre => Some(keys.vorbis_genre.to_owned()),
+            Field::Style => Some(keys.vorbis_style.to_owned()),
+            Field::Custom { vorbis, .. } => vorbis.to_owned()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GenreMode {
+    None,
+    //Styles in genre field
+    Style,
+    Genre,
+    //Genres + styles in genre field
+    Merge,
+    //Genres in genre field, styles in style field
+    Both
+}
+
+impl GenreMode {
+    // 0 None, 1 Only Style, 2 Only Genre, 3 Merge, 4 Both
+    pub fn from_id3(value: i64) -> GenreMode {
+        match value {
+            1 => GenreMode::Style,
+            2 => GenreMode::Genre,
+            3 => GenreMode::Merge,
+            4 => GenreMode::Both,
+            _ => GenreMode::None
+        }
+    }
+
+    // 0 None, 1 Both, 2 Only Style, 3 Only Genre, 4 Merge
+    pub fn from_flac(value: i64) -> GenreMode {
+        match value {
+            1 => GenreMode::Both,
+            2 => GenreMode::Style,
+            3 => GenreMode::Genre,
+            4 => GenreMode::Merge,
+            _ => GenreMode::None
+        }
+    }
+}
+
+//Loaded tag of single file
+pub trait AudioTag {
+    //None if field is missing or not supported
+    fn get(&self, field: &Field) -> Option<Vec<String>>;
+    //Formats without multiple values join them, empty values = remove field
+    fn set(&mut self, field: &Field, values: Vec<String>);
+    fn has_art(&self) -> bool;
+    fn set_art(&mut self, art: Art);
+    fn remove_art(&mut self);
+    //Write to path, temporary copy of the file when writing safely
+    fn save(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>>;
+
+    //Embedded CUESHEET, false if not supported by format
+    fn set_cue(&mut self, _file: &CueFile) -> bool {
+        false
+    }
+
+    //Which genre option applies to this format
+    fn genre_mode(&self, config: &TaggerConfig) -> GenreMode {
+        config.flac_genre
+    }
+
+    //Convert values to format version being written, returns changed values
+    fn migrate(&mut self) -> Vec<FieldChange> {
+        vec![]
+    }
+}
+
+//Audio format
+pub trait Backend {
+    fn file_type(&self) -> MusicFileType;
+    //Lowercase, with dot
+    fn extensions(&self) -> &'static [&'static str];
+    //Whether the content is of this format, header = first bytes after ID3v2 tag
+    fn detect(&self, header: &[u8]) -> bool;
+    fn read(&self, path: &str, config: &TaggerConfig) -> Result<Box<dyn AudioTag>, Box<dyn std::error::Error>>;
+    fn properties(&self, path: &str) -> Result<AudioProperties, Box<dyn std::error::Error>>;
+}
+
+//All supported formats
+pub fn backends() -> Vec<Box<dyn Backend>> {
+    vec![
+        Box::new(id3tag::Id3Backend::new(MusicFileType::MP3)),
+        Box::new(id3tag::Id3Backend::new(MusicFileType::AIFF)),
+        Box::new(id3tag::Id3Backend::new(MusicFileType::WAV)),
+        Box::new(flac::FlacBackend),
+        Box::new(mp4::Mp4Backend),
+        Box::new(oggtag::OggBackend),
+        Box::new(ape::ApeBackend)
+    ]
+}
+
+//Find backend for file by content, falls back to extension
+pub fn detect(path: &str) -> Option<Box<dyn Backend>> {
+    let (header, id3) = match read_header(path) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Failed reading header of {}: {}", path, e);
+            return backends().into_iter().find(|b| has_extension(path, b.extensions()));
+        }
+    };
+    let (backend, mismatch) = detect_header(path, &header, id3);
+    if let (Some(backend), true) = (backend.as_ref(), mismatch) {
+        ui::print_warning(&format!("File extension doesn't match content, reading as {:?}: {}", backend.file_type(), path));
+    }
+    backend
+}
+
+//Backend and whether extension is of another format
+fn detect_header(path: &str, header: &[u8], id3: bool) -> (Option<Box<dyn Backend>>, bool) {
+    let by_extension = backends().into_iter().find(|b| has_extension(path, b.extensions()));
+    //ID3 with unknown content = MP3 without frame sync right after tag
+    let by_content = backends().into_iter().find(|b| b.detect(header))
+        .or_else(|| match id3 {
+            true => Some(for_type(&MusicFileType::MP3)),
+            false => None
+        });
+
+    match (by_extension, by_content) {
+        (Some(e), Some(c)) => {
+            let mismatch = e.file_type() != c.file_type();
+            (Some(c), mismatch)
+        },
+        (None, Some(c)) => (Some(c), false),
+        (e, None) => (e, false)
+    }
+}
+
+//Save through temporary copy of file, original is kept if audio data changed
+pub fn save(tag: &mut dyn AudioTag, path: &str, config: &TaggerConfig) -> Result<(), Box<dyn std::error::Error>> {
+    atomic::write(path, config.preserve_times, |temp| {
+        tag.save(temp)?;
+        match config.verify_audio {
+            true => integrity::verify(path, temp),
+            false => Ok(())
+        }
+    })
+}
+
+//Backend of already loaded file
+pub fn for_type(file_type: &MusicFileType) -> Box<dyn Backend> {
+    backends().into_iter().find(|b| &b.file_type() == file_type).unwrap()
+}
+
+//Whether file has extension of any supported format, or other file (renamed, without extension) has audio content
+pub fn is_supported(path: &str) -> bool {
+    if backends().iter().any(|b| has_extension(path, b.extensions())) {
+        return true;
+    }
+    if !worth_sniffing(path) {
+        return false;
+    }
+    //Folders are scanned again and again in watch mode
+    static SNIFFED: OnceLock<Mutex<HashMap<String, (SystemTime, bool)>>> = OnceLock::new();
+    let modified = match std::fs::metadata(path).and_then(|m| m.modified()) {
+        Ok(m) => m,
+        Err(_) => return false
+    };
+    let cache = SNIFFED.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some((time, result)) = cache.lock().unwrap().get(path) {
+        if *time == modified {
+            return *result;
+        }
+    }
+    let result = sniff(path);
+    cache.lock().unwrap().insert(path.to_owned(), (modified, result));
+    result
+}
+
+//Files which are certainly not audio aren't opened
+fn worth_sniffing(path: &str) -> bool {
+    let name = std::path::Path::new(path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    //Hidden files (state, journal, leftovers of interrupted atomic write)
+    if name.starts_with('.') {
+        return false;
+    }
+    !has_extension(&name, &[
+        ".tmp", ".txt", ".nfo", ".log", ".cue", ".m3u", ".m3u8", ".pls", ".json", ".jsonl", ".csv", ".html", ".pdf",
+        ".jpg", ".jpeg", ".png", ".gif", ".bmp", ".webp", ".sfv", ".md5", ".ffp", ".accurip", ".url", ".db", ".ini"
+    ])
+}
+
+//Only unambiguous signatures, MPEG frame sync and video MP4 can appear in other files
+fn sniff(path: &str) -> bool {
+    let (header, id3) = match read_header(path) {
+        Ok(r) => r,
+        Err(_) => return false
+    };
+    if id3 {
+        return true;
+    }
+    if header.len() >= 12 && &header[4..8] == b"ftyp" {
+        return [b"M4A ", b"M4B ", b"M4P "].iter().any(|brand| &header[8..12] == *brand) || properties::mp4_is_audio(path);
+    }
+    backends().iter().filter(|b| b.file_type() != MusicFileType::MP3).any(|b| b.detect(&header))
+}
+
+//Case insensitive extension check
+pub fn has_extension(path: &str, extensions: &[&str]) -> bool {
+    let path = path.to_ascii_lowercase();
+    extensions.iter().any(|e| path.ends_with(e))
+}
+
+//First 12 bytes after ID3v2 tag, and whether there was ID3
+fn read_header(path: &str) -> Result<(Vec<u8>, bool), Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let id3_size = flacid3::read_id3_size(&mut file).unwrap_or(0);
+    file.seek(SeekFrom::Start(id3_size))?;
+    let mut header = vec![];
+    file.take(12).read_to_end(&mut header)?;
+    Ok((header, id3_size > 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::test_util::TempDir;
+
+    fn atom(name: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut out = ((data.len() + 8) as u32).to_be_bytes().to_vec();
+        out.extend(name);
+        out.extend(data);
+        out
+    }
+
+    //ftyp with brand and moov with tracks of handler types
+    fn mp4(brand: &[u8], handlers: &[&[u8]]) -> Vec<u8> {
+        let mut moov = vec![];
+        for handler in handlers {
+            let mut hdlr = vec![0u8; 8];
+            hdlr.extend(*handler);
+            hdlr.extend(&[0u8; 12]);
+            moov.extend(atom(b"trak", &atom(b"mdia", &atom(b"hdlr", &hdlr))));
+        }
+        let mut data = atom(b"ftyp", &[brand, &[0u8; 4][..]].concat());
+        data.extend(atom(b"moov", &moov));
+        data
+    }
+
+    fn file_type(backend: Option<Box<dyn Backend>>) -> Option<MusicFileType> {
+        backend.map(|b| b.file_type())
+    }
+
+    #[test]
+    fn detect_by_content() {
+        let dir = TempDir::new("backend");
+        assert_eq!(file_type(detect(&dir.write("song.mp3", b"fLaC\0\0\0\0"))), Some(MusicFileType::FLAC));
+        assert_eq!(file_type(detect(&dir.write("song", b"OggS\0\0"))), Some(MusicFileType::OGG));
+        //ID3 followed by unknown data
+        assert_eq!(file_type(detect(&dir.write("song.bin", b"ID3\x04\0\0\0\0\0\0junk"))), Some(MusicFileType::MP3));
+        //Unknown content falls back to extension
+        assert_eq!(file_type(detect(&dir.write("song.flac", b"junk"))), Some(MusicFileType::FLAC));
+        assert_eq!(file_type(detect(&dir.write("song.bin", b"junk"))), None);
+    }
+
+    #[test]
+    fn extension_mismatch() {
+        let (backend, mismatch) = detect_header("song.mp3", b"fLaC", false);
+        assert_eq!((file_type(backend), mismatch), (Some(MusicFileType::FLAC), true));
+        let (backend, mismatch) = detect_header("song.flac", b"fLaC", false);
+        assert_eq!((file_type(backend), mismatch), (Some(MusicFileType::FLAC), false));
+        //No extension isn't mismatch
+        assert!(!detect_header("song", b"fLaC", false).1);
+        assert!(!detect_header("song.flac", b"junk", false).1);
+    }
+
+    #[test]
+    fn supported_files() {
+        let dir = TempDir::new("backend");
+        assert!(is_supported(&dir.write("song.MP3", b"")));
+        assert!(is_supported(&dir.write("song.m4a", b"")));
+        assert!(is_supported(&dir.write("renamed.bin", b"fLaC")));
+        //Audio signature in files which aren't sniffed
+        assert!(!is_supported(&dir.write("cover.jpg", b"fLaC")));
+        assert!(!is_supported(&dir.write(".song.flac.1.tmp", b"fLaC")));
+        //MP4 needs audio brand or only audio tracks
+        assert!(is_supported(&dir.write("brand.mp4", mp4(b"M4A ", &[]))));
+        assert!(is_supported(&dir.write("audio.mp4", mp4(b"isom", &[b"soun"]))));
+        assert!(!is_supported(&dir.write("video.mp4", mp4(b"isom", &[b"vide", b"soun"]))));
+    }
+
+    #[test]
+    fn sniff_cached_until_modified() {
+        let dir = TempDir::new("backend");
+        let path = dir.write("song", b"junk");
+        assert!(!is_supported(&path));
+        //Same modification time = cached result
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::fs::write(&path, b"fLaC").unwrap();
+        File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        assert!(!is_supported(&path));
+        File::options().write(true).open(&path).unwrap().set_modified(modified + Duration::from_secs(1)).unwrap();
+        assert!(is_supported(&path));
+    }
+}
@@ -0,0 +1,318 @@
+use serde_json::{Value, Map};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::tagger::TaggerConfig;
+use crate::runner;
+use crate::journal;
+use crate::state;
+use crate::watch::Watcher;
+use crate::ui;
+
+const HELP: &str = r#"Usage: discogstaggerrs --path <folder> [--config <config.json>] [--token <token>] [--<option> <value>]...
+       discogstaggerrs --undo [journal] [--path <folder>] [--undoFiles <files>]
+       discogstaggerrs --watch <folders> [--<option> <value>]...
+
+Options use the same names as the config file, command line values override the config file.
+
+Tags (value: never, fill, overwrite, merge):
+    --title, --artist, --album, --label, --date, --track, --genre, --art
+
+Other:
+    --id3Genre <0-4>                0 = None, 1 = Only Style, 2 = Only Genre, 3 = Merge, 4 = Both (style in TXXX:STYLE)
+    --flacGenre <0-4>               FLAC, Ogg, MP4 and APE, 0 = None, 1 = Both, 2 = Only Style, 3 = Only Genre, 4 = Merge
+    --separator <string>            Artist separator
+    --fuzziness <0-100>             Strictness
+    --durationTolerance <seconds>   Only match tracks with similar duration on Discogs, 0 = ignore
+    --losslessOnly                  Skip lossy files (MP3, AAC, Vorbis, Opus)
+    --cue <bool>                    Match single file albums with .cue sheet as whole release, default true
+    --cueEmbed                      Also write CUESHEET block and CUE_TRACKnn_TITLE/PERFORMER to FLAC
+    --dryRun                        Match and show changes without writing any files
+    --dryRunOutput <file>           JSON file with dry run changes, default dry-run.json
+    --skip <policies>               Skip already tagged files, comma separated: provenance (has DISCOGS_RELEASE_ID),
+                                    fields (all --requiredFields filled), unchanged (not modified since last run)
+    --requiredFields <fields>       Default title,artist,album,label,date,genre
+    --provenance <policy>           Write DISCOGS_RELEASE_ID to tagged files: never, fill, overwrite,
+                                    default fill with --skip provenance, otherwise never
+    --resume <bool>                 Skip files already processed by interrupted run in the same folder, asks if not set
+    --preserveTimes                 Keep modification and access time of written files
+    --verifyAudio <bool>            Keep original file if audio data changed while writing (MP3, FLAC, AIFF, WAV), default true
+    --report <format>               Per file results written after run: json, csv, html, none, default json
+    --reportOutput <file>           Report file, extension by format is added if missing,
+                                    default discogstagger-report-<date>-<time> in the tagged folder
+    --journal <bool>                Save original tags of written files for undo, default true
+    --journalDir <folder>           Folder with undo journals, default .discogstagger-journal in the tagged folder
+    --id3v23                        Write ID3v2.3 instead of ID3v2.4
+    --id3MultiValue                 Write null separated multiple values (ID3v2.4 only)
+    --id3Separator <string>         Separator for multiple values in ID3, default ", "
+    --apeMp3 <mode>                 APEv2 tags in MP3: ignore, read, update, strip
+    --repairFlacId3                 Move ID3 tags in FLAC files to Vorbis comments and remove them
+    --id3v1                         Also write ID3v1 tag to MP3 files
+    --id3Migrate                    Convert existing ID3v2.2/v2.3/v2.4 frames to the written version
+    --wavInfo                       Also write title, artist, album, date, genre and track to RIFF INFO in WAV files
+
+Watch:
+    --watch [folders]               Tag new or changed files in folders (separated by ;) until stopped, without value = --path
+    --watchInterval <seconds>       Time between folder scans, default 5
+    --watchSettle <seconds>         Time file must be unchanged (copy finished) before tagging, default 10
+    --watchLog <file>               Results log, default watch.log
+
+Organize:
+    --organize <template>           Move tagged files, for example "{label}/{catno} - {album}/{position} {artist} - {title}.{ext}"
+                                    Same variables as --mapping and {ext}, single file albums with cue sheet aren't moved
+    --organizeRoot <folder>         Folder the template is relative to, default --path
+    --quarantine <folder>           Move files without match here
+
+Retry:
+    --retry [file]                  Tag again only files which failed, from JSON report or state file, without value = state file in --path
+    --retryCategories <categories>  Only these failures, comma separated: no-match, network, matching, write,
+                                    integrity, invalid, default all
+                                    Combine with --fuzziness, --durationTolerance, --cue... to change matching for this pass
+
+Undo:
+    --undo [journal]                Restore original tags from journal, without value = last run in --path
+    --undoFiles <files>             Only restore these files, separated by ;
+    --force                         Restore even files changed since tagging
+
+Labels:
+    --labels <mode>                 primary, all
+    --labelSeparator <string>       Join labels into single value
+    --dropPlaceholderLabels         Remove "Not On Label" placeholders
+    --stripLabelSuffix <bool>       Remove Discogs (n) suffix, default true
+
+Album art:
+    --artMaxSize <px>               Downscale larger art and re-encode as JPEG, 0 = keep size
+    --artMinSize <px>               Skip art smaller than this
+    --artQuality <1-100>            JPEG quality, default 90
+    --artConvertJpeg                Re-encode PNG/GIF/WebP art as JPEG
+
+Tag mapping:
+    --preset <name>                 rekordbox, traktor, serato, foobar2000
+    --mapping <mappings>            Custom tags, one per line: KEY = "{catno} | {label} | {discogs_url}"
+"#;
+
+//Options with true/false value
+const BOOL_OPTIONS: [&str; 19] = [
+    "overwrite", "losslessOnly", "cue", "cueEmbed", "dryRun", "resume", "preserveTimes", "verifyAudio", "journal",
+    "id3v23", "id3MultiValue", "repairFlacId3", "id3v1", "id3Migrate", "wavInfo", "dropPlaceholderLabels",
+    "stripLabelSuffix", "artConvertJpeg", "force"
+];
+//Options with whole number value
+const NUMBER_OPTIONS: [&str; 9] = [
+    "id3Genre", "flacGenre", "fuzziness", "durationTolerance", "watchInterval", "watchSettle", "artMaxSize", "artMinSize", "artQuality"
+];
+//Options which can be turned on/off or have a value (write policy, file or folder)
+const TOGGLE_OPTIONS: [&str; 11] = ["title", "artist", "album", "label", "date", "track", "genre", "art", "undo", "watch", "retry"];
+
+//Run without UI, returns exit code
+pub fn start_cli(args: Vec<String>) -> i32 {
+    if args.iter().any(|a| a == "--help" || a == "-h") {
+        println!("{}", HELP);
+        return 0;
+    }
+    match run(&args) {
+        Ok(_) => 0,
+        Err(e) => {
+            ui::print_warning(&e);
+            1
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let config_data = parse_args(args)?;
+    if enabled(&config_data["undo"]) {
+        return undo(&config_data);
+    }
+    if enabled(&config_data["watch"]) {
+        return watch(&config_data);
+    }
+    if enabled(&config_data["retry"]) {
+        return retry(&config_data);
+    }
+    //Check path
+    let path = config_data["path"].as_str().ok_or("Missing --path!")?;
+    if !Path::new(path).is_dir() {
+        return Err(String::from("Invalid path!"));
+    }
+    let mut config = TaggerConfig::from_json(&config_data)?;
+    //Interrupted run in same folder
+    if let (None, Some(entries)) = (config.resume, state::unfinished(path)) {
+        config.resume = Some(ask(&format!("Previous run in this folder was interrupted after {} files. Resume? [Y/n] ", entries.len())));
+    }
+    //Token from args or saved
+    let saved_token = std::fs::read_to_string(".discogstoken").ok();
+    let token = config_data["token"].as_str().or(saved_token.as_deref());
+    let mut discogs = runner::create_discogs(token)?;
+
+    println!("Starting...\n");
+    runner::run(&mut discogs, &config, path, |_, _, _, _, _| {});
+    Ok(())
+}
+
+//Tag new files in folders until killed, --watch without value = --path
+fn watch(config_data: &Value) -> Result<(), String> {
+    let folders: Vec<String> = config_data["watch"].as_str().or_else(|| config_data["path"].as_str()).ok_or("Missing folders to watch!")?
+        .split(';').map(|f| f.trim().to_owned()).filter(|f| !f.is_empty()).collect();
+    if folders.is_empty() {
+        return Err(String::from("Missing folders to watch!"));
+    }
+    if let Some(folder) = folders.iter().find(|f| !Path::new(f).is_dir()) {
+        return Err(format!("Invalid path: {}", folder));
+    }
+    let config = TaggerConfig::from_json(config_data)?;
+    let saved_token = std::fs::read_to_string(".discogstoken").ok();
+    let token = config_data["token"].as_str().or(saved_token.as_deref());
+    let mut discogs = runner::create_discogs(token)?;
+
+    println!("Watching: {}\n", folders.join(", "));
+    let mut watcher = Watcher::new(folders, &config)?;
+    let (mut ok, mut fail) = (0, 0);
+    loop {
+        watcher.poll(&mut discogs, &config, |path, result| {
+            match result {
+                Ok(_) => ok += 1,
+                Err(_) => fail += 1
+            }
+            ui::print_console(path, result.as_ref().map(|_| ()).map_err(String::from), ok, fail, ok + fail);
+        });
+        std::thread::sleep(Duration::from_secs(config.watch_interval));
+    }
+}
+
+//Tag again files which failed in previous run, from report or state file
+fn retry(config_data: &Value) -> Result<(), String> {
+    let source = match config_data["retry"].as_str() {
+        Some(s) => s.to_owned(),
+        None => {
+            let path = config_data["path"].as_str().ok_or("Missing report or state file to retry!")?;
+            state::state_path(path).to_str().ok_or("Invalid path!")?.to_owned()
+        }
+    };
+    let config = TaggerConfig::from_json(config_data)?;
+    let saved_token = std::fs::read_to_string(".discogstoken").ok();
+    let token = config_data["token"].as_str().or(saved_token.as_deref());
+    let mut discogs = runner::create_discogs(token)?;
+
+    println!("Starting...\n");
+    runner::retry(&mut discogs, &config, &source, |_, _, _, _, _| {})
+}
+
+//Yes/no question, default yes
+fn ask(question: &str) -> bool {
+    print!("{}", question);
+    std::io::stdout().flush().ok();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).ok();
+    !answer.trim().to_lowercase().starts_with('n')
+}
+
+//Restore original tags from journal, --undo without value = last run
+fn undo(config_data: &Value) -> Result<(), String> {
+    let config = TaggerConfig::from_json(config_data)?;
+    let path = match config_data["undo"].as_str() {
+        Some(p) => p.to_owned(),
+        None => {
+            let folder = config_data["path"].as_str().unwrap_or(".");
+            journal::latest(&config.journal_folder(folder)).ok_or("No journal found!")?
+        }
+    };
+    let files: Vec<String> = config_data["undoFiles"].as_str().unwrap_or("")
+        .split(';').map(|f| f.trim().to_owned()).filter(|f| !f.is_empty()).collect();
+    let force = config_data["force"].as_bool().unwrap_or(false);
+    println!("Restoring tags from: {}\n", path);
+    let (ok, fail) = journal::undo(&path, &files, force, &config).map_err(|e| format!("Failed reading journal! {}", e))?;
+    println!("\nRestored: {}, failed or skipped: {}", ok, fail);
+    Ok(())
+}
+
+//Convert --key value pairs to config JSON, on top of config file
+pub fn parse_args(args: &[String]) -> Result<Value, String> {
+    let mut options = Map::new();
+    let mut i = 0;
+    while i < args.len() {
+        let key = args[i].strip_prefix("--").ok_or(format!("Invalid argument: {}", args[i]))?;
+        //Flag without value = true
+        let value = match args.get(i + 1) {
+            Some(v) if !v.starts_with("--") => {
+                i += 1;
+                parse_value(key, v)?
+            },
+            _ => Value::Bool(true)
+        };
+        options.insert(key.to_owned(), value);
+        i += 1;
+    }
+
+    //Load config file
+    let mut config = match options.get("config").and_then(|c| c.as_str()) {
+        Some(path) => {
+            let data = std::fs::read_to_string(path).map_err(|e| format!("Failed reading config file! {}", e))?;
+            match serde_json::from_str(&data) {
+                Ok(Value::Object(o)) => o,
+                _ => return Err(String::from("Invalid config file!"))
+            }
+        },
+        None => Map::new()
+    };
+    for (k, v) in options {
+        config.insert(k, v);
+    }
+    Ok(Value::Object(config))
+}
+
+//Option set and not turned off
+fn enabled(value: &Value) -> bool {
+    !value.is_null() && value != &Value::Bool(false)
+}
+
+//Value by option type, everything else (separators, paths...) is kept as string
+fn parse_value(key: &str, value: &str) -> Result<Value, String> {
+    if BOOL_OPTIONS.contains(&key) {
+        return value.parse::<bool>().map(Value::Bool).map_err(|_| format!("Invalid value for --{}: {} (true or false)", key, value));
+    }
+    if NUMBER_OPTIONS.contains(&key) {
+        return value.trim().parse::<i64>().map(Value::from).map_err(|_| format!("Invalid value for --{}: {} (number)", key, value));
+    }
+    if TOGGLE_OPTIONS.contains(&key) {
+        if let Ok(b) = value.parse::<bool>() {
+            return Ok(Value::Bool(b));
+        }
+    }
+    Ok(Value::String(value.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn values_by_option() {
+        let config = parse_args(&args(&["--separator", "1", "--id3Separator", "true", "--fuzziness", "60", "--dryRun", "--cue", "false", "--title", "merge"])).unwrap();
+        assert_eq!(config["separator"], Value::from("1"));
+        assert_eq!(config["id3Separator"], Value::from("true"));
+        assert_eq!(config["fuzziness"], Value::from(60));
+        assert_eq!(config["dryRun"], Value::Bool(true));
+        assert_eq!(config["cue"], Value::Bool(false));
+        assert_eq!(config["title"], Value::from("merge"));
+        assert!(parse_args(&args(&["--fuzziness", "high"])).is_err());
+        assert!(parse_args(&args(&["--journal", "no"])).is_err());
+    }
+
+    #[test]
+    fn toggles() {
+        let config = parse_args(&args(&["--undo", "false", "--watch", "/music", "--retry", "--title", "false"])).unwrap();
+        assert!(!enabled(&config["undo"]));
+        assert!(enabled(&config["watch"]));
+        assert!(enabled(&config["retry"]));
+        assert!(!enabled(&config["path"]));
+        assert_eq!(config["watch"], Value::from("/music"));
+        assert_eq!(config["retry"], Value::Bool(true));
+        assert_eq!(config["title"], Value::Bool(false));
+    }
+}
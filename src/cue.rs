@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::atomic;
+use crate::discogs::ReleaseMaster;
+use crate::tagger::{TaggerConfig, clean_discogs_artist};
+
+#[derive(Debug, Clone)]
+pub struct CueTrack {
+    pub number: u8,
+    kind: String,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    //(index number, position in CD frames)
+    pub indexes: Vec<(u8, u32)>,
+    //ISRC, FLAGS, REM... kept as is
+    lines: Vec<String>
+}
+
+#[derive(Debug, Clone)]
+pub struct CueFile {
+    pub name: String,
+    format: String,
+    lines: Vec<String>,
+    pub tracks: Vec<CueTrack>
+}
+
+#[derive(Debug, Clone)]
+pub struct CueSheet {
+    pub path: String,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    //REM, CATALOG... kept as is
+    lines: Vec<String>,
+    pub files: Vec<CueFile>,
+    //False = decoded from unknown codepage
+    pub utf8: bool,
+    bom: bool,
+    crlf: bool
+}
+
+impl CueSheet {
+    pub fn read_from_path(path: &str) -> Result<CueSheet, Box<dyn std::error::Error>> {
+        let data = std::fs::read(path)?;
+        let bom = data.starts_with(b"\xEF\xBB\xBF");
+        let data = data.strip_prefix(b"\xEF\xBB\xBF".as_ref()).unwrap_or(&data);
+        //Older rippers use system codepage
+        let (text, utf8) = match std::str::from_utf8(data) {
+            Ok(t) => (t.to_owned(), true),
+            Err(_) => (data.iter().map(|b| *b as char).collect(), false)
+        };
+
+        let mut cue = CueSheet {
+            path: path.to_owned(),
+            title: None,
+            performer: None,
+            lines: vec![],
+            files: vec![],
+            utf8,
+            bom,
+            crlf: text.contains("\r\n")
+        };
+        for line in text.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            let (command, value) = match line.find(' ') {
+                Some(i) => (line[..i].to_uppercase(), line[i+1..].trim()),
+                None => (line.to_uppercase(), "")
+            };
+            let global = cue.files.is_empty();
+            let file = cue.files.last_mut();
+            let track = file.and_then(|f| f.tracks.last_mut());
+            match (command.as_str(), track) {
+                ("FILE", _) => {
+                    //Name is quoted, format is last word
+                    let (name, format) = match value.rfind(' ') {
+                        Some(i) => (unquote(&value[..i]), value[i+1..].to_owned()),
+                        None => (unquote(value), String::from("WAVE"))
+                    };
+                    cue.files.push(CueFile { name, format, lines: vec![], tracks: vec![] });
+                },
+                ("TRACK", _) => {
+                    let mut parts = value.split_whitespace();
+                    let number = parts.next().and_then(|n| n.parse().ok()).ok_or("Invalid CUE track number!")?;
+                    let kind = parts.next().unwrap_or("AUDIO").to_owned();
+                    cue.files.last_mut().ok_or("CUE track without file!")?
+                        .tracks.push(CueTrack { number, kind, title: None, performer: None, indexes: vec![], lines: vec![] });
+                },
+                ("TITLE", Some(track)) => track.title = Some(unquote(value)),
+                ("PERFORMER", Some(track)) => track.performer = Some(unquote(value)),
+                ("INDEX", Some(track)) => {
+                    let mut parts = value.split_whitespace();
+                    let number = parts.next().and_then(|n| n.parse().ok()).ok_or("Invalid CUE index!")?;
+                    let time = parts.next().and_then(parse_time).ok_or("Invalid CUE index time!")?;
+                    track.indexes.push((number, time));
+                },
+                (_, Some(track)) => track.lines.push(line.to_owned()),
+                ("TITLE", None) if global => cue.title = Some(unquote(value)),
+                ("PERFORMER", None) if global => cue.performer = Some(unquote(value)),
+                _ => match cue.files.last_mut() {
+                    Some(file) => file.lines.push(line.to_owned()),
+                    None => cue.lines.push(line.to_owned())
+                }
+            }
+        }
+        Ok(cue)
+    }
+
+    pub fn save(&self, config: &TaggerConfig) -> Result<(), Box<dyn std::error::Error>> {
+        let mut out = self.lines.clone();
+        if let Some(performer) = self.performer.as_ref() {
+            out.push(format!("PERFORMER {}", quote(performer)));
+        }
+        if let Some(title) = self.title.as_ref() {
+            out.push(format!("TITLE {}", quote(title)));
+        }
+        for file in &self.files {
+            out.push(format!("FILE {} {}", quote(&file.name), file.format));
+            out.extend(file.lines.iter().map(|l| format!("  {}", l)));
+            for track in &file.tracks {
+                out.push(format!("  TRACK {:02} {}", track.number, track.kind));
+                if let Some(title) = track.title.as_ref() {
+                    out.push(format!("    TITLE {}", quote(title)));
+                }
+                if let Some(performer) = track.performer.as_ref() {
+                    out.push(format!("    PERFORMER {}", quote(performer)));
+                }
+                out.extend(track.lines.iter().map(|l| format!("    {}", l)));
+                for (number, time) in &track.indexes {
+                    out.push(format!("    INDEX {:02} {}", number, format_time(*time)));
+                }
+            }
+        }
+        let newline = if self.crlf { "\r\n" } else { "\n" };
+        let data = match self.bom {
+            true => format!("\u{FEFF}{}{}", out.join(newline), newline),
+            false => out.join(newline) + newline
+        };
+        atomic::write(&self.path, config.preserve_times, |temp| Ok(std::fs::write(temp, data)?))
+    }
+
+    //Entry of audio file, same name or only different extension
+    pub fn file(&self, path: &str) -> Option<&CueFile> {
+        let audio = Path::new(path);
+        let stem = |p: &Path| p.file_stem().map(|s| s.to_string_lossy().to_lowercase());
+        self.files.iter().find(|f| Path::new(&f.name).file_name() == audio.file_name())
+            .or_else(|| self.files.iter().find(|f| stem(Path::new(&f.name)) == stem(audio)))
+    }
+
+    fn file_mut(&mut self, path: &str) -> Option<&mut CueFile> {
+        let name = self.file(path)?.name.to_owned();
+        self.files.iter_mut().find(|f| f.name == name)
+    }
+
+    //Fill titles and performers from Discogs tracklist, tracks are in same order
+    pub fn fill(&mut self, path: &str, release: &ReleaseMaster, config: &TaggerConfig) {
+        let release_artists = release.artists.as_ref().map(|a| join_artists(a, config)).unwrap_or_default();
+        if config.album.should_write(self.title.is_some()) {
+            self.title = Some(release.title.to_owned());
+        }
+        if config.artist.should_write(self.performer.is_some()) && !release_artists.is_empty() {
+            self.performer = Some(release_artists.to_owned());
+        }
+        let date = release.released.to_owned().or_else(|| release.year.map(|y| y.to_string()));
+        if let Some(year) = date.as_ref().and_then(|d| d.get(0..4)) {
+            let exists = self.lines.iter().any(|l| l.to_uppercase().starts_with("REM DATE "));
+            if config.date.should_write(exists) {
+                self.lines.retain(|l| !l.to_uppercase().starts_with("REM DATE "));
+                self.lines.push(format!("REM DATE {}", year));
+            }
+        }
+
+        let tracks = match (self.file_mut(path), release.tracks.as_ref()) {
+            (Some(file), Some(tracks)) => file.tracks.iter_mut().zip(tracks),
+            _ => return
+        };
+        for (cue_track, track) in tracks {
+            if config.title.should_write(cue_track.title.is_some()) {
+                cue_track.title = Some(track.title.to_owned());
+            }
+            let performer = track.artists.as_ref().map(|a| join_artists(a, config)).unwrap_or_else(|| release_artists.to_owned());
+            if config.artist.should_write(cue_track.performer.is_some()) && !performer.is_empty() {
+                cue_track.performer = Some(performer);
+            }
+        }
+    }
+}
+
+//Parsed cue sheets by folder, so each is read only once
+#[derive(Default)]
+pub struct CueCache {
+    folders: HashMap<PathBuf, Vec<CueSheet>>
+}
+
+impl CueCache {
+    //Cue sheet describing single file album, same name first, then any in folder
+    pub fn find_for(&mut self, path: &str) -> Option<CueSheet> {
+        let audio = Path::new(path);
+        let folder = audio.parent()?;
+        let same_name = audio.with_extension("cue");
+        let sheets = self.folders.entry(folder.to_owned()).or_insert_with(|| read_folder(folder));
+        let is_same = |c: &&CueSheet| Path::new(&c.path) == same_name;
+        sheets.iter().filter(is_same)
+            .chain(sheets.iter().filter(|c| !is_same(c)))
+            .find(|c| c.file(path).map(|f| f.tracks.len() > 1).unwrap_or(false))
+            .cloned()
+    }
+}
+
+fn read_folder(folder: &Path) -> Vec<CueSheet> {
+    let entries = match std::fs::read_dir(folder) {
+        Ok(e) => e,
+        Err(_) => return vec![]
+    };
+    entries.filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.is_file() && p.extension().map(|e| e.eq_ignore_ascii_case("cue")).unwrap_or(false))
+        .filter_map(|p| CueSheet::read_from_path(p.to_str()?).ok())
+        .collect()
+}
+
+fn join_artists(artists: &[String], config: &TaggerConfig) -> String {
+    artists.iter().map(|a| clean_discogs_artist(a)).collect::<Vec<String>>().join(&config.artist_separator)
+}
+
+fn unquote(value: &str) -> String {
+    value.trim().trim_matches('"').to_owned()
+}
+
+//Quotes can't be escaped in CUE
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "'"))
+}
+
+//mm:ss:ff, 75 frames per second
+fn parse_time(time: &str) -> Option<u32> {
+    let parts: Vec<u32> = time.split(':').map(|p| p.parse().ok()).collect::<Option<Vec<u32>>>()?;
+    match parts.as_slice() {
+        [m, s, f] => Some((m * 60 + s) * 75 + f),
+        _ => None
+    }
+}
+
+fn format_time(frames: u32) -> String {
+    format!("{:02}:{:02}:{:02}", frames / 75 / 60, frames / 75 % 60, frames % 75)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::test_util::TempDir;
+
+    const CUE: &str = "REM GENRE Rock\r\nPERFORMER \"Artist\"\r\nTITLE \"Album\"\r\nFILE \"album.flac\" WAVE\r\n  TRACK 01 AUDIO\r\n    TITLE \"One\"\r\n    ISRC ABC\r\n    INDEX 01 00:00:00\r\n  TRACK 02 AUDIO\r\n    INDEX 00 03:59:70\r\n    INDEX 01 04:00:00\r\n";
+
+    #[test]
+    fn parse() {
+        let dir = TempDir::new("cue");
+        let cue = CueSheet::read_from_path(&dir.write("album.cue", CUE)).unwrap();
+        assert_eq!(cue.title.as_deref(), Some("Album"));
+        assert_eq!(cue.performer.as_deref(), Some("Artist"));
+        assert!(cue.utf8 && cue.crlf && !cue.bom);
+        let file = cue.file("/music/album.flac").unwrap();
+        assert_eq!(file.tracks.len(), 2);
+        assert_eq!(file.tracks[0].title.as_deref(), Some("One"));
+        assert_eq!(file.tracks[1].indexes, vec![(0, (3 * 60 + 59) * 75 + 70), (1, 4 * 60 * 75)]);
+        //Different extension matches by name
+        assert!(cue.file("album.wv").is_some());
+        assert!(cue.file("other.flac").is_none());
+    }
+
+    #[test]
+    fn save_keeps_unknown_lines() {
+        let dir = TempDir::new("cue");
+        let path = dir.write("album.cue", format!("\u{FEFF}{}", CUE));
+        let mut cue = CueSheet::read_from_path(&path).unwrap();
+        cue.files[0].tracks[1].title = Some(String::from("Two \"quoted\""));
+        cue.save(&TaggerConfig::from_json(&json!({})).unwrap()).unwrap();
+        let data = std::fs::read_to_string(&path).unwrap();
+        assert!(data.starts_with('\u{FEFF}'));
+        assert!(data.contains("REM GENRE Rock\r\n"));
+        assert!(data.contains("    ISRC ABC\r\n"));
+        assert!(data.contains("    INDEX 00 03:59:70\r\n"));
+        let cue = CueSheet::read_from_path(&path).unwrap();
+        assert_eq!(cue.files[0].tracks[1].title.as_deref(), Some("Two 'quoted'"));
+        assert_eq!(cue.files[0].tracks[0].title.as_deref(), Some("One"));
+    }
+
+    #[test]
+    fn non_utf8_is_marked() {
+        let dir = TempDir::new("cue");
+        let cue = CueSheet::read_from_path(&dir.write("album.cue", b"TITLE \"Caf\xE9\"\nFILE \"album.flac\" WAVE\n")).unwrap();
+        assert!(!cue.utf8);
+        assert_eq!(cue.title.as_deref(), Some("Caf\u{E9}"));
+    }
+
+    #[test]
+    fn cache_prefers_same_name() {
+        let dir = TempDir::new("cue");
+        dir.write("a.cue", CUE.replace("Album", "Other"));
+        dir.write("album.cue", CUE);
+        dir.write("single.cue", "FILE \"single.flac\" WAVE\n  TRACK 01 AUDIO\n    INDEX 01 00:00:00\n");
+        let mut cues = CueCache::default();
+        assert_eq!(cues.find_for(&dir.file("album.flac")).unwrap().title.as_deref(), Some("Album"));
+        //Single track isn't album
+        assert!(cues.find_for(&dir.file("single.flac")).is_none());
+        assert_eq!(cues.folders.len(), 1);
+    }
+
+    #[test]
+    fn time() {
+        assert_eq!(parse_time("03:59:70"), Some((3 * 60 + 59) * 75 + 70));
+        assert_eq!(format_time((3 * 60 + 59) * 75 + 70), "03:59:70");
+        assert_eq!(parse_time("03:xx:00"), None);
+    }
+}
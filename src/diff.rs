@@ -0,0 +1,187 @@
+use serde_json::{json, Value};
+
+use crate::backend::{AudioTag, Field, GenreMode};
+use crate::tagger::TaggerConfig;
+use crate::art::Art;
+use crate::cue::CueFile;
+
+#[derive(Debug, Clone)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Vec<String>,
+    pub after: Vec<String>
+}
+
+impl FieldChange {
+    pub fn changed(&self) -> bool {
+        self.before != self.after
+    }
+}
+
+//Changes written (or which would be written) to single file
+#[derive(Debug, Clone)]
+pub struct FileDiff {
+    pub path: String,
+    pub changes: Vec<FieldChange>,
+    pub art_before: bool,
+    pub art_after: bool,
+    //Art was replaced or removed, even if it had art before and after
+    pub art_changed: bool,
+    //New path by organizer
+    pub moved_to: Option<String>
+}
+
+impl FileDiff {
+    pub fn has_changes(&self) -> bool {
+        self.art_before != self.art_after || self.art_changed || self.moved_to.is_some() || self.changes.iter().any(|c| c.changed())
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "path": self.path,
+            "changes": self.changes.iter().map(|c| json!({
+                "field": c.field,
+                "before": c.before,
+                "after": c.after,
+                "changed": c.changed()
+            })).collect::<Vec<Value>>(),
+            "art": {
+                "before": self.art_before,
+                "after": self.art_after
+            },
+            "movedTo": self.moved_to
+        })
+    }
+}
+
+//Records every change made to wrapped tag
+pub struct DiffTag {
+    tag: Box<dyn AudioTag>,
+    changes: Vec<FieldChange>,
+    art_before: bool,
+    art_after: bool,
+    art_changed: bool
+}
+
+impl DiffTag {
+    pub fn new(tag: Box<dyn AudioTag>) -> DiffTag {
+        let art = tag.has_art();
+        DiffTag { tag, changes: vec![], art_before: art, art_after: art, art_changed: false }
+    }
+
+    pub fn into_diff(self, path: &str) -> FileDiff {
+        FileDiff {
+            path: path.to_owned(),
+            changes: self.changes,
+            art_before: self.art_before,
+            art_after: self.art_after,
+            art_changed: self.art_changed,
+            moved_to: None
+        }
+    }
+}
+
+impl AudioTag for DiffTag {
+    fn get(&self, field: &Field) -> Option<Vec<String>> {
+        self.tag.get(field)
+    }
+
+    fn set(&mut self, field: &Field, values: Vec<String>) {
+        let name = field.name();
+        //Same field written twice = keep original value
+        match self.changes.iter_mut().find(|c| c.field == name) {
+            Some(change) => change.after = values.clone(),
+            None => self.changes.push(FieldChange {
+                field: name,
+                before: self.tag.get(field).unwrap_or_default(),
+                after: values.clone()
+            })
+        }
+        self.tag.set(field, values);
+    }
+
+    fn has_art(&self) -> bool {
+        self.tag.has_art()
+    }
+
+    //Not values from Discogs, but changed by writing
+    fn migrate(&mut self) -> Vec<FieldChange> {
+        let changes = self.tag.migrate();
+        self.changes.extend(changes.iter().cloned());
+        changes
+    }
+
+    fn set_art(&mut self, art: Art) {
+        self.art_changed = true;
+        self.art_after = true;
+        self.tag.set_art(art);
+    }
+
+    fn remove_art(&mut self) {
+        self.art_changed = true;
+        self.art_after = false;
+        self.tag.remove_art();
+    }
+
+    fn save(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.tag.save(path)
+    }
+
+    fn genre_mode(&self, config: &TaggerConfig) -> GenreMode {
+        self.tag.genre_mode(config)
+    }
+
+    fn set_cue(&mut self, file: &CueFile) -> bool {
+        self.tag.set_cue(file)
+    }
+}
+
+//All diffs as JSON array
+pub fn save_json(diffs: &[FileDiff], path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let json = Value::Array(diffs.iter().map(|d| d.to_json()).collect());
+    std::fs::write(path, serde_json::to_string_pretty(&json)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{MemoryTag, values};
+
+    #[test]
+    fn migration_is_recorded() {
+        let mut tag = DiffTag::new(Box::new(MemoryTag::new(&[("YEAR", &["2001"])])));
+        tag.migrate();
+        //Written value compared with migrated one
+        tag.set(&Field::Date, values(&["2001-05-01"]));
+        let diff = tag.into_diff("song.mp3");
+        let changes: Vec<(&str, Vec<String>, Vec<String>)> = diff.changes.iter().map(|c| (c.field.as_str(), c.before.clone(), c.after.clone())).collect();
+        assert_eq!(changes, vec![
+            ("YEAR", values(&["2001"]), vec![]),
+            ("Date", vec![], values(&["2001-05-01"]))
+        ]);
+        assert!(diff.has_changes());
+    }
+
+    #[test]
+    fn same_field_written_twice() {
+        let mut tag = DiffTag::new(Box::new(MemoryTag::new(&[("Genre", &["Pop"])])));
+        tag.set(&Field::Genre, values(&["Rock"]));
+        tag.set(&Field::Genre, values(&["Techno"]));
+        let diff = tag.into_diff("song.mp3");
+        assert_eq!(diff.changes.len(), 1);
+        assert_eq!((diff.changes[0].before.clone(), diff.changes[0].after.clone()), (values(&["Pop"]), values(&["Techno"])));
+    }
+
+    #[test]
+    fn art_changes() {
+        let mut tag = DiffTag::new(Box::new(MemoryTag { art: true, ..MemoryTag::new(&[]) }));
+        assert!(!DiffTag::new(Box::new(MemoryTag::new(&[]))).into_diff("a.mp3").has_changes());
+        //Replaced cover has art before and after
+        tag.remove_art();
+        tag.set_art(Art { data: vec![], mime: String::from("image/jpeg"), width: 1, height: 1, depth: 24 });
+        let diff = tag.into_diff("song.mp3");
+        assert!(diff.art_before && diff.art_after && diff.art_changed);
+        assert!(diff.has_changes());
+    }
+}
@@ -188,6 +188,7 @@ pub struct ReleaseMaster {
     pub art_url: Option<String>,
     pub year: Option<i16>,
     pub label: Option<Vec<String>>,
+    pub catno: Option<String>,
     pub artists: Option<Vec<String>>,
     pub extra_artists: Option<Vec<String>>,
     pub tracks: Option<Vec<Track>>,
@@ -221,6 +222,11 @@ impl ReleaseMaster {
                     }
                 }
             },
+            //Search results have catno directly
+            catno: match json["catno"].as_str() {
+                Some(c) => Some(c.to_owned()),
+                None => json["labels"].as_array().and_then(|l| l.first()).and_then(|l| l["catno"].as_str()).map(|c| c.to_owned())
+            },
             url: json["uri"].as_str().unwrap_or("").to_owned(),
             country: json["country"].as_str().unwrap_or("").to_owned(),
             //Available only in full JSON
@@ -266,6 +272,14 @@ impl ReleaseMaster {
             }
         })
     }
+
+    //Search results have relative uri
+    pub fn full_url(&self) -> String {
+        if self.url.starts_with('/') {
+            return format!("https://www.discogs.com{}", self.url);
+        }
+        self.url.to_owned()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -0,0 +1,179 @@
+use metaflac::block::{Block, BlockType, VorbisComment, Picture as FLACPicture, PictureType as FLACPictureType};
+use metaflac::block::{CueSheet, CueSheetTrack, CueSheetTrackIndex};
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+use crate::backend::{AudioTag, Backend, Field};
+use crate::tagger::{MusicFileType, TaggerConfig};
+use crate::mapping::TagKeys;
+use crate::art::Art;
+use crate::cue::CueFile;
+use crate::properties::{self, AudioProperties};
+use crate::flacid3;
+use crate::ui;
+
+pub struct FlacBackend;
+
+impl Backend for FlacBackend {
+    fn file_type(&self) -> MusicFileType {
+        MusicFileType::FLAC
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &[".flac"]
+    }
+
+    fn detect(&self, header: &[u8]) -> bool {
+        header.starts_with(b"fLaC")
+    }
+
+    fn read(&self, path: &str, config: &TaggerConfig) -> Result<Box<dyn AudioTag>, Box<dyn std::error::Error>> {
+        //Load header
+        let mut file = File::open(path)?;
+        let id3_size = flacid3::read_id3_size(&mut file)?;
+        //Check for FLAC with ID3, gets converted when saving
+        if id3_size > 0 && !config.repair_flac_id3 {
+            ui::print_warning(&format!("FLAC with ID3 tags are not supported, and should not be used. Enable ID3 repair or convert this track metadata to Vorbis! {}", path));
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "FLAC ID3 not supported!").into());
+        }
+        //Check if FLAC
+        let mut header: [u8; 4] = [0; 4];
+        file.seek(SeekFrom::Start(id3_size))?;
+        file.read_exact(&mut header)?;
+        if &header != b"fLaC" {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Not a valid FLAC!").into());
+        }
+        //Load tag
+        let tag = match id3_size {
+            0 => metaflac::Tag::read_from_path(path)?,
+            _ => {
+                file.seek(SeekFrom::Start(id3_size))?;
+                let mut tag = metaflac::Tag::read_from(&mut file)?;
+                //Existing Vorbis comments have priority
+                flacid3::id3_to_flac(&id3::Tag::read_from_path(path)?, &mut tag, &config.keys.vorbis_label);
+                tag
+            }
+        };
+
+        Ok(Box::new(FlacTag {
+            path: path.to_owned(),
+            tag,
+            keys: config.keys.clone(),
+            strip_id3: id3_size > 0
+        }))
+    }
+
+    fn properties(&self, path: &str) -> Result<AudioProperties, Box<dyn std::error::Error>> {
+        properties::flac(path)
+    }
+}
+
+struct FlacTag {
+    path: String,
+    tag: metaflac::Tag,
+    keys: TagKeys,
+    strip_id3: bool
+}
+
+impl AudioTag for FlacTag {
+    fn get(&self, field: &Field) -> Option<Vec<String>> {
+        get_vorbis(self.tag.vorbis_comments()?, field, &self.keys)
+    }
+
+    fn set(&mut self, field: &Field, values: Vec<String>) {
+        set_vorbis(self.tag.vorbis_comments_mut(), field, values, &self.keys);
+    }
+
+    fn has_art(&self) -> bool {
+        self.tag.pictures().count() > 0
+    }
+
+    fn set_art(&mut self, art: Art) {
+        self.remove_art();
+        self.tag.push_block(Block::Picture(flac_picture(art)));
+    }
+
+    fn remove_art(&mut self) {
+        self.tag.remove_picture_type(FLACPictureType::CoverFront);
+    }
+
+    fn save(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        //Path is temporary copy from backend::save, replaced atomically after audio is verified
+        if self.strip_id3 {
+            let data = flacid3::strip_id3(&std::fs::read(path)?, &mut self.tag)?;
+            std::fs::write(path, data)?;
+            println!("Moved ID3 tag to Vorbis comments: {}", self.path);
+            return Ok(());
+        }
+        self.tag.write_to_path(path)?;
+        Ok(())
+    }
+
+    fn set_cue(&mut self, file: &CueFile) -> bool {
+        let (sample_rate, total_samples) = match self.tag.get_streaminfo() {
+            Some(info) => (info.sample_rate as u64, info.total_samples),
+            None => return false
+        };
+        //Offsets in samples, CD frames are 1/75 s
+        let is_cd = sample_rate == 44100;
+        let mut tracks: Vec<CueSheetTrack> = file.tracks.iter().map(|track| {
+            let offset = track.indexes.first().map(|(_, t)| *t).unwrap_or(0) as u64 * sample_rate / 75;
+            CueSheetTrack {
+                offset,
+                number: track.number,
+                isrc: String::new(),
+                is_audio: true,
+                pre_emphasis: false,
+                indices: track.indexes.iter().map(|(n, t)| CueSheetTrackIndex {
+                    offset: (*t as u64 * sample_rate / 75).saturating_sub(offset),
+                    point_num: *n
+                }).collect()
+            }
+        }).collect();
+        //Lead-out
+        tracks.push(CueSheetTrack {
+            offset: total_samples,
+            number: if is_cd { 170 } else { 255 },
+            isrc: String::new(),
+            is_audio: true,
+            pre_emphasis: false,
+            indices: vec![]
+        });
+        self.tag.remove_blocks(BlockType::CueSheet);
+        self.tag.push_block(Block::CueSheet(CueSheet {
+            catalog_num: String::new(),
+            num_leadin: if is_cd { 88200 } else { 0 },
+            is_cd,
+            tracks
+        }));
+        true
+    }
+}
+
+//Vorbis comment fields, shared by FLAC and Ogg
+pub fn get_vorbis(vorbis: &VorbisComment, field: &Field, keys: &TagKeys) -> Option<Vec<String>> {
+    vorbis.get(&field.vorbis_key(keys)?).cloned()
+}
+
+pub fn set_vorbis(vorbis: &mut VorbisComment, field: &Field, values: Vec<String>, keys: &TagKeys) {
+    match (field.vorbis_key(keys), values.is_empty()) {
+        (Some(key), true) => vorbis.remove(&key),
+        (Some(key), false) => vorbis.set(key, values),
+        _ => {}
+    }
+}
+
+//Front cover picture block, also used in Ogg METADATA_BLOCK_PICTURE
+pub fn flac_picture(art: Art) -> FLACPicture {
+    FLACPicture {
+        picture_type: FLACPictureType::CoverFront,
+        mime_type: art.mime,
+        description: String::new(),
+        width: art.width,
+        height: art.height,
+        depth: art.depth,
+        num_colors: 0,
+        data: art.data
+    }
+}
@@ -0,0 +1,198 @@
+use id3::frame::PictureType as ID3PictureType;
+use metaflac::block::{Block, VorbisComment, Picture as FLACPicture, PictureType as FLACPictureType};
+use std::fs::File;
+use std::io::prelude::*;
+use std::convert::TryInto;
+
+use crate::art;
+
+//ID3 frame to Vorbis comment
+const FRAMES: [(&str, &str); 15] = [
+    ("TIT2", "TITLE"),
+    ("TPE1", "ARTIST"),
+    ("TALB", "ALBUM"),
+    ("TPE2", "ALBUMARTIST"),
+    ("TCON", "GENRE"),
+    ("TRCK", "TRACKNUMBER"),
+    ("TPOS", "DISCNUMBER"),
+    ("TDRC", "DATE"),
+    ("TYER", "DATE"),
+    ("TPUB", "LABEL"),
+    ("TCOM", "COMPOSER"),
+    ("TIT1", "GROUPING"),
+    ("TBPM", "BPM"),
+    ("TKEY", "KEY"),
+    ("TSRC", "ISRC")
+];
+
+//Size of ID3 tag at start of file including header, None if there is no ID3
+pub fn id3_size(header: &[u8; 10]) -> Option<u64> {
+    if &header[0..3] != b"ID3" {
+        return None;
+    }
+    //Syncsafe integer
+    let size = header[6..10].iter().fold(0u64, |acc, b| (acc << 7) | (*b & 0x7f) as u64);
+    //Footer flag
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    Some(10 + size + footer)
+}
+
+//Read ID3 size from start of file, 0 if there is no ID3
+pub fn read_id3_size(file: &mut File) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut header = [0u8; 10];
+    file.read_exact(&mut header)?;
+    Ok(id3_size(&header).unwrap_or(0))
+}
+
+//Copy ID3 fields and pictures which are missing in FLAC
+pub fn id3_to_flac(id3: &id3::Tag, tag: &mut metaflac::Tag, label_key: &str) {
+    id3_to_vorbis(id3, tag.vorbis_comments_mut(), label_key);
+    if tag.pictures().count() > 0 {
+        return;
+    }
+    for picture in id3.pictures() {
+        let (width, height) = art::dimensions(&picture.data).unwrap_or((0, 0));
+        tag.push_block(Block::Picture(FLACPicture {
+            picture_type: match picture.picture_type {
+                ID3PictureType::CoverFront => FLACPictureType::CoverFront,
+                ID3PictureType::CoverBack => FLACPictureType::CoverBack,
+                _ => FLACPictureType::Other
+            },
+            mime_type: picture.mime_type.to_owned(),
+            description: picture.description.to_owned(),
+            width,
+            height,
+            depth: 24,
+            num_colors: 0,
+            data: picture.data.to_owned()
+        }));
+    }
+}
+
+//FLAC metadata + original audio frames, without the prepended ID3
+pub fn strip_id3(data: &[u8], tag: &mut metaflac::Tag) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if data.len() < 10 {
+        return Err("Invalid FLAC!".into());
+    }
+    let offset = id3_size(data[0..10].try_into()?).unwrap_or(0) as usize;
+    //Don't touch files with unexpected data between ID3 and FLAC
+    if data.len() < offset + 4 || &data[offset..offset+4] != b"fLaC" {
+        return Err("Unexpected data after ID3 tag, not repairing!".into());
+    }
+    let audio = offset + metadata_size(&data[offset..])?;
+    if audio > data.len() {
+        return Err("Invalid FLAC metadata!".into());
+    }
+    let mut out = vec![];
+    tag.write_to(&mut out)?;
+    out.extend(&data[audio..]);
+    Ok(out)
+}
+
+//Size of fLaC marker + metadata blocks
+pub fn metadata_size(data: &[u8]) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut pos = 4;
+    loop {
+        if pos + 4 > data.len() {
+            return Err("Invalid FLAC metadata!".into());
+        }
+        let last = data[pos] & 0x80 != 0;
+        let length = u32::from_be_bytes([0, data[pos+1], data[pos+2], data[pos+3]]) as usize;
+        pos += 4 + length;
+        //Truncated file, block longer than data
+        if pos > data.len() {
+            return Err("Invalid FLAC metadata!".into());
+        }
+        if last {
+            return Ok(pos);
+        }
+    }
+}
+
+//Copy ID3 fields which are missing in Vorbis
+fn id3_to_vorbis(id3: &id3::Tag, vorbis: &mut VorbisComment, label_key: &str) {
+    let mut values: Vec<(String, Vec<String>)> = vec![];
+    for (frame, key) in FRAMES.iter() {
+        let key = if *key == "LABEL" { label_key } else { key };
+        if let Some(text) = id3.get(frame).and_then(|f| f.content().text()) {
+            values.push((key.to_owned(), text.split('\0').map(String::from).collect()));
+        }
+    }
+    if let Some(comment) = id3.comments().find(|c| c.description.is_empty()) {
+        values.push((String::from("COMMENT"), vec![comment.text.to_owned()]));
+    }
+    for text in id3.extended_texts() {
+        values.push((text.description.to_uppercase(), vec![text.value.to_owned()]));
+    }
+
+    for (key, value) in values {
+        if vorbis.get(&key).is_none() && value.iter().any(|v| !v.trim().is_empty()) {
+            vorbis.set(key, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //ID3 header with syncsafe size
+    fn id3_header(size: u32) -> Vec<u8> {
+        let mut header = b"ID3\x03\x00\x00".to_vec();
+        header.extend((0..4).rev().map(|i| ((size >> (i * 7)) & 0x7f) as u8));
+        header
+    }
+
+    //fLaC marker and single STREAMINFO block
+    fn flac_metadata() -> Vec<u8> {
+        let mut data = b"fLaC".to_vec();
+        data.extend(&[0x80, 0, 0, 34]);
+        data.extend(&[0u8; 34]);
+        data
+    }
+
+    #[test]
+    fn id3_size_syncsafe() {
+        let header: [u8; 10] = id3_header(300).try_into().unwrap();
+        assert_eq!(id3_size(&header), Some(310));
+        assert_eq!(id3_size(b"fLaC\0\0\0\0\0\0"), None);
+    }
+
+    #[test]
+    fn metadata_size_truncated() {
+        let data = flac_metadata();
+        assert_eq!(metadata_size(&data).unwrap(), data.len());
+        assert!(metadata_size(&data[..20]).is_err());
+        assert!(metadata_size(&data[..6]).is_err());
+        //Block which isn't last, but nothing follows
+        let mut not_last = data.clone();
+        not_last[4] = 0;
+        assert!(metadata_size(&not_last).is_err());
+    }
+
+    #[test]
+    fn strip_id3_keeps_audio() {
+        let mut data = id3_header(5);
+        data.extend(&[0u8; 5]);
+        data.extend(flac_metadata());
+        data.extend(b"AUDIO");
+        let out = strip_id3(&data, &mut metaflac::Tag::new()).unwrap();
+        assert!(out.ends_with(b"AUDIO"));
+        assert!(!out.starts_with(b"ID3"));
+    }
+
+    #[test]
+    fn strip_id3_rejects_invalid() {
+        let mut tag = metaflac::Tag::new();
+        assert!(strip_id3(b"ID3", &mut tag).is_err());
+        //Garbage between ID3 and FLAC
+        let mut data = id3_header(0);
+        data.extend(b"JUNK");
+        data.extend(flac_metadata());
+        assert!(strip_id3(&data, &mut tag).is_err());
+        //Truncated metadata
+        let mut data = id3_header(0);
+        data.extend(&flac_metadata()[..30]);
+        assert!(strip_id3(&data, &mut tag).is_err());
+    }
+}
@@ -0,0 +1,450 @@
+use std::collections::HashMap;
+use id3::{Tag, Version, Timestamp, ErrorKind};
+use id3::frame::{Picture, PictureType, Comment};
+use chrono::{NaiveDate, Datelike};
+
+use crate::backend::{AudioTag, Backend, Field, GenreMode};
+use crate::diff::FieldChange;
+use crate::tagger::{MusicFileType, TaggerConfig};
+use crate::mapping::TagKeys;
+use crate::ape::{ApeMode, ApeTag};
+use crate::ui;
+use crate::art::Art;
+use crate::properties::{self, AudioProperties};
+use crate::id3v1::Id3v1;
+use crate::wav;
+
+//MP3, AIFF and WAV
+pub struct Id3Backend {
+    file_type: MusicFileType
+}
+
+impl Id3Backend {
+    pub fn new(file_type: MusicFileType) -> Id3Backend {
+        Id3Backend { file_type }
+    }
+}
+
+impl Backend for Id3Backend {
+    fn file_type(&self) -> MusicFileType {
+        self.file_type.clone()
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        match self.file_type {
+            MusicFileType::MP3 => &[".mp3"],
+            MusicFileType::AIFF => &[".aif", ".aiff"],
+            MusicFileType::WAV => &[".wav"],
+            _ => &[]
+        }
+    }
+
+    fn detect(&self, header: &[u8]) -> bool {
+        match self.file_type {
+            //MPEG frame sync
+            MusicFileType::MP3 => header.len() >= 2 && header[0] == 0xFF && header[1] & 0xE0 == 0xE0,
+            MusicFileType::AIFF => header.len() >= 12 && &header[0..4] == b"FORM" && &header[8..12] == b"AIFF",
+            MusicFileType::WAV => header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"WAVE",
+            _ => false
+        }
+    }
+
+    fn read(&self, path: &str, config: &TaggerConfig) -> Result<Box<dyn AudioTag>, Box<dyn std::error::Error>> {
+        let version = match config.id3v23 {
+            true => Version::Id3v23,
+            false => Version::Id3v24
+        };
+        let tag = match self.file_type {
+            MusicFileType::AIFF => or_new(Tag::read_from_aiff(path))?,
+            MusicFileType::WAV => match wav::read_id3(path)? {
+                Some(tag) => tag,
+                None => wav::info_tag(path)?
+            },
+            //Fallback to ID3v1
+            _ => match Tag::read_from_path(path) {
+                Err(e) if matches!(e.kind, ErrorKind::NoTag) => match Id3v1::read_from_path(path)? {
+                    Some(v1) => v1.to_id3(),
+                    None => Tag::new()
+                },
+                r => r?
+            }
+        };
+        //APE at end of MP3, only extra data so broken one is left untouched
+        let ape = match self.file_type {
+            MusicFileType::MP3 if config.ape_mp3 == ApeMode::Read || config.ape_mp3 == ApeMode::Update => {
+                ApeTag::read_from_path(path).unwrap_or_else(|e| {
+                    ui::print_warning(&format!("Invalid APE tag, ignoring it: {} {}", path, e));
+                    None
+                })
+            },
+            _ => None
+        };
+
+        Ok(Box::new(Id3Tag {
+            file_type: self.file_type.clone(),
+            tag,
+            version,
+            keys: config.keys.clone(),
+            multi_value: config.id3_multi_value && !config.id3v23,
+            separator: config.id3_separator.to_owned(),
+            artist_separator: config.artist_separator.to_owned(),
+            wav_info: config.wav_info,
+            ape,
+            ape_mode: config.ape_mp3,
+            id3v1: config.id3v1,
+            migrate: config.id3_migrate
+        }))
+    }
+
+    fn properties(&self, path: &str) -> Result<AudioProperties, Box<dyn std::error::Error>> {
+        match self.file_type {
+            MusicFileType::AIFF => properties::aiff(path),
+            MusicFileType::WAV => properties::wav(path),
+            _ => properties::mp3(path)
+        }
+    }
+}
+
+//Missing tag = empty tag
+fn or_new(result: id3::Result<Tag>) -> id3::Result<Tag> {
+    match result {
+        Err(e) if matches!(e.kind, ErrorKind::NoTag) => Ok(Tag::new()),
+        r => r
+    }
+}
+
+struct Id3Tag {
+    file_type: MusicFileType,
+    tag: Tag,
+    version: Version,
+    keys: TagKeys,
+    //Null separated values, only ID3v2.4
+    multi_value: bool,
+    separator: String,
+    artist_separator: String,
+    wav_info: bool,
+    ape: Option<ApeTag>,
+    ape_mode: ApeMode,
+    //Mirror to ID3v1, only MP3
+    id3v1: bool,
+    //Convert frames of other ID3 versions
+    migrate: bool
+}
+
+impl Id3Tag {
+    //Frame of field
+    fn frame(&self, field: &Field) -> Option<String> {
+        match field {
+            Field::Title => Some(String::from("TIT2")),
+            Field::Artist => Some(String::from("TPE1")),
+            Field::Album => Some(String::from("TALB")),
+            Field::Label => Some(self.keys.id3_label.to_owned()),
+            Field::Date => Some(String::from("TDRC")),
+            Field::Track => Some(String::from("TRCK")),
+            Field::Genre => Some(String::from("TCON")),
+            Field::Style => Some(self.keys.id3_style.to_owned()),
+            Field::Custom { id3, .. } => id3.to_owned()
+        }
+    }
+
+    //Join multiple values for ID3 text frame
+    fn join(&self, values: &[String]) -> String {
+        match self.multi_value {
+            true => values.join("\0"),
+            false => values.join(&self.separator)
+        }
+    }
+
+    //Split ID3 text frame into values, both null separated and joined by separator
+    fn split(&self, value: &str) -> Vec<String> {
+        value.split('\0')
+            .flat_map(|v| v.split(self.separator.as_str()))
+            .map(|v| v.trim().to_owned())
+            .filter(|v| !v.is_empty())
+            .collect()
+    }
+
+    fn remove(&mut self, field: &Field) {
+        match field {
+            Field::Date => {
+                for frame in &["TDRC", "TYER", "TDAT", "TIME"] {
+                    self.tag.remove(frame);
+                }
+            },
+            _ => match self.frame(field) {
+                Some(frame) if frame == "COMM" => self.tag.remove_comment(Some(""), None),
+                Some(frame) => match frame.strip_prefix("TXXX:") {
+                    Some(description) => self.tag.remove_extended_text(Some(description), None),
+                    None => {
+                        self.tag.remove(frame);
+                    }
+                },
+                None => {}
+            }
+        }
+    }
+
+    fn set_date(&mut self, value: &str) {
+        let year = match value.get(0..4).and_then(|y| y.parse::<i32>().ok()) {
+            Some(year) => year,
+            None => return
+        };
+        let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok();
+        match self.version {
+            //ID3v2.3
+            Version::Id3v23 => {
+                self.tag.remove_date_recorded();
+                self.tag.set_text("TYER", year.to_string());
+                //Year only = drop day and month of previous date
+                match date {
+                    Some(date) => self.tag.set_text("TDAT", format!("{:02}{:02}", date.day(), date.month())),
+                    None => {
+                        self.tag.remove("TDAT");
+                    }
+                }
+            },
+            //ID3v2.4
+            _ => {
+                self.tag.remove("TDAT");
+                self.tag.remove("TYER");
+                self.tag.set_date_recorded(Timestamp {
+                    year,
+                    month: date.map(|d| d.month() as u8),
+                    day: date.map(|d| d.day() as u8),
+                    hour: None,
+                    minute: None,
+                    second: None
+                });
+            }
+        }
+    }
+}
+
+impl AudioTag for Id3Tag {
+    fn get(&self, field: &Field) -> Option<Vec<String>> {
+        //Some players prefer APE over ID3
+        if let Some(ape) = self.ape.as_ref().filter(|_| matches!(field, Field::Title | Field::Artist)) {
+            if let Some(values) = ape.get_field(field, &self.keys) {
+                return Some(values);
+            }
+        }
+        match field {
+            Field::Date => match self.version {
+                //TDAT = DDMM
+                //TDRC of not migrated v2.4 tag
+                Version::Id3v23 => get_id3_text(&self.tag, "TYER").map(|year| {
+                    match get_id3_text(&self.tag, "TDAT").filter(|d| d.len() == 4 && d.chars().all(|c| c.is_ascii_digit())) {
+                        Some(d) => format!("{}-{}-{}", year, &d[2..4], &d[0..2]),
+                        None => year
+                    }
+                }).or_else(|| self.tag.date_recorded().map(|d| d.to_string())),
+                _ => self.tag.date_recorded().map(|d| d.to_string())
+            }.map(|d| vec![d]),
+            Field::Track => self.tag.track().map(|t| vec![t.to_string()]),
+            Field::Label | Field::Genre | Field::Style => self.frame(field).and_then(|f| get_id3_text(&self.tag, &f)).map(|v| self.split(&v)),
+            _ => self.frame(field).and_then(|f| get_id3_text(&self.tag, &f)).map(|v| vec![v])
+        }
+    }
+
+    fn set(&mut self, field: &Field, values: Vec<String>) {
+        if let (Some(ape), ApeMode::Update) = (self.ape.as_mut(), self.ape_mode) {
+            ape.set_field(field, values.clone(), &self.keys);
+        }
+        if values.is_empty() {
+            self.remove(field);
+            return;
+        }
+        match field {
+            Field::Artist => self.tag.set_artist(values.join(&self.artist_separator)),
+            Field::Date => {
+                if let Some(date) = values.first() {
+                    self.set_date(date);
+                }
+            },
+            Field::Track => {
+                if let Some(track) = values.first().and_then(|t| t.parse().ok()) {
+                    self.tag.set_track(track);
+                }
+            },
+            _ => {
+                if let Some(frame) = self.frame(field) {
+                    let value = self.join(&values);
+                    set_id3_text(&mut self.tag, &frame, value);
+                }
+            }
+        }
+    }
+
+    fn has_art(&self) -> bool {
+        self.tag.pictures().count() > 0
+    }
+
+    fn remove_art(&mut self) {
+        if let (Some(ape), ApeMode::Update) = (self.ape.as_mut(), self.ape_mode) {
+            ape.remove_art();
+        }
+        self.tag.remove_picture_by_type(PictureType::CoverFront);
+    }
+
+    fn set_art(&mut self, art: Art) {
+        if let (Some(ape), ApeMode::Update) = (self.ape.as_mut(), self.ape_mode) {
+            ape.set_art(art.clone());
+        }
+        self.tag.remove_picture_by_type(PictureType::CoverFront);
+        self.tag.add_picture(Picture {
+            mime_type: art.mime,
+            picture_type: PictureType::CoverFront,
+            description: "Cover".to_string(),
+            data: art.data
+        });
+    }
+
+    fn save(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match self.file_type {
+            MusicFileType::AIFF => self.tag.write_to_aiff(path, self.version)?,
+            MusicFileType::WAV => wav::write_wav(path, &self.tag, self.version, self.wav_info)?,
+            _ => {
+                self.tag.write_to_path(path, self.version)?;
+                match (self.ape.as_ref(), self.ape_mode) {
+                    (Some(ape), ApeMode::Update) => ape.write_to_path(path)?,
+                    (_, ApeMode::Strip) => {
+                        ApeTag::remove_from_path(path)?;
+                    },
+                    _ => {}
+                }
+                //ID3v1 is after APE
+                if self.id3v1 {
+                    Id3v1::from_id3(&self.tag).write_to_path(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn genre_mode(&self, config: &TaggerConfig) -> GenreMode {
+        config.id3_genre
+    }
+
+    fn migrate(&mut self) -> Vec<FieldChange> {
+        if !self.migrate {
+            return vec![];
+        }
+        let before = text_frames(&self.tag);
+        migrate(&mut self.tag, self.version, &self.separator);
+        let after = text_frames(&self.tag);
+        let mut frames: Vec<&String> = before.keys().chain(after.keys()).collect();
+        frames.sort();
+        frames.dedup();
+        frames.into_iter().map(|frame| FieldChange {
+            field: frame.to_owned(),
+            before: before.get(frame).cloned().into_iter().collect(),
+            after: after.get(frame).cloned().into_iter().collect()
+        }).filter(|c| c.changed()).collect()
+    }
+}
+
+//Convert version specific frames, v2.2 frame ids are converted to v2.3 by id3 when reading
+pub fn migrate(tag: &mut Tag, version: Version, separator: &str) {
+    match version {
+        Version::Id3v24 => {
+            //TYER + TDAT (DDMM) + TIME (HHMM) = TDRC
+            let year = get_id3_text(tag, "TYER").and_then(|y| y.trim().parse().ok()).filter(|_| tag.date_recorded().is_none());
+            if let Some(year) = year {
+                let date = get_id3_text(tag, "TDAT").filter(|d| d.len() == 4);
+                let time = get_id3_text(tag, "TIME").filter(|t| t.len() == 4);
+                let part = |v: &Option<String>, r: std::ops::Range<usize>| v.as_ref().and_then(|v| v.get(r)).and_then(|v| v.parse().ok());
+                tag.set_date_recorded(Timestamp {
+                    year,
+                    month: part(&date, 2..4),
+                    day: part(&date, 0..2),
+                    hour: part(&time, 0..2),
+                    minute: part(&time, 2..4),
+                    second: None
+                });
+            }
+            if let Some(year) = get_id3_text(tag, "TORY").and_then(|y| y.trim().parse().ok()) {
+                tag.set_text("TDOR", Timestamp { year, month: None, day: None, hour: None, minute: None, second: None }.to_string());
+            }
+            if let Some(people) = get_id3_text(tag, "IPLS") {
+                tag.set_text("TIPL", people);
+            }
+            //Removed in v2.4
+            for frame in &["TYER", "TDAT", "TIME", "TORY", "TRDA", "TSIZ", "IPLS", "EQUA", "RVAD"] {
+                tag.remove(frame);
+            }
+        },
+        _ => {
+            //TDRC = TYER + TDAT + TIME
+            if let Some(date) = tag.date_recorded() {
+                tag.set_text("TYER", date.year.to_string());
+                if let (Some(month), Some(day)) = (date.month, date.day) {
+                    tag.set_text("TDAT", format!("{:02}{:02}", day, month));
+                }
+                if let (Some(hour), Some(minute)) = (date.hour, date.minute) {
+                    tag.set_text("TIME", format!("{:02}{:02}", hour, minute));
+                }
+            }
+            if let Some(year) = get_id3_text(tag, "TDOR").and_then(|d| d.get(0..4).map(String::from)) {
+                tag.set_text("TORY", year);
+            }
+            let people: Vec<String> = ["TIPL", "TMCL"].iter().filter_map(|f| get_id3_text(tag, f)).collect();
+            if !people.is_empty() {
+                tag.set_text("IPLS", people.join("\0"));
+            }
+            //Only in v2.4
+            for frame in &["TDRC", "TDOR", "TDRL", "TDTG", "TDEN", "TIPL", "TMCL", "TMOO", "TPRO", "TSST", "RVA2", "EQU2", "ASPI", "SEIK", "SIGN"] {
+                tag.remove(frame);
+            }
+            //No null separated values in v2.3
+            let joined: Vec<(String, String)> = tag.frames()
+                .filter(|f| f.id().starts_with('T') && f.id() != "TXXX")
+                .filter_map(|f| f.content().text().filter(|t| t.contains('\0')).map(|t| (f.id().to_owned(), t.split('\0').collect::<Vec<_>>().join(separator))))
+                .collect();
+            for (frame, text) in joined {
+                tag.set_text(frame, text);
+            }
+        }
+    }
+}
+
+//Text of every text frame by ID
+fn text_frames(tag: &Tag) -> HashMap<String, String> {
+    tag.frames().filter_map(|f| f.content().text().map(|t| (f.id().to_owned(), t.to_owned()))).collect()
+}
+
+//Get text of ID3 frame, supports COMM and TXXX:DESCRIPTION
+pub fn get_id3_text(tag: &Tag, frame: &str) -> Option<String> {
+    if frame == "COMM" {
+        return tag.comments().find(|c| c.description.is_empty()).map(|c| c.text.to_owned());
+    }
+    if let Some(description) = frame.strip_prefix("TXXX:") {
+        return tag.extended_texts().find(|t| t.description == description).map(|t| t.value.to_owned());
+    }
+    tag.get(frame).and_then(|f| f.content().text()).map(String::from)
+}
+
+//Write text to ID3 frame, supports COMM and TXXX:DESCRIPTION
+pub fn set_id3_text(tag: &mut Tag, frame: &str, value: String) {
+    //Comment
+    if frame == "COMM" {
+        tag.remove_comment(Some(""), None);
+        tag.add_comment(Comment {
+            lang: String::from("eng"),
+            description: String::new(),
+            text: value
+        });
+        return;
+    }
+    //User defined text
+    if let Some(description) = frame.strip_prefix("TXXX:") {
+        tag.remove_extended_text(Some(description), None);
+        tag.add_extended_text(description, value);
+        return;
+    }
+    //Only text frames
+    if frame.len() != 4 || !frame.starts_with('T') {
+        eprintln!("Unsupported ID3 frame: {}", frame);
+        return;
+    }
+    tag.set_text(frame, value);
+}
@@ -0,0 +1,199 @@
+use id3::Tag;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+//Standard ID3v1 genres, index = genre byte
+const GENRES: [&str; 80] = [
+    "Blues", "Classic Rock", "Country", "Dance", "Disco", "Funk", "Grunge", "Hip-Hop", "Jazz", "Metal",
+    "New Age", "Oldies", "Other", "Pop", "R&B", "Rap", "Reggae", "Rock", "Techno", "Industrial",
+    "Alternative", "Ska", "Death Metal", "Pranks", "Soundtrack", "Euro-Techno", "Ambient", "Trip-Hop", "Vocal", "Jazz+Funk",
+    "Fusion", "Trance", "Classical", "Instrumental", "Acid", "House", "Game", "Sound Clip", "Gospel", "Noise",
+    "AlternRock", "Bass", "Soul", "Punk", "Space", "Meditative", "Instrumental Pop", "Instrumental Rock", "Ethnic", "Gothic",
+    "Darkwave", "Techno-Industrial", "Electronic", "Pop-Folk", "Eurodance", "Dream", "Southern Rock", "Comedy", "Cult", "Gangsta",
+    "Top 40", "Christian Rap", "Pop/Funk", "Jungle", "Native American", "Cabaret", "New Wave", "Psychadelic", "Rave", "Showtunes",
+    "Trailer", "Lo-Fi", "Tribal", "Acid Punk", "Acid Jazz", "Polka", "Retro", "Musical", "Rock & Roll", "Hard Rock"
+];
+
+//128 byte tag at end of MP3
+#[derive(Debug, Clone, Default)]
+pub struct Id3v1 {
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub year: String,
+    pub comment: String,
+    //ID3v1.1
+    pub track: Option<u8>,
+    //255 = unknown
+    pub genre: u8
+}
+
+impl Id3v1 {
+    //None if file has no ID3v1
+    pub fn read_from_path(path: &str) -> Result<Option<Id3v1>, Box<dyn std::error::Error>> {
+        let mut file = File::open(path)?;
+        let data = match read_raw(&mut file)? {
+            Some(d) => d,
+            None => return Ok(None)
+        };
+        //ID3v1.1 track in last comment byte
+        let track = match data[125] == 0 && data[126] != 0 {
+            true => Some(data[126]),
+            false => None
+        };
+        Ok(Some(Id3v1 {
+            title: decode(&data[3..33]),
+            artist: decode(&data[33..63]),
+            album: decode(&data[63..93]),
+            year: decode(&data[93..97]),
+            comment: decode(&data[97..track.map(|_| 125).unwrap_or(127)]),
+            track,
+            genre: data[127]
+        }))
+    }
+
+    //Replace existing ID3v1 or append
+    pub fn write_to_path(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut data = b"TAG".to_vec();
+        data.extend(encode(&self.title, 30));
+        data.extend(encode(&self.artist, 30));
+        data.extend(encode(&self.album, 30));
+        data.extend(encode(&self.year, 4));
+        match self.track {
+            Some(track) => {
+                data.extend(encode(&self.comment, 28));
+                data.push(0);
+                data.push(track);
+            },
+            None => data.extend(encode(&self.comment, 30))
+        }
+        data.push(self.genre);
+
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        match read_raw(&mut file)? {
+            Some(_) => file.seek(SeekFrom::End(-128))?,
+            None => file.seek(SeekFrom::End(0))?
+        };
+        file.write_all(&data)?;
+        Ok(())
+    }
+
+    //Fallback ID3v2 tag for files without one
+    pub fn to_id3(&self) -> Tag {
+        let mut tag = Tag::new();
+        if !self.title.is_empty() {
+            tag.set_title(self.title.to_owned());
+        }
+        if !self.artist.is_empty() {
+            tag.set_artist(self.artist.to_owned());
+        }
+        if !self.album.is_empty() {
+            tag.set_album(self.album.to_owned());
+        }
+        if let Ok(year) = self.year.parse() {
+            tag.set_year(year);
+        }
+        if let Some(track) = self.track {
+            tag.set_track(track as u32);
+        }
+        if let Some(genre) = GENRES.get(self.genre as usize) {
+            tag.set_genre(*genre);
+        }
+        tag
+    }
+
+    //Mirror of ID3v2 tag, values are truncated
+    pub fn from_id3(tag: &Tag) -> Id3v1 {
+        let genre = tag.genre()
+            .and_then(|g| GENRES.iter().position(|v| g.split('\0').any(|g| v.eq_ignore_ascii_case(g.trim()))))
+            .map(|g| g as u8)
+            .unwrap_or(255);
+        Id3v1 {
+            title: tag.title().unwrap_or("").to_owned(),
+            artist: tag.artist().unwrap_or("").to_owned(),
+            album: tag.album().unwrap_or("").to_owned(),
+            year: tag.year().or_else(|| tag.date_recorded().map(|d| d.year)).map(|y| y.to_string()).unwrap_or_default(),
+            comment: tag.comments().find(|c| c.description.is_empty()).map(|c| c.text.to_owned()).unwrap_or_default(),
+            track: tag.track().filter(|t| *t < 256).map(|t| t as u8),
+            genre
+        }
+    }
+}
+
+//Last 128 bytes if it is ID3v1
+fn read_raw(file: &mut File) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    if file.metadata()?.len() < 128 {
+        return Ok(None);
+    }
+    let mut data = vec![0u8; 128];
+    file.seek(SeekFrom::End(-128))?;
+    file.read_exact(&mut data)?;
+    match data.starts_with(b"TAG") {
+        true => Ok(Some(data)),
+        false => Ok(None)
+    }
+}
+
+//ISO-8859-1, null or space padded
+fn decode(data: &[u8]) -> String {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    data[..end].iter().map(|b| *b as char).collect::<String>().trim_end().to_owned()
+}
+
+//ISO-8859-1, null padded, unsupported characters = ?
+fn encode(value: &str, len: usize) -> Vec<u8> {
+    let mut out: Vec<u8> = value.chars().map(|c| match c as u32 {
+        c if c < 256 => c as u8,
+        _ => b'?'
+    }).take(len).collect();
+    out.resize(len, 0);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn encode_decode() {
+        assert_eq!(encode("Café", 6), vec![b'C', b'a', b'f', 0xE9, 0, 0]);
+        assert_eq!(encode("Łódź", 4), vec![b'?', 0xF3, b'd', b'?']);
+        assert_eq!(encode("Too long", 3), b"Too".to_vec());
+        assert_eq!(decode(&[b'C', b'a', b'f', 0xE9, 0, b'x']), "Café");
+        assert_eq!(decode(b"Year  "), "Year");
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let dir = TempDir::new("id3v1");
+        let path = &dir.write("song.mp3", b"AUDIO");
+        assert!(Id3v1::read_from_path(path).unwrap().is_none());
+
+        let mut tag = Id3v1 {
+            title: String::from("Title"),
+            artist: String::from("Artist"),
+            album: String::from("Album"),
+            year: String::from("1999"),
+            comment: String::from("Comment"),
+            track: Some(3),
+            genre: 17
+        };
+        tag.write_to_path(path).unwrap();
+        assert_eq!(Id3v1::read_from_path(path).unwrap().unwrap().track, Some(3));
+        //Existing tag is replaced, not appended
+        tag.track = None;
+        tag.title = String::from("Other");
+        tag.write_to_path(path).unwrap();
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 5 + 128);
+
+        let read = Id3v1::read_from_path(path).unwrap().unwrap();
+        assert_eq!(read.title, "Other");
+        assert_eq!(read.artist, "Artist");
+        assert_eq!(read.year, "1999");
+        assert_eq!(read.comment, "Comment");
+        assert_eq!(read.track, None);
+        assert_eq!(read.genre, 17);
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::hash_map::DefaultHasher;
+use std::convert::TryInto;
+use std::hash::Hasher;
+
+use crate::{ape, flacid3};
+
+//Audio data differs after writing, reported separately from other write errors
+#[derive(Debug, Clone)]
+pub struct IntegrityError;
+
+impl std::fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Audio data changed while writing tag, original file was kept!")
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+//Check that writing tag didn't touch audio data, original = file before writing
+pub fn verify(original: &str, written: &str) -> Result<(), Box<dyn std::error::Error>> {
+    //Unsupported format = can't check
+    let before = match payload_hash(&std::fs::read(original)?) {
+        Some(h) => h,
+        None => return Ok(())
+    };
+    if payload_hash(&std::fs::read(written)?) != Some(before) {
+        return Err(IntegrityError.into());
+    }
+    Ok(())
+}
+
+fn payload_hash(data: &[u8]) -> Option<u64> {
+    let (start, end) = audio_range(data)?;
+    let mut hasher = DefaultHasher::new();
+    hasher.write(&data[start..end]);
+    Some(hasher.finish())
+}
+
+//Start and end of audio data without tags: MP3 frames, FLAC frames, AIFF SSND, WAV data, MP4 mdat, WavPack / Monkey's Audio
+pub fn audio_range(data: &[u8]) -> Option<(usize, usize)> {
+    if data.starts_with(b"FORM") {
+        return chunk(data, b"SSND", true);
+    }
+    if data.starts_with(b"RIFF") {
+        return chunk(data, b"data", false);
+    }
+    if data.get(4..8) == Some(b"ftyp") {
+        return mdat(data);
+    }
+    let start = match data.get(0..10) {
+        Some(header) => flacid3::id3_size(header.try_into().ok()?).unwrap_or(0) as usize,
+        None => return None
+    };
+    let audio = data.get(start..)?;
+    if audio.starts_with(b"fLaC") {
+        let start = start + flacid3::metadata_size(audio).ok()?;
+        return Some((start, data.len())).filter(|(s, e)| s <= e);
+    }
+    //APE tag and ID3v1 at end
+    let tags_start = || match ape::locate(data).ok()? {
+        Some((ape_start, _)) => Some(ape_start),
+        None => Some(ape::id3v1_start(data))
+    };
+    if audio.starts_with(b"wvpk") || audio.starts_with(b"MAC ") {
+        return Some((start, tags_start()?)).filter(|(s, e)| s <= e);
+    }
+    //Some taggers leave padding or garbage after ID3, first frame is searched
+    let sync = match start {
+        0 => Some(0).filter(|_| is_mpeg_frame(audio)),
+        _ => (0..audio.len().min(MAX_JUNK)).find(|i| is_mpeg_frame(&audio[*i..]))
+    };
+    if let Some(sync) = sync {
+        return Some((start + sync, tags_start()?)).filter(|(s, e)| s <= e);
+    }
+    None
+}
+
+//Searched bytes after ID3 for first MPEG frame
+const MAX_JUNK: usize = 65536;
+
+//Frame sync with valid layer, bitrate and sample rate
+fn is_mpeg_frame(data: &[u8]) -> bool {
+    data.len() > 2 && data[0] == 0xFF && data[1] & 0xE0 == 0xE0
+        && data[1] & 0x06 != 0 && data[2] >> 4 != 0x0F && data[2] & 0x0C != 0x0C
+}
+
+//Only MP4 with single mdat atom
+fn mdat(data: &[u8]) -> Option<(usize, usize)> {
+    let mut pos = 0;
+    let mut found = None;
+    while pos + 8 <= data.len() {
+        let (size, header) = match u32::from_be_bytes(data[pos..pos+4].try_into().ok()?) {
+            0 => (data.len() - pos, 8),
+            1 => (u64::from_be_bytes(data.get(pos+8..pos+16)?.try_into().ok()?) as usize, 16),
+            s => (s as usize, 8)
+        };
+        let end = pos.checked_add(size).filter(|e| size >= header && *e <= data.len())?;
+        if &data[pos+4..pos+8] == b"mdat" {
+            if found.is_some() {
+                return None;
+            }
+            found = Some((pos + header, end));
+        }
+        pos = end;
+    }
+    found
+}
+
+//Data range of RIFF / IFF chunk
+fn chunk(data: &[u8], id: &[u8], big_endian: bool) -> Option<(usize, usize)> {
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let size: [u8; 4] = data[pos+4..pos+8].try_into().ok()?;
+        let size = match big_endian {
+            true => u32::from_be_bytes(size),
+            false => u32::from_le_bytes(size)
+        } as usize;
+        if &data[pos..pos+4] == id {
+            return Some((pos + 8, pos + 8 + size)).filter(|(_, e)| *e <= data.len());
+        }
+        pos += 8 + size + size % 2;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn riff(chunks: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut data = b"RIFF\0\0\0\0WAVE".to_vec();
+        for (id, chunk) in chunks {
+            data.extend(*id);
+            data.extend(&(chunk.len() as u32).to_le_bytes());
+            data.extend(*chunk);
+            if chunk.len() % 2 == 1 {
+                data.push(0);
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn changed_audio() {
+        let dir = TempDir::new("integrity");
+        let original = dir.write("original.wav", riff(&[(b"data", b"AUDIO"), (b"id3 ", b"OLD")]));
+        let same = dir.write("same.wav", riff(&[(b"data", b"AUDIO"), (b"id3 ", b"NEW TAG")]));
+        let changed = dir.write("changed.wav", riff(&[(b"data", b"OTHER"), (b"id3 ", b"NEW TAG")]));
+        assert!(verify(&original, &same).is_ok());
+        assert!(verify(&original, &changed).unwrap_err().is::<IntegrityError>());
+    }
+
+    #[test]
+    fn wav_data_chunk() {
+        let data = riff(&[(b"fmt ", &[0u8; 3]), (b"data", b"AUDIO"), (b"id3 ", b"TAG")]);
+        assert_eq!(audio_range(&data), Some((32, 37)));
+        //Truncated data chunk
+        assert_eq!(audio_range(&data[..35]), None);
+        assert_eq!(audio_range(&riff(&[(b"fmt ", &[0u8; 3])])), None);
+    }
+
+    #[test]
+    fn mp3_without_tags() {
+        let mut data = b"ID3\x04\0\0\0\0\0\x02\0\0".to_vec();
+        data.extend(&[0xFF, 0xFB, 1, 2]);
+        let end = data.len();
+        data.extend(b"TAG");
+        data.resize(end + 128, 0);
+        assert_eq!(audio_range(&data), Some((12, end)));
+        //Not MP3 frame
+        assert_eq!(audio_range(b"ID3\x04\0\0\0\0\0\x00JUNK"), None);
+        assert_eq!(audio_range(b"ID3"), None);
+    }
+
+    #[test]
+    fn mp3_junk_after_id3() {
+        let mut data = b"ID3\x04\0\0\0\0\0\x02\0\0".to_vec();
+        data.extend(&[0, 0xFF, 0x00, 0xFF, 0xFB, 0x90, 0]);
+        assert_eq!(audio_range(&data), Some((15, 19)));
+        //Without ID3 frame has to be at start
+        assert_eq!(audio_range(&data[12..]), None);
+    }
+
+    #[test]
+    fn wavpack_before_ape() {
+        let dir = TempDir::new("integrity");
+        let path = dir.write("song.wv", b"wvpkAUDIO");
+        let mut tag = ape::ApeTag::default();
+        tag.set("Title", vec![String::from("Song")]);
+        tag.write_to_path(&path).unwrap();
+        assert_eq!(audio_range(&std::fs::read(&path).unwrap()), Some((0, 9)));
+    }
+
+    fn atom(name: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut out = ((data.len() + 8) as u32).to_be_bytes().to_vec();
+        out.extend(name);
+        out.extend(data);
+        out
+    }
+
+    #[test]
+    fn mp4_mdat() {
+        let mut data = atom(b"ftyp", b"M4A \0\0\0\0");
+        data.extend(atom(b"moov", &[0u8; 8]));
+        data.extend(atom(b"mdat", b"AUDIO"));
+        assert_eq!(audio_range(&data), Some((40, 45)));
+        //Truncated
+        assert_eq!(audio_range(&data[..44]), None);
+        //Multiple mdat atoms
+        data.extend(atom(b"mdat", b"MORE"));
+        assert_eq!(audio_range(&data), None);
+    }
+
+    #[test]
+    fn flac_frames() {
+        let mut data = b"fLaC\x80\0\0\x02\0\0".to_vec();
+        data.extend(b"FRAMES");
+        assert_eq!(audio_range(&data), Some((10, 16)));
+        //Block longer than file
+        assert_eq!(audio_range(b"fLaC\x80\0\0\x20\0\0"), None);
+    }
+
+    #[test]
+    fn verify_detects_change() {
+        let dir = TempDir::new("integrity");
+        let original = dir.write("original.wav", riff(&[(b"data", b"AUDIO")]));
+        let written = dir.write("written.wav", riff(&[(b"data", b"AUDIO"), (b"id3 ", b"TAG")]));
+        assert!(verify(&original, &written).is_ok());
+        std::fs::write(&written, riff(&[(b"data", b"OTHER")])).unwrap();
+        assert!(verify(&original, &written).is_err());
+    }
+}
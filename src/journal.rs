@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::path::Path;
+use std::time::{SystemTime, Duration};
+use serde_json::{json, Value};
+
+use crate::tagger::TaggerConfig;
+use crate::{atomic, integrity, oggtag};
+use crate::ui;
+
+//Original state of every file written in single run, JSON per line:
+//{"action": "record", ...} before writing, {"action": "done", ...} after, {"action": "undone", ...} after undo
+pub struct Journal {
+    pub path: String,
+    file: File
+}
+
+impl Journal {
+    //New journal file in folder, named by start time
+    pub fn create(dir: &str) -> Result<Journal, Box<dyn std::error::Error>> {
+        std::fs::create_dir_all(dir)?;
+        let ts = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_else(|_| Duration::from_millis(0)).as_millis();
+        let path = Path::new(dir).join(format!("{}.jsonl", ts)).to_str().ok_or("Invalid journal path!")?.to_owned();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Journal { path, file })
+    }
+
+    //Original tags: bytes around audio data (ID3, APE, FLAC blocks, WAV/AIFF chunks, MP4 atoms), Ogg comment header or whole cue sheet
+    //Has to be called before saving, files with unknown tag location are only reported
+    pub fn record(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let data = std::fs::read(path)?;
+        let entry = match integrity::audio_range(&data) {
+            Some((start, end)) => json!({
+                "action": "record",
+                "path": path,
+                "head": base64::encode(&data[..start]),
+                "tail": base64::encode(&data[end..]),
+                "audio": end - start
+            }),
+            None if data.starts_with(b"OggS") => json!({ "action": "record", "path": path, "comment": base64::encode(oggtag::comment_packet(path)?) }),
+            None if is_cue(path) => json!({ "action": "record", "path": path, "content": base64::encode(&data) }),
+            None => {
+                ui::print_warning(&format!("Tags couldn't be located, changes can't be undone: {}", path));
+                return Ok(());
+            }
+        };
+        self.write(entry)
+    }
+
+    //File renamed / moved after tagging
+    pub fn record_move(&mut self, from: &str, to: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.write(json!({ "action": "move", "path": to, "from": from }))
+    }
+
+    //File state after writing, used to detect later changes
+    pub fn done(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let (mtime, size) = file_state(path)?;
+        self.write(json!({ "action": "done", "path": path, "mtime": mtime, "size": size }))
+    }
+
+    fn write(&mut self, value: Value) -> Result<(), Box<dyn std::error::Error>> {
+        writeln!(self.file, "{}", value)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+//Newest journal in folder
+pub fn latest(dir: &str) -> Option<String> {
+    std::fs::read_dir(dir).ok()?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().map(|e| e == "jsonl").unwrap_or(false))
+        .max()
+        .and_then(|p| p.to_str().map(String::from))
+}
+
+//Restore original tags from journal, files = only these (empty = whole run), force = ignore changes since writing
+//Returns (restored, failed)
+pub fn undo(journal_path: &str, files: &[String], force: bool, config: &TaggerConfig) -> Result<(i32, i32), Box<dyn std::error::Error>> {
+    //Last line can be incomplete after crash
+    let entries: Vec<Value> = std::fs::read_to_string(journal_path)?.lines().filter_map(|l| serde_json::from_str(l).ok()).collect();
+    let mut journal = Journal { path: journal_path.to_owned(), file: OpenOptions::new().append(true).open(journal_path)? };
+
+    let (mut ok, mut fail) = (0, 0);
+    //Checked once per file against state after its last write, older records are restored without checking
+    let mut restorable: HashMap<String, bool> = HashMap::new();
+    //Newest first, so the same file written twice ends with oldest state, moved files are moved back first
+    for (i, entry) in entries.iter().enumerate().rev().filter(|(_, e)| e["action"] == "record" || e["action"] == "move") {
+        let path = entry["path"].as_str().unwrap_or("");
+        //Moved file can be selected by both paths
+        let selected = |p: &str| files.iter().any(|f| Path::new(f) == Path::new(p));
+        let later = &entries[i+1..];
+        let moved_to = later.iter().rev().find(|e| e["action"] == "move" && e["from"] == path).and_then(|e| e["path"].as_str());
+        if !files.is_empty() && !selected(path) && !moved_to.map(selected).unwrap_or(false)
+            && !entry["from"].as_str().map(selected).unwrap_or(false) {
+            continue;
+        }
+        if later.iter().any(|e| e["action"] == "undone" && e["path"] == path) {
+            continue;
+        }
+        if let Some(from) = entry["from"].as_str() {
+            match move_back(path, from) {
+                Ok(_) => {
+                    println!("Moved back: {} -> {}", path, from);
+                    journal.write(json!({ "action": "undone", "path": path }))?;
+                    ok += 1;
+                },
+                Err(e) => {
+                    ui::print_warning(&format!("Failed moving back {}: {}", path, e));
+                    fail += 1;
+                }
+            }
+            continue;
+        }
+        let can_restore = match restorable.get(path) {
+            Some(r) => *r,
+            None => {
+                let r = force || unchanged(&entries, path);
+                if !r {
+                    ui::print_warning(&format!("File was changed or not fully written since tagging, skipping (use --force): {}", path));
+                    fail += 1;
+                }
+                restorable.insert(path.to_owned(), r);
+                r
+            }
+        };
+        if !can_restore {
+            continue;
+        }
+
+        match restore(entry, config) {
+            Ok(_) => {
+                println!("Restored: {}", path);
+                journal.write(json!({ "action": "undone", "path": path }))?;
+                ok += 1;
+            },
+            Err(e) => {
+                ui::print_warning(&format!("Failed restoring {}: {}", path, e));
+                fail += 1;
+            }
+        }
+    }
+    Ok((ok, fail))
+}
+
+fn move_back(path: &str, from: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if Path::new(from).exists() {
+        return Err("Original path already exists!".into());
+    }
+    if let Some(parent) = Path::new(from).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    atomic::rename(path, from)
+}
+
+fn is_cue(path: &str) -> bool {
+    Path::new(path).extension().map(|e| e.eq_ignore_ascii_case("cue")).unwrap_or(false)
+}
+
+//File is same as after its last write in journal
+fn unchanged(entries: &[Value], path: &str) -> bool {
+    let done = entries.iter().rev().find(|e| e["action"] == "done" && e["path"] == path);
+    match (done, file_state(path)) {
+        (Some(done), Ok((mtime, size))) => done["mtime"].as_u64() == Some(mtime) && done["size"].as_u64() == Some(size),
+        _ => false
+    }
+}
+
+fn restore(entry: &Value, config: &TaggerConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let path = entry["path"].as_str().ok_or("Missing path!")?;
+    if let Some(comment) = entry["comment"].as_str() {
+        let packet = base64::decode(comment)?;
+        return atomic::write(path, config.preserve_times, |temp| oggtag::write_comment_packet(temp, packet));
+    }
+    let data = match entry["content"].as_str() {
+        Some(content) => base64::decode(content)?,
+        //Original tags around current audio data
+        None => {
+            let head = base64::decode(entry["head"].as_str().ok_or("Missing original tag!")?)?;
+            let tail = base64::decode(entry["tail"].as_str().ok_or("Missing original tag!")?)?;
+            let current = std::fs::read(path)?;
+            let (start, end) = integrity::audio_range(&current).ok_or("Audio data not found!")?;
+            if entry["audio"].as_u64() != Some((end - start) as u64) {
+                return Err("Audio data differs from tagged file!".into());
+            }
+            [&head[..], &current[start..end], &tail[..]].concat()
+        }
+    };
+    atomic::write(path, config.preserve_times, |temp| Ok(std::fs::write(temp, data)?))
+}
+
+//Modified time (ms) and size
+fn file_state(path: &str) -> Result<(u64, u64), Box<dyn std::error::Error>> {
+    let meta = std::fs::metadata(path)?;
+    let mtime = meta.modified()?.duration_since(SystemTime::UNIX_EPOCH)?.as_millis() as u64;
+    Ok((mtime, meta.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::test_util::TempDir;
+
+    fn wav(audio: &[u8], tag: &[u8]) -> Vec<u8> {
+        let mut data = b"RIFF\0\0\0\0WAVEdata".to_vec();
+        data.extend(&(audio.len() as u32).to_le_bytes());
+        data.extend(audio);
+        data.extend(b"id3 ");
+        data.extend(&(tag.len() as u32).to_le_bytes());
+        data.extend(tag);
+        data
+    }
+
+    //Tag file twice in one run, like multiple passes over same file
+    fn tag(journal: &mut Journal, path: &str, tags: &[&[u8]]) {
+        for t in tags {
+            journal.record(path).unwrap();
+            std::fs::write(path, wav(b"AUDIO", t)).unwrap();
+            journal.done(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn undo_restores_original() {
+        let dir = TempDir::new("journal");
+        let config = TaggerConfig::from_json(&json!({})).unwrap();
+        let original = wav(b"AUDIO", b"OLD");
+        let path = dir.write("song.wav", &original);
+        let mut journal = Journal::create(&dir.file("journal")).unwrap();
+        tag(&mut journal, &path, &[b"NEW", b"NEWER"]);
+        let journal_path = latest(&dir.file("journal")).unwrap();
+        assert_eq!(journal_path, journal.path);
+
+        assert_eq!(undo(&journal_path, &[], false, &config).unwrap(), (2, 0));
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+        //Already undone
+        assert_eq!(undo(&journal_path, &[], false, &config).unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn unknown_format_isnt_journaled() {
+        let dir = TempDir::new("journal");
+        let path = dir.write("song.xyz", b"UNKNOWN DATA");
+        let mut journal = Journal::create(&dir.file("journal")).unwrap();
+        journal.record(&path).unwrap();
+        assert!(std::fs::read_to_string(&journal.path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn journal_keeps_only_tags() {
+        let dir = TempDir::new("journal");
+        let audio = vec![7u8; 10000];
+        let path = dir.write("song.wav", wav(&audio, b"OLD"));
+        let mut journal = Journal::create(&dir.file("journal")).unwrap();
+        journal.record(&path).unwrap();
+        let entry: Value = serde_json::from_str(std::fs::read_to_string(&journal.path).unwrap().trim()).unwrap();
+        assert!(entry["content"].is_null());
+        assert_eq!(entry["audio"], 10000);
+        assert!(entry.to_string().len() < 500);
+    }
+
+    #[test]
+    fn undo_skips_changed_file() {
+        let dir = TempDir::new("journal");
+        let config = TaggerConfig::from_json(&json!({})).unwrap();
+        let original = wav(b"AUDIO", b"OLD");
+        let path = dir.write("song.wav", &original);
+        let mut journal = Journal::create(&dir.file("journal")).unwrap();
+        tag(&mut journal, &path, &[b"NEW"]);
+        //Edited by other program
+        std::fs::write(&path, wav(b"AUDIO", b"EDITED!")).unwrap();
+
+        assert_eq!(undo(&journal.path, &[], false, &config).unwrap(), (0, 1));
+        assert_eq!(std::fs::read(&path).unwrap(), wav(b"AUDIO", b"EDITED!"));
+        assert_eq!(undo(&journal.path, &[], true, &config).unwrap(), (1, 0));
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+    }
+
+    #[test]
+    fn undo_moves_back() {
+        let dir = TempDir::new("journal");
+        let config = TaggerConfig::from_json(&json!({})).unwrap();
+        let from = dir.write("song.wav", wav(b"AUDIO", b""));
+        std::fs::create_dir_all(dir.path().join("Artist")).unwrap();
+        let to = dir.file("Artist/song.wav");
+        std::fs::rename(&from, &to).unwrap();
+        let mut journal = Journal::create(&dir.file("journal")).unwrap();
+        journal.record_move(&from, &to).unwrap();
+
+        //Selected by original path
+        assert_eq!(undo(&journal.path, &[from.to_owned()], false, &config).unwrap(), (1, 0));
+        assert!(Path::new(&from).exists() && !Path::new(&to).exists());
+    }
+}
@@ -1,7 +1,38 @@
+mod ape;
+mod art;
+mod atomic;
+mod backend;
+mod cli;
+mod cue;
+mod diff;
 mod discogs;
+mod flac;
+mod flacid3;
+mod id3tag;
+mod id3v1;
+mod integrity;
+mod journal;
+mod mapping;
+mod mp4;
+mod oggtag;
+mod organize;
+mod properties;
+mod report;
+mod runner;
+mod skip;
+mod state;
 mod tagger;
+#[cfg(test)]
+mod test_util;
 mod ui;
+mod watch;
+mod wav;
 
 fn main() {
+    //Run in CLI mode if there are any arguments
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::start_cli(args));
+    }
     ui::start_ui();
 }
\ No newline at end of file
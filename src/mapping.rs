@@ -0,0 +1,271 @@
+use regex::{Regex, Captures};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::discogs::{Track, ReleaseMaster};
+use crate::tagger::{TaggerConfig, WritePolicy, clean_discogs_artist, release_labels};
+
+//Custom tag with value generated from template
+#[derive(Debug, Clone)]
+pub struct TagMapping {
+    //Frame ID, TXXX:DESCRIPTION or COMM, None = don't write to ID3
+    pub id3: Option<String>,
+    //None = don't write to Vorbis
+    pub vorbis: Option<String>,
+    pub template: String,
+    pub policy: WritePolicy
+}
+
+impl TagMapping {
+    //Mapping from Vorbis style name, used for both formats
+    pub fn new(key: &str, template: &str) -> TagMapping {
+        let key = key.trim().to_uppercase();
+        TagMapping {
+            id3: Some(id3_frame(&key)),
+            vorbis: Some(key),
+            template: template.trim().to_owned(),
+            policy: WritePolicy::Overwrite
+        }
+    }
+
+    //Parse {"id3": "COMM", "vorbis": "COMMENT", "template": "...", "policy": "fill"}
+    pub fn from_json(json: &Value) -> Result<TagMapping, String> {
+        Ok(TagMapping {
+            id3: json["id3"].as_str().map(|v| v.to_owned()),
+            vorbis: json["vorbis"].as_str().map(|v| v.to_uppercase()),
+            template: json["template"].as_str().ok_or("Missing mapping template!")?.to_owned(),
+            policy: match json.get("policy") {
+                Some(p) => WritePolicy::from_json(p, true)?,
+                None => WritePolicy::Overwrite
+            }
+        })
+    }
+}
+
+//Frame/key names of built-in fields
+#[derive(Debug, Clone)]
+pub struct TagKeys {
+    pub id3_label: String,
+    pub id3_style: String,
+    pub vorbis_label: String,
+    pub vorbis_genre: String,
+    pub vorbis_style: String
+}
+
+impl Default for TagKeys {
+    fn default() -> TagKeys {
+        TagKeys {
+            id3_label: String::from("TPUB"),
+            id3_style: String::from("TXXX:STYLE"),
+            vorbis_label: String::from("LABEL"),
+            vorbis_genre: String::from("GENRE"),
+            vorbis_style: String::from("STYLE")
+        }
+    }
+}
+
+impl TagKeys {
+    //Override defaults with {"id3Label": "TPUB", ...}
+    pub fn load_json(&mut self, json: &Value) {
+        if let Some(v) = json["id3Label"].as_str() {
+            self.id3_label = v.to_owned();
+        }
+        if let Some(v) = json["id3Style"].as_str() {
+            self.id3_style = v.to_owned();
+        }
+        if let Some(v) = json["vorbisLabel"].as_str() {
+            self.vorbis_label = v.to_uppercase();
+        }
+        if let Some(v) = json["vorbisGenre"].as_str() {
+            self.vorbis_genre = v.to_uppercase();
+        }
+        if let Some(v) = json["vorbisStyle"].as_str() {
+            self.vorbis_style = v.to_uppercase();
+        }
+    }
+}
+
+//Get keys and mappings for preset
+pub fn preset(name: &str) -> Result<(TagKeys, Vec<TagMapping>), String> {
+    let mut keys = TagKeys::default();
+    let mappings = match name.to_lowercase().as_str() {
+        "" | "none" => vec![],
+        "rekordbox" => vec![
+            TagMapping::new("COMMENT", "{catno} | {label}")
+        ],
+        "traktor" => vec![
+            TagMapping::new("COMMENT", "{catno} | {label} | {year}")
+        ],
+        "serato" => vec![
+            TagMapping::new("COMMENT", "{catno} | {label} | {year}"),
+            TagMapping::new("GROUPING", "{styles}")
+        ],
+        "foobar2000" => {
+            //foobar2000 shows TPUB as publisher
+            keys.id3_label = String::from("TXXX:LABEL");
+            vec![
+                TagMapping::new("CATALOGNUMBER", "{catno}"),
+                TagMapping::new("RELEASECOUNTRY", "{country}")
+            ]
+        },
+        _ => return Err(format!("Unknown mapping preset: {}", name))
+    };
+    Ok((keys, mappings))
+}
+
+//Parse user mappings, either `KEY = template` lines, object or array of objects
+pub fn parse_mappings(json: &Value) -> Result<Vec<TagMapping>, String> {
+    match json {
+        Value::Null => Ok(vec![]),
+        Value::String(s) => {
+            let mut out = vec![];
+            for line in s.lines().filter(|l| !l.trim().is_empty()) {
+                let mut split = line.splitn(2, '=');
+                let key = split.next().unwrap();
+                let template = split.next().ok_or(format!("Invalid mapping: {}", line))?;
+                out.push(TagMapping::new(key, template.trim().trim_matches('"')));
+            }
+            Ok(out)
+        },
+        Value::Object(o) => Ok(o.iter().map(|(k, v)| TagMapping::new(k, v.as_str().unwrap_or(""))).collect()),
+        Value::Array(a) => a.iter().map(TagMapping::from_json).collect(),
+        _ => Err(String::from("Invalid mappings!"))
+    }
+}
+
+//Vorbis name to ID3 frame
+fn id3_frame(key: &str) -> String {
+    match key {
+        "COMMENT" | "DESCRIPTION" => "COMM",
+        "GROUPING" => "TIT1",
+        "COMPOSER" => "TCOM",
+        "LABEL" | "ORGANIZATION" | "PUBLISHER" => "TPUB",
+        "BPM" => "TBPM",
+        "KEY" => "TKEY",
+        "ISRC" => "TSRC",
+        "REMIXER" => "TPE4",
+        _ => return format!("TXXX:{}", key)
+    }.to_owned()
+}
+
+//Values available in templates
+pub struct TemplateData {
+    values: HashMap<&'static str, String>
+}
+
+impl TemplateData {
+    pub fn new(release: &ReleaseMaster, track: &Track, config: &TaggerConfig) -> TemplateData {
+        let mut values = HashMap::new();
+        let artists = track.artists.as_ref().or(release.artists.as_ref()).cloned().unwrap_or_default();
+        let mut genres = release.genres.clone();
+        let mut styles = release.styles.clone();
+        genres.sort();
+        styles.sort();
+
+        values.insert("title", track.title.to_owned());
+        values.insert("artist", artists.first().map(|a| clean_discogs_artist(a)).unwrap_or_default());
+        values.insert("artists", artists.iter().map(|a| clean_discogs_artist(a)).collect::<Vec<String>>().join(&config.artist_separator));
+        values.insert("album", release.title.to_owned());
+        let labels = release_labels(release, config);
+        values.insert("label", labels.first().map(|l| l.to_owned()).unwrap_or_default());
+        values.insert("labels", labels.join(", "));
+        values.insert("catno", release.catno.to_owned().unwrap_or_default());
+        values.insert("year", release.year.map(|y| y.to_string()).unwrap_or_default());
+        values.insert("released", release.released.to_owned().unwrap_or_default());
+        values.insert("genre", genres.first().map(|g| g.to_owned()).unwrap_or_default());
+        values.insert("genres", genres.join(", "));
+        values.insert("style", styles.first().map(|s| s.to_owned()).unwrap_or_default());
+        values.insert("styles", styles.join(", "));
+        values.insert("country", release.country.to_owned());
+        values.insert("track", track.position_int.to_string());
+        values.insert("position", track.position.to_owned());
+        values.insert("duration", track.duration.to_owned());
+        values.insert("release_id", release.id.to_string());
+        values.insert("discogs_url", release.full_url());
+
+        TemplateData { values }
+    }
+
+    pub fn set(&mut self, key: &'static str, value: String) {
+        self.values.insert(key, value);
+    }
+
+    //Replace {variable}, None if template has variables and all of them are empty
+    pub fn render(&self, template: &str) -> Option<String> {
+        self.render_with(template, |v| v.to_owned())
+    }
+
+    //Values are passed through filter (for example sanitization) first
+    pub fn render_with(&self, template: &str, filter: impl Fn(&str) -> String) -> Option<String> {
+        static VARIABLE: OnceLock<Regex> = OnceLock::new();
+        let re = VARIABLE.get_or_init(|| Regex::new(r"\{(\w+)\}").unwrap());
+        let (mut referenced, mut filled) = (false, false);
+        let out = re.replace_all(template, |c: &Captures| {
+            let value = self.values.get(&c[1]).map(|v| filter(v)).unwrap_or_default();
+            referenced = true;
+            filled |= !value.is_empty();
+            value
+        }).to_string();
+        match !referenced || filled {
+            true => Some(out.trim().to_owned()),
+            false => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn data(values: &[(&'static str, &str)]) -> TemplateData {
+        TemplateData { values: values.iter().map(|(k, v)| (*k, v.to_string())).collect() }
+    }
+
+    #[test]
+    fn render() {
+        let data = data(&[("catno", "CAT001"), ("label", ""), ("year", "2020")]);
+        assert_eq!(data.render("{catno} | {year}").as_deref(), Some("CAT001 | 2020"));
+        //Some variables empty
+        assert_eq!(data.render("{catno} {label}").as_deref(), Some("CAT001"));
+        //All variables empty or unknown
+        assert_eq!(data.render("{label} {unknown}"), None);
+        //Constant
+        assert_eq!(data.render(" Discogs ").as_deref(), Some("Discogs"));
+        assert_eq!(data.render_with("{catno}", |v| v.to_lowercase()).as_deref(), Some("cat001"));
+    }
+
+    #[test]
+    fn parse() {
+        let mappings = parse_mappings(&json!("comment = \"{catno} | {label}\"\n\nGrouping={styles}")).unwrap();
+        assert_eq!(mappings.len(), 2);
+        assert_eq!(mappings[0].id3.as_deref(), Some("COMM"));
+        assert_eq!(mappings[0].vorbis.as_deref(), Some("COMMENT"));
+        assert_eq!(mappings[0].template, "{catno} | {label}");
+        assert_eq!(mappings[1].id3.as_deref(), Some("TIT1"));
+        assert!(parse_mappings(&json!("MISSING TEMPLATE")).is_err());
+
+        let mappings = parse_mappings(&json!({"custom": "{year}"})).unwrap();
+        assert_eq!((mappings[0].id3.as_deref(), mappings[0].vorbis.as_deref()), (Some("TXXX:CUSTOM"), Some("CUSTOM")));
+
+        let mappings = parse_mappings(&json!([{"id3": "TXXX:CATALOGNUMBER", "template": "{catno}", "policy": "fill"}])).unwrap();
+        assert_eq!(mappings[0].vorbis, None);
+        assert_eq!(mappings[0].policy, WritePolicy::FillEmpty);
+        assert!(parse_mappings(&json!([{"id3": "COMM"}])).is_err());
+        assert!(parse_mappings(&json!(1)).is_err());
+        assert!(parse_mappings(&Value::Null).unwrap().is_empty());
+    }
+
+    #[test]
+    fn presets() {
+        let (keys, mappings) = preset("foobar2000").unwrap();
+        assert_eq!(keys.id3_label, "TXXX:LABEL");
+        assert_eq!(mappings.iter().map(|m| m.vorbis.as_deref().unwrap()).collect::<Vec<_>>(), vec!["CATALOGNUMBER", "RELEASECOUNTRY"]);
+        let (keys, mappings) = preset("Serato").unwrap();
+        assert_eq!(keys.id3_label, "TPUB");
+        assert_eq!(mappings[1].id3.as_deref(), Some("TIT1"));
+        assert!(preset("").unwrap().1.is_empty());
+        assert!(preset("itunes").is_err());
+    }
+}
@@ -0,0 +1,248 @@
+use mp4ameta::{Tag, Data, DataIdent, Fourcc, Img};
+
+use crate::backend::{AudioTag, Backend, Field};
+use crate::tagger::{MusicFileType, TaggerConfig};
+use crate::mapping::TagKeys;
+use crate::art::Art;
+use crate::properties::{self, AudioProperties};
+
+//Freeform atoms are ----:com.apple.iTunes:NAME
+const ITUNES_MEAN: &str = "com.apple.iTunes";
+
+//MP4/M4A/ALAC
+pub struct Mp4Backend;
+
+impl Backend for Mp4Backend {
+    fn file_type(&self) -> MusicFileType {
+        MusicFileType::MP4
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        //.mp4 is usually video, those are sniffed
+        &[".m4a"]
+    }
+
+    //Size + ftyp atom
+    fn detect(&self, header: &[u8]) -> bool {
+        header.len() >= 8 && &header[4..8] == b"ftyp"
+    }
+
+    fn read(&self, path: &str, config: &TaggerConfig) -> Result<Box<dyn AudioTag>, Box<dyn std::error::Error>> {
+        Ok(Box::new(Mp4Tag {
+            tag: Tag::read_from_path(path)?,
+            keys: config.keys.clone(),
+            artist_separator: config.artist_separator.to_owned()
+        }))
+    }
+
+    fn properties(&self, path: &str) -> Result<AudioProperties, Box<dyn std::error::Error>> {
+        properties::mp4(path)
+    }
+}
+
+struct Mp4Tag {
+    tag: Tag,
+    keys: TagKeys,
+    artist_separator: String
+}
+
+impl Mp4Tag {
+    fn ident(&self, field: &Field) -> Option<DataIdent> {
+        field.vorbis_key(&self.keys).map(|k| ident(&k))
+    }
+}
+
+impl AudioTag for Mp4Tag {
+    fn get(&self, field: &Field) -> Option<Vec<String>> {
+        if *field == Field::Track {
+            return self.tag.track_number().map(|t| vec![t.to_string()]);
+        }
+        let values: Vec<String> = self.tag.strings_of(&self.ident(field)?).map(String::from).collect();
+        match values.is_empty() {
+            true => None,
+            false => Some(values)
+        }
+    }
+
+    fn set(&mut self, field: &Field, values: Vec<String>) {
+        if values.is_empty() {
+            match (field, self.ident(field)) {
+                (Field::Track, _) => self.tag.remove_data_of(&Fourcc(*b"trkn")),
+                (_, Some(ident)) => self.tag.remove_data_of(&ident),
+                _ => {}
+            }
+            return;
+        }
+        let values = match field {
+            Field::Track => {
+                if let Some(track) = values.first().and_then(|t| t.parse().ok()) {
+                    self.tag.set_track_number(track);
+                }
+                return;
+            },
+            //iTunes shows only first artist atom, so join
+            Field::Artist => vec![values.join(&self.artist_separator)],
+            //Numeric genre would be shown instead
+            Field::Genre => {
+                self.tag.remove_data_of(&Fourcc(*b"gnre"));
+                values
+            },
+            _ => values
+        };
+        if let Some(ident) = self.ident(field) {
+            self.tag.set_all_data(ident, values.into_iter().map(Data::Utf8));
+        }
+    }
+
+    fn has_art(&self) -> bool {
+        self.tag.artwork().is_some()
+    }
+
+    fn set_art(&mut self, art: Art) {
+        match art.mime.as_str() {
+            "image/jpeg" => self.tag.set_artwork(Img::jpeg(art.data)),
+            "image/png" => self.tag.set_artwork(Img::png(art.data)),
+            "image/bmp" => self.tag.set_artwork(Img::bmp(art.data)),
+            _ => eprintln!("Unsupported album art format for MP4 ({}), ignoring!", art.mime)
+        }
+    }
+
+    fn remove_art(&mut self) {
+        self.tag.remove_data_of(&Fourcc(*b"covr"));
+    }
+
+    fn save(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.tag.write_to_path(path)?;
+        Ok(())
+    }
+}
+
+//Vorbis name to iTunes atom, unknown = freeform
+fn ident(key: &str) -> DataIdent {
+    let fourcc = match key {
+        "TITLE" => *b"\xa9nam",
+        "ARTIST" => *b"\xa9ART",
+        "ALBUM" => *b"\xa9alb",
+        "DATE" => *b"\xa9day",
+        "GENRE" => *b"\xa9gen",
+        "COMMENT" | "DESCRIPTION" => *b"\xa9cmt",
+        "GROUPING" => *b"\xa9grp",
+        "COMPOSER" => *b"\xa9wrt",
+        _ => return DataIdent::Freeform { mean: ITUNES_MEAN.to_owned(), name: key.to_owned() }
+    };
+    DataIdent::Fourcc(Fourcc(fourcc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::integrity;
+    use crate::test_util::TempDir;
+
+    fn atom(name: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut out = ((data.len() + 8) as u32).to_be_bytes().to_vec();
+        out.extend(name);
+        out.extend(data);
+        out
+    }
+
+    //Full atom with version and flags
+    fn full_atom(name: &[u8], flags: u32, data: &[u8]) -> Vec<u8> {
+        atom(name, &[&flags.to_be_bytes()[..], data].concat())
+    }
+
+    //One second of AAC track without tag, stco points to mdat
+    fn m4a() -> Vec<u8> {
+        let audio = b"AUDIO";
+        let mvhd = full_atom(b"mvhd", 0, &[
+            &[0u8; 8][..], &44100u32.to_be_bytes(), &44100u32.to_be_bytes(),
+            &0x10000u32.to_be_bytes(), &0x100u16.to_be_bytes(), &[0u8; 10],
+            &[0, 1, 0, 0], &[0u8; 12], &[0, 1, 0, 0], &[0u8; 12], &[0x40, 0, 0, 0],
+            &[0u8; 24], &2u32.to_be_bytes()
+        ].concat());
+        let tkhd = full_atom(b"tkhd", 1, &[&[0u8; 8][..], &1u32.to_be_bytes(), &[0u8; 4], &44100u32.to_be_bytes(), &[0u8; 60]].concat());
+        let mdhd = full_atom(b"mdhd", 0, &[&[0u8; 8][..], &44100u32.to_be_bytes(), &44100u32.to_be_bytes(), &[0x55, 0xc4, 0, 0]].concat());
+        let hdlr = full_atom(b"hdlr", 0, &[&[0u8; 4][..], b"soun", &[0u8; 13]].concat());
+        let dinf = atom(b"dinf", &full_atom(b"dref", 0, &[&1u32.to_be_bytes()[..], &full_atom(b"url ", 1, &[])].concat()));
+        let mp4a = atom(b"mp4a", &[&[0u8; 6][..], &1u16.to_be_bytes(), &[0u8; 8], &2u16.to_be_bytes(), &16u16.to_be_bytes(), &[0u8; 4], &(44100u32 << 16).to_be_bytes()].concat());
+        let stsd = full_atom(b"stsd", 0, &[&1u32.to_be_bytes()[..], &mp4a].concat());
+        let stbl = |offset: u32| atom(b"stbl", &[
+            stsd.clone(),
+            full_atom(b"stts", 0, &0u32.to_be_bytes()),
+            full_atom(b"stsc", 0, &0u32.to_be_bytes()),
+            full_atom(b"stsz", 0, &[0u8; 8]),
+            full_atom(b"stco", 0, &[&1u32.to_be_bytes()[..], &offset.to_be_bytes()].concat())
+        ].concat());
+        let moov = |offset: u32| {
+            let minf = atom(b"minf", &[full_atom(b"smhd", 0, &[0u8; 4]), dinf.clone(), stbl(offset)].concat());
+            let trak = atom(b"trak", &[tkhd.clone(), atom(b"mdia", &[mdhd.clone(), hdlr.clone(), minf].concat())].concat());
+            atom(b"moov", &[mvhd.clone(), trak].concat())
+        };
+        let ftyp = atom(b"ftyp", b"M4A \0\0\0\0M4A mp42isom");
+        let offset = (ftyp.len() + moov(0).len() + 8) as u32;
+        [ftyp, moov(offset), atom(b"mdat", audio)].concat()
+    }
+
+    fn read(path: &str) -> Box<dyn AudioTag> {
+        Mp4Backend.read(path, &TaggerConfig::from_json(&json!({})).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn itunes_atoms() {
+        assert_eq!(ident("TITLE"), DataIdent::Fourcc(Fourcc(*b"\xa9nam")));
+        assert_eq!(ident("DESCRIPTION"), DataIdent::Fourcc(Fourcc(*b"\xa9cmt")));
+        assert_eq!(ident("GROUPING"), DataIdent::Fourcc(Fourcc(*b"\xa9grp")));
+        assert_eq!(ident("CATALOGNUMBER"), DataIdent::Freeform { mean: String::from(ITUNES_MEAN), name: String::from("CATALOGNUMBER") });
+    }
+
+    #[test]
+    fn fixture() {
+        let dir = TempDir::new("mp4");
+        let path = dir.write("song.m4a", m4a());
+        let properties = properties::mp4(&path).unwrap();
+        assert_eq!((properties.codec.as_str(), properties.duration, properties.sample_rate), ("AAC", Some(1.0), Some(44100)));
+        assert!(properties::mp4_is_audio(&path));
+    }
+
+    #[test]
+    fn roundtrip_fields() {
+        let dir = TempDir::new("mp4");
+        let path = dir.write("song.m4a", m4a());
+        let mut tag = read(&path);
+        tag.set(&Field::Title, vec![String::from("Song")]);
+        tag.set(&Field::Artist, vec![String::from("A"), String::from("B")]);
+        tag.set(&Field::Genre, vec![String::from("House"), String::from("Techno")]);
+        tag.set(&Field::Track, vec![String::from("3")]);
+        let custom = Field::Custom { id3: None, vorbis: Some(String::from("CATALOGNUMBER")) };
+        tag.set(&custom, vec![String::from("CAT001")]);
+        tag.save(&path).unwrap();
+
+        let tag = read(&path);
+        assert_eq!(tag.get(&Field::Title), Some(vec![String::from("Song")]));
+        assert_eq!(tag.get(&Field::Artist), Some(vec![String::from("A; B")]));
+        assert_eq!(tag.get(&Field::Genre), Some(vec![String::from("House"), String::from("Techno")]));
+        assert_eq!(tag.get(&Field::Track), Some(vec![String::from("3")]));
+        assert_eq!(tag.get(&custom), Some(vec![String::from("CAT001")]));
+        //Audio and chunk offsets still valid
+        let data = std::fs::read(&path).unwrap();
+        let (start, end) = integrity::audio_range(&data).unwrap();
+        assert_eq!(&data[start..end], b"AUDIO");
+        assert_eq!(properties::mp4(&path).unwrap().duration, Some(1.0));
+    }
+
+    #[test]
+    fn roundtrip_cover() {
+        let dir = TempDir::new("mp4");
+        let path = dir.write("song.m4a", m4a());
+        let mut tag = read(&path);
+        assert!(!tag.has_art());
+        tag.set_art(Art { data: vec![0xFF, 0xD8, 0xFF, 0xD9], mime: String::from("image/jpeg"), width: 1, height: 1, depth: 24 });
+        tag.save(&path).unwrap();
+        let mut tag = read(&path);
+        assert!(tag.has_art());
+        tag.remove_art();
+        tag.save(&path).unwrap();
+        assert!(!read(&path).has_art());
+    }
+}
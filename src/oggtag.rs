@@ -0,0 +1,316 @@
+use ogg::reading::PacketReader;
+use ogg::writing::{PacketWriter, PacketWriteEndInfo};
+use ogg::Packet;
+use metaflac::block::{VorbisComment, Picture as FLACPicture, PictureType as FLACPictureType};
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::backend::{AudioTag, Backend, Field};
+use crate::tagger::{MusicFileType, TaggerConfig};
+use crate::mapping::TagKeys;
+use crate::art::Art;
+use crate::properties::{self, AudioProperties};
+use crate::flac;
+
+const VORBIS_HEADER: &[u8] = b"\x03vorbis";
+const OPUS_HEADER: &[u8] = b"OpusTags";
+const PICTURE_KEY: &str = "METADATA_BLOCK_PICTURE";
+//Unofficial base64 image written by older taggers, without picture type
+const LEGACY_KEYS: [&str; 2] = ["COVERART", "COVERARTMIME"];
+
+//Parsed Ogg Vorbis / Opus file
+struct OggFile {
+    packets: Vec<Packet>,
+    //Index of comment header packet
+    comment: usize,
+    opus: bool
+}
+
+impl OggFile {
+    fn read(path: &str) -> Result<OggFile, Box<dyn std::error::Error>> {
+        let mut reader = PacketReader::new(BufReader::new(File::open(path)?));
+        let mut packets = vec![];
+        while let Some(packet) = reader.read_packet()? {
+            packets.push(packet);
+        }
+        //Comment header is second packet of first stream
+        let serial = packets.first().ok_or("Empty Ogg file!")?.stream_serial();
+        let comment = packets.iter().enumerate().filter(|(_, p)| p.stream_serial() == serial).nth(1).ok_or("Missing comment header!")?.0;
+        let opus = match &packets[comment].data {
+            d if d.starts_with(VORBIS_HEADER) => false,
+            d if d.starts_with(OPUS_HEADER) => true,
+            _ => return Err("Unsupported Ogg codec!".into())
+        };
+        Ok(OggFile { packets, comment, opus })
+    }
+
+    fn vorbis_comment(&self) -> Result<VorbisComment, Box<dyn std::error::Error>> {
+        let data = &self.packets[self.comment].data;
+        let header = if self.opus { OPUS_HEADER } else { VORBIS_HEADER };
+        Ok(VorbisComment::from_bytes(&data[header.len()..])?)
+    }
+
+    fn set_vorbis_comment(&mut self, vorbis: &VorbisComment) {
+        let mut data = match self.opus {
+            true => OPUS_HEADER.to_vec(),
+            false => VORBIS_HEADER.to_vec()
+        };
+        data.extend(vorbis.to_bytes());
+        //Vorbis framing bit
+        if !self.opus {
+            data.push(1);
+        }
+        self.packets[self.comment].data = data;
+    }
+
+    //Write all packets again, keeping page boundaries and granule positions
+    fn save(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut out = vec![];
+        {
+            let mut writer = PacketWriter::new(&mut out);
+            for packet in self.packets.drain(..) {
+                let info = match (packet.last_in_stream(), packet.last_in_page()) {
+                    (true, _) => PacketWriteEndInfo::EndStream,
+                    (false, true) => PacketWriteEndInfo::EndPage,
+                    _ => PacketWriteEndInfo::NormalPacket
+                };
+                let (serial, absgp) = (packet.stream_serial(), packet.absgp_page());
+                writer.write_packet(packet.data, serial, info, absgp)?;
+            }
+        }
+        std::fs::write(path, out)?;
+        Ok(())
+    }
+}
+
+//Comment header packet, original tags for undo journal
+pub fn comment_packet(path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let file = OggFile::read(path)?;
+    Ok(file.packets[file.comment].data.to_owned())
+}
+
+//Replace comment header packet with journaled one
+pub fn write_comment_packet(path: &str, data: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = OggFile::read(path)?;
+    file.packets[file.comment].data = data;
+    file.save(path)
+}
+
+pub struct OggBackend;
+
+impl Backend for OggBackend {
+    fn file_type(&self) -> MusicFileType {
+        MusicFileType::OGG
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &[".ogg", ".oga", ".opus"]
+    }
+
+    fn detect(&self, header: &[u8]) -> bool {
+        header.starts_with(b"OggS")
+    }
+
+    fn read(&self, path: &str, config: &TaggerConfig) -> Result<Box<dyn AudioTag>, Box<dyn std::error::Error>> {
+        let file = OggFile::read(path)?;
+        Ok(Box::new(OggTag {
+            vorbis: file.vorbis_comment()?,
+            file,
+            keys: config.keys.clone()
+        }))
+    }
+
+    fn properties(&self, path: &str) -> Result<AudioProperties, Box<dyn std::error::Error>> {
+        properties::ogg(path)
+    }
+}
+
+struct OggTag {
+    file: OggFile,
+    vorbis: VorbisComment,
+    keys: TagKeys
+}
+
+impl AudioTag for OggTag {
+    fn get(&self, field: &Field) -> Option<Vec<String>> {
+        flac::get_vorbis(&self.vorbis, field, &self.keys)
+    }
+
+    fn set(&mut self, field: &Field, values: Vec<String>) {
+        flac::set_vorbis(&mut self.vorbis, field, values, &self.keys);
+    }
+
+    fn has_art(&self) -> bool {
+        [PICTURE_KEY, LEGACY_KEYS[0]].iter().any(|k| self.vorbis.get(k).map(|p| !p.is_empty()).unwrap_or(false))
+    }
+
+    //Base64 encoded FLAC picture block
+    fn set_art(&mut self, art: Art) {
+        self.remove_art();
+        let mut pictures = self.vorbis.get(PICTURE_KEY).cloned().unwrap_or_default();
+        pictures.push(base64::encode(flac::flac_picture(art).to_bytes()));
+        self.vorbis.set(PICTURE_KEY, pictures);
+    }
+
+    //Keep other picture types, legacy cover is always front
+    fn remove_art(&mut self) {
+        for key in &LEGACY_KEYS {
+            self.vorbis.remove(key);
+        }
+        let pictures: Vec<String> = self.vorbis.get(PICTURE_KEY).cloned().unwrap_or_default().into_iter().filter(|p| {
+            decode_picture(p).map(|p| p.picture_type != FLACPictureType::CoverFront).unwrap_or(true)
+        }).collect();
+        match pictures.is_empty() {
+            true => self.vorbis.remove(PICTURE_KEY),
+            false => self.vorbis.set(PICTURE_KEY, pictures)
+        }
+    }
+
+    fn save(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.file.set_vorbis_comment(&self.vorbis);
+        self.file.save(path)
+    }
+}
+
+fn decode_picture(value: &str) -> Option<FLACPicture> {
+    base64::decode(value).ok().and_then(|data| FLACPicture::from_bytes(&data).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use serde_json::json;
+    use crate::test_util::TempDir;
+
+    fn crc(data: &[u8]) -> u32 {
+        let mut crc = 0u32;
+        for byte in data {
+            crc ^= (*byte as u32) << 24;
+            for _ in 0..8 {
+                crc = match crc & 0x80000000 {
+                    0 => crc << 1,
+                    _ => (crc << 1) ^ 0x04c11db7
+                };
+            }
+        }
+        crc
+    }
+
+    //Page with single packet shorter than 255 bytes
+    fn page(header_type: u8, granule: u64, sequence: u32, packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\0".to_vec();
+        page.push(header_type);
+        page.extend(&granule.to_le_bytes());
+        page.extend(&1234u32.to_le_bytes());
+        page.extend(&sequence.to_le_bytes());
+        page.extend(&[0u8; 4]);
+        page.push(1);
+        page.push(packet.len() as u8);
+        page.extend(packet);
+        let checksum = crc(&page);
+        page[22..26].copy_from_slice(&checksum.to_le_bytes());
+        page
+    }
+
+    fn opus_tags(comments: &[&str]) -> Vec<u8> {
+        let mut data = OPUS_HEADER.to_vec();
+        data.extend(&4u32.to_le_bytes());
+        data.extend(b"test");
+        data.extend(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            data.extend(&(comment.len() as u32).to_le_bytes());
+            data.extend(comment.as_bytes());
+        }
+        data
+    }
+
+    fn opus(comments: &[&str]) -> Vec<u8> {
+        [
+            page(2, 0, 0, b"OpusHead\x01\x02\0\0\x44\xac\0\0\0\0\0"),
+            page(0, 0, 1, &opus_tags(comments)),
+            page(0, 48000, 2, b"AUDIO1"),
+            page(4, 96000, 3, b"AUDIO2")
+        ].concat()
+    }
+
+    //Granule position and packet of every page
+    fn pages(data: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        let mut out = vec![];
+        let mut pos = 0;
+        while pos + 27 <= data.len() {
+            let segments = data[pos + 26] as usize;
+            let size: usize = data[pos+27..pos+27+segments].iter().map(|s| *s as usize).sum();
+            let start = pos + 27 + segments;
+            out.push((u64::from_le_bytes(data[pos+6..pos+14].try_into().unwrap()), data[start..start+size].to_vec()));
+            pos = start + size;
+        }
+        out
+    }
+
+    fn read(path: &str) -> Box<dyn AudioTag> {
+        OggBackend.read(path, &TaggerConfig::from_json(&json!({})).unwrap()).unwrap()
+    }
+
+    fn art() -> Art {
+        Art { data: vec![1, 2, 3], mime: String::from("image/jpeg"), width: 1, height: 1, depth: 24 }
+    }
+
+    #[test]
+    fn legacy_cover() {
+        let mut vorbis = VorbisComment::new();
+        vorbis.comments.insert(String::from("COVERART"), vec![base64::encode([1, 2, 3])]);
+        let tag = OggTag {
+            file: OggFile { packets: vec![], comment: 0, opus: true },
+            vorbis,
+            keys: TagKeys::default()
+        };
+        assert!(tag.has_art());
+    }
+
+    #[test]
+    fn roundtrip_comment_packet() {
+        let dir = TempDir::new("ogg");
+        let path = dir.write("song.opus", opus(&["TITLE=Old", "ARTIST=Artist"]));
+        let original = comment_packet(&path).unwrap();
+        assert_eq!(original, opus_tags(&["TITLE=Old", "ARTIST=Artist"]));
+
+        let mut tag = read(&path);
+        tag.set(&Field::Title, vec![String::from("New")]);
+        tag.save(&path).unwrap();
+        let tag = read(&path);
+        assert_eq!(tag.get(&Field::Title), Some(vec![String::from("New")]));
+        assert_eq!(tag.get(&Field::Artist), Some(vec![String::from("Artist")]));
+        //Other packets and granule positions are kept
+        let written = pages(&std::fs::read(&path).unwrap());
+        assert_eq!(written.len(), 4);
+        assert_eq!(written[2], (48000, b"AUDIO1".to_vec()));
+        assert_eq!(written[3], (96000, b"AUDIO2".to_vec()));
+        //Opus comment header has no framing bit
+        assert!(written[1].1.starts_with(OPUS_HEADER));
+        assert_ne!(written[1].1.last(), Some(&1));
+
+        //Undo
+        write_comment_packet(&path, original).unwrap();
+        assert_eq!(read(&path).get(&Field::Title), Some(vec![String::from("Old")]));
+    }
+
+    #[test]
+    fn roundtrip_picture() {
+        let dir = TempDir::new("ogg");
+        let path = dir.write("song.opus", opus(&["COVERART=AQID", "COVERARTMIME=image/jpeg"]));
+        let mut tag = read(&path);
+        assert!(tag.has_art());
+        tag.set_art(art());
+        tag.save(&path).unwrap();
+
+        let tag = read(&path);
+        assert!(tag.has_art());
+        let vorbis = OggFile::read(&path).unwrap().vorbis_comment().unwrap();
+        assert!(vorbis.get("COVERART").is_none());
+        let pictures: Vec<FLACPicture> = vorbis.get(PICTURE_KEY).unwrap().iter().filter_map(|p| decode_picture(p)).collect();
+        assert_eq!(pictures.len(), 1);
+        assert_eq!(pictures[0].picture_type, FLACPictureType::CoverFront);
+        assert_eq!((pictures[0].mime_type.as_str(), pictures[0].data.as_slice()), ("image/jpeg", &[1u8, 2, 3][..]));
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::atomic;
+use crate::discogs::{ReleaseMaster, Track};
+use crate::mapping::TemplateData;
+use crate::tagger::TaggerConfig;
+
+//Characters not allowed in file names on Windows / macOS / Linux
+const ILLEGAL: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+const MAX_NAME: usize = 200;
+
+//Rename and move files after tagging, unmatched ones optionally to quarantine
+pub struct Organizer {
+    template: Option<String>,
+    root: PathBuf,
+    quarantine: Option<PathBuf>,
+    //Tagged folder, for paths inside quarantine
+    source: PathBuf,
+    dry_run: bool,
+    //Targets of this run, collisions in dry run
+    planned: HashSet<PathBuf>
+}
+
+impl Organizer {
+    //None = disabled
+    pub fn new(config: &TaggerConfig, source: &str) -> Option<Organizer> {
+        if config.organize.is_none() && config.quarantine.is_none() {
+            return None;
+        }
+        Some(Organizer {
+            template: config.organize.to_owned(),
+            root: PathBuf::from(config.organize_root.as_deref().unwrap_or(source)),
+            quarantine: config.quarantine.as_ref().map(PathBuf::from),
+            source: PathBuf::from(source),
+            dry_run: config.dry_run,
+            planned: HashSet::new()
+        })
+    }
+
+    //Move tagged file to path by template, returns new path
+    pub fn organize(&mut self, path: &str, release: &ReleaseMaster, track: &Track, config: &TaggerConfig) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let template = match self.template.as_ref() {
+            Some(t) => t,
+            None => return Ok(None)
+        };
+        let mut data = TemplateData::new(release, track, config);
+        data.set("ext", Path::new(path).extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default());
+        //Every folder separately, so values can't create new ones
+        let mut target = self.root.to_owned();
+        for part in template.split(['/', '\\']).filter(|p| !p.trim().is_empty()) {
+            let name = data.render_with(part, sanitize).map(|n| clean_name(&n)).filter(|n| !n.is_empty());
+            target.push(name.unwrap_or_else(|| String::from("Unknown")));
+        }
+        self.move_file(path, target).map(Some)
+    }
+
+    //Move unmatched file, keeping path relative to tagged folder
+    pub fn quarantine(&mut self, path: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let target = match self.quarantine.as_ref() {
+            Some(q) => q.join(Path::new(path).strip_prefix(&self.source).unwrap_or_else(|_| Path::new(path).file_name().map(Path::new).unwrap())),
+            None => return Ok(None)
+        };
+        self.move_file(path, target).map(Some)
+    }
+
+    fn move_file(&mut self, path: &str, target: PathBuf) -> Result<String, Box<dyn std::error::Error>> {
+        if Path::new(path) == target {
+            return Ok(path.to_owned());
+        }
+        let target = unique(target, &self.planned);
+        self.planned.insert(target.to_owned());
+        let target_str = target.to_str().ok_or("Invalid target path!")?.to_owned();
+        if self.dry_run {
+            println!("Would move: {} -> {}", path, target_str);
+            return Ok(target_str);
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        atomic::rename(path, &target_str)?;
+        println!("Moved: {} -> {}", path, target_str);
+        Ok(target_str)
+    }
+}
+
+//Illegal and control characters in value
+fn sanitize(value: &str) -> String {
+    value.chars().map(|c| match c {
+        c if ILLEGAL.contains(&c) || c.is_control() => '_',
+        c => c
+    }).collect()
+}
+
+//Windows doesn't allow trailing dots and spaces, long names are shortened keeping extension
+fn clean_name(name: &str) -> String {
+    let name = name.trim().trim_end_matches('.').trim();
+    if name.chars().count() <= MAX_NAME {
+        return name.to_owned();
+    }
+    let ext = Path::new(name).extension().map(|e| format!(".{}", e.to_string_lossy())).filter(|e| e.len() < 10).unwrap_or_default();
+    let stem: String = name.chars().take(MAX_NAME - ext.chars().count()).collect();
+    format!("{}{}", stem.trim_end(), ext)
+}
+
+//Add (2), (3)... if file exists or is already planned
+fn unique(path: PathBuf, planned: &HashSet<PathBuf>) -> PathBuf {
+    let taken = |p: &PathBuf| p.exists() || planned.contains(p);
+    if !taken(&path) {
+        return path;
+    }
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let ext = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    (2..).map(|i| path.with_file_name(format!("{} ({}){}", stem, i, ext))).find(|p| !taken(p)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn sanitize_names() {
+        assert_eq!(sanitize("AC/DC: Live?"), "AC_DC_ Live_");
+        assert_eq!(sanitize("Tab\there"), "Tab_here");
+        assert_eq!(clean_name(" Name... "), "Name");
+        let long = format!("{}.flac", "a".repeat(300));
+        let cleaned = clean_name(&long);
+        assert_eq!(cleaned.chars().count(), MAX_NAME);
+        assert!(cleaned.ends_with("a.flac"));
+    }
+
+    #[test]
+    fn unique_paths() {
+        let dir = TempDir::new("organize");
+        dir.write("song.mp3", b"");
+        let dir = dir.path();
+        let mut planned = HashSet::new();
+        planned.insert(dir.join("song (2).mp3"));
+        assert_eq!(unique(dir.join("song.mp3"), &planned), dir.join("song (3).mp3"));
+        assert_eq!(unique(dir.join("other.mp3"), &planned), dir.join("other.mp3"));
+    }
+
+    #[test]
+    fn quarantine_keeps_relative_path() {
+        let config = TaggerConfig::from_json(&json!({"quarantine": "/quarantine", "dryRun": true})).unwrap();
+        let mut organizer = Organizer::new(&config, "/music").unwrap();
+        let first = organizer.quarantine("/music/Artist/song.mp3").unwrap();
+        //Second file with same target in dry run
+        let second = organizer.quarantine("/music/Artist/song.mp3").unwrap();
+        let outside = organizer.quarantine("/other/track.mp3").unwrap();
+        assert_eq!(first.map(PathBuf::from), Some(Path::new("/quarantine").join("Artist").join("song.mp3")));
+        assert_eq!(second.map(PathBuf::from), Some(Path::new("/quarantine").join("Artist").join("song (2).mp3")));
+        assert_eq!(outside.map(PathBuf::from), Some(Path::new("/quarantine").join("track.mp3")));
+        assert!(Organizer::new(&TaggerConfig::from_json(&json!({})).unwrap(), "/music").is_none());
+    }
+}
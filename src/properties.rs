@@ -0,0 +1,607 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+use crate::flacid3;
+
+//Audio stream info, read from headers without decoding
+#[derive(Debug, Clone, Default)]
+pub struct AudioProperties {
+    pub codec: String,
+    pub lossless: bool,
+    //Seconds
+    pub duration: Option<f64>,
+    pub sample_rate: Option<u32>,
+    pub bit_depth: Option<u8>,
+    pub channels: Option<u8>,
+    //kbps, average for VBR
+    pub bitrate: Option<u32>,
+    pub vbr: bool
+}
+
+impl AudioProperties {
+    pub fn new(codec: &str, lossless: bool) -> AudioProperties {
+        AudioProperties { codec: codec.to_owned(), lossless, ..Default::default() }
+    }
+
+    //Average bitrate from file size
+    fn bitrate_from_size(&mut self, size: u64) {
+        if let Some(duration) = self.duration.filter(|d| *d > 0.0) {
+            self.bitrate = Some((size as f64 * 8.0 / duration / 1000.0).round() as u32);
+        }
+    }
+}
+
+impl std::fmt::Display for AudioProperties {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.codec)?;
+        if let Some(sample_rate) = self.sample_rate {
+            write!(f, ", {} Hz", sample_rate)?;
+        }
+        if let Some(bit_depth) = self.bit_depth {
+            write!(f, ", {} bit", bit_depth)?;
+        }
+        if let Some(channels) = self.channels {
+            write!(f, ", {} ch", channels)?;
+        }
+        if let Some(bitrate) = self.bitrate {
+            write!(f, ", {} kbps{}", bitrate, if self.vbr { " VBR" } else { "" })?;
+        }
+        if let Some(duration) = self.duration {
+            let duration = duration.round() as u64;
+            write!(f, ", {}:{:02}", duration / 60, duration % 60)?;
+        }
+        Ok(())
+    }
+}
+
+fn be16(data: &[u8], pos: usize) -> u16 {
+    u16::from_be_bytes(data[pos..pos+2].try_into().unwrap())
+}
+
+fn be32(data: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes(data[pos..pos+4].try_into().unwrap())
+}
+
+fn be64(data: &[u8], pos: usize) -> u64 {
+    u64::from_be_bytes(data[pos..pos+8].try_into().unwrap())
+}
+
+fn le16(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes(data[pos..pos+2].try_into().unwrap())
+}
+
+fn le32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos+4].try_into().unwrap())
+}
+
+//Read up to len bytes from offset
+fn read_at(file: &mut File, offset: u64, len: usize) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = vec![];
+    file.take(len as u64).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+//Kbps, index = bitrate bits, [MPEG1 L1, MPEG1 L2, MPEG1 L3, MPEG2 L1, MPEG2 L2/L3]
+const MPEG_BITRATES: [[u32; 16]; 5] = [
+    [0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448, 0],
+    [0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384, 0],
+    [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 0],
+    [0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256, 0],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160, 0]
+];
+
+//MPEG audio, first frame + Xing/Info/VBRI header
+pub fn mp3(path: &str) -> Result<AudioProperties, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let id3_size = flacid3::read_id3_size(&mut file).unwrap_or(0);
+    let data = read_at(&mut file, id3_size, 65536)?;
+    //Find valid frame header
+    let pos = (0..data.len().saturating_sub(4)).find(|i| {
+        data[*i] == 0xFF && data[i+1] & 0xE0 == 0xE0 &&
+        (data[i+1] >> 3) & 3 != 1 && (data[i+1] >> 1) & 3 != 0 &&
+        data[i+2] >> 4 != 0 && data[i+2] >> 4 != 15 && (data[i+2] >> 2) & 3 != 3
+    }).ok_or("No MPEG frame found!")?;
+    let header = &data[pos..];
+    //0 = MPEG2.5, 2 = MPEG2, 3 = MPEG1
+    let version = (header[1] >> 3) & 3;
+    //1 = Layer III, 2 = Layer II, 3 = Layer I
+    let layer = (header[1] >> 1) & 3;
+    let mono = header[3] >> 6 == 3;
+    let table = match (version, layer) {
+        (3, 3) => 0,
+        (3, 2) => 1,
+        (3, _) => 2,
+        (_, 3) => 3,
+        _ => 4
+    };
+    let bitrate = MPEG_BITRATES[table][(header[2] >> 4) as usize];
+    let sample_rate = [44100, 48000, 32000][((header[2] >> 2) & 3) as usize] >> match version {
+        3 => 0,
+        2 => 1,
+        _ => 2
+    };
+    let samples_per_frame = match (version, layer) {
+        (_, 3) => 384,
+        (3, _) | (_, 2) => 1152,
+        _ => 576
+    };
+
+    let mut properties = AudioProperties::new(["MP3", "MP2", "MP1"][(layer - 1) as usize], false);
+    properties.sample_rate = Some(sample_rate);
+    properties.channels = Some(if mono { 1 } else { 2 });
+    let audio_size = size.saturating_sub(id3_size + pos as u64);
+
+    //Frame count from Xing/Info (after side info) or VBRI header
+    let xing = pos + 4 + match (version, mono) {
+        (3, true) => 17,
+        (3, false) => 32,
+        (_, true) => 9,
+        _ => 17
+    };
+    let frames = if data.len() >= xing + 12 && (&data[xing..xing+4] == b"Xing" || &data[xing..xing+4] == b"Info") {
+        properties.vbr = &data[xing..xing+4] == b"Xing";
+        match be32(&data, xing + 4) & 1 {
+            1 => Some(be32(&data, xing + 8)),
+            _ => None
+        }
+    } else if data.len() >= pos + 36 + 18 && &data[pos+36..pos+40] == b"VBRI" {
+        properties.vbr = true;
+        Some(be32(&data, pos + 36 + 14))
+    } else {
+        None
+    };
+
+    match frames {
+        Some(frames) => {
+            properties.duration = Some(frames as f64 * samples_per_frame as f64 / sample_rate as f64);
+            properties.bitrate_from_size(audio_size);
+        },
+        //CBR
+        None => {
+            properties.bitrate = Some(bitrate);
+            properties.duration = Some(audio_size as f64 * 8.0 / (bitrate as f64 * 1000.0));
+        }
+    }
+    Ok(properties)
+}
+
+//STREAMINFO block, after optional ID3
+pub fn flac(path: &str) -> Result<AudioProperties, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let id3_size = flacid3::read_id3_size(&mut file).unwrap_or(0);
+    let data = read_at(&mut file, id3_size, 42)?;
+    if data.len() < 42 || &data[0..4] != b"fLaC" || data[4] & 0x7f != 0 {
+        return Err("Missing FLAC STREAMINFO!".into());
+    }
+    let info = &data[8..];
+    let sample_rate = ((info[10] as u32) << 12) | ((info[11] as u32) << 4) | (info[12] as u32 >> 4);
+    let total_samples = (((info[13] & 0xF) as u64) << 32) | be32(info, 14) as u64;
+
+    let mut properties = AudioProperties::new("FLAC", true);
+    properties.sample_rate = Some(sample_rate);
+    properties.channels = Some(((info[12] >> 1) & 7) + 1);
+    properties.bit_depth = Some((((info[12] & 1) << 4) | (info[13] >> 4)) + 1);
+    properties.vbr = true;
+    if sample_rate > 0 && total_samples > 0 {
+        properties.duration = Some(total_samples as f64 / sample_rate as f64);
+    }
+    properties.bitrate_from_size(size.saturating_sub(id3_size));
+    Ok(properties)
+}
+
+//Walk RIFF/IFF chunks, returns offset and size of chunk data
+fn find_chunk(file: &mut File, start: u64, end: u64, id: &[u8], big_endian: bool) -> Result<Option<(u64, u32)>, Box<dyn std::error::Error>> {
+    let mut pos = start;
+    while pos + 8 <= end {
+        let header = read_at(file, pos, 8)?;
+        if header.len() < 8 {
+            break;
+        }
+        let size = match big_endian {
+            true => be32(&header, 4),
+            false => le32(&header, 4)
+        };
+        if &header[0..4] == id {
+            return Ok(Some((pos + 8, size)));
+        }
+        pos += 8 + size as u64 + (size % 2) as u64;
+    }
+    Ok(None)
+}
+
+//COMM chunk
+pub fn aiff(path: &str) -> Result<AudioProperties, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let (offset, _) = find_chunk(&mut file, 12, size, b"COMM", true)?.ok_or("Missing AIFF COMM chunk!")?;
+    let comm = read_at(&mut file, offset, 18)?;
+    if comm.len() < 18 {
+        return Err("Invalid AIFF COMM chunk!".into());
+    }
+    //80 bit extended float
+    let exponent = (((comm[8] & 0x7f) as i32) << 8 | comm[9] as i32) - 16383;
+    let sample_rate = (be64(&comm, 10) as f64 * 2f64.powi(exponent - 63)).round() as u32;
+    let frames = be32(&comm, 2);
+
+    let mut properties = AudioProperties::new("PCM", true);
+    properties.channels = Some(be16(&comm, 0) as u8);
+    properties.bit_depth = Some(be16(&comm, 6) as u8);
+    properties.sample_rate = Some(sample_rate);
+    if sample_rate > 0 {
+        properties.duration = Some(frames as f64 / sample_rate as f64);
+        //Broken header can overflow
+        properties.bitrate = sample_rate.checked_mul(be16(&comm, 6) as u32)
+            .and_then(|b| b.checked_mul(be16(&comm, 0) as u32))
+            .map(|b| b / 1000);
+    }
+    Ok(properties)
+}
+
+//fmt and data chunks
+pub fn wav(path: &str) -> Result<AudioProperties, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let (offset, _) = find_chunk(&mut file, 12, size, b"fmt ", false)?.ok_or("Missing WAV fmt chunk!")?;
+    let fmt = read_at(&mut file, offset, 16)?;
+    if fmt.len() < 16 {
+        return Err("Invalid WAV fmt chunk!".into());
+    }
+    let (codec, lossless) = match le16(&fmt, 0) {
+        //PCM, float, extensible
+        1 | 3 | 0xFFFE => ("PCM", true),
+        0x55 => ("MP3", false),
+        _ => ("Unknown", false)
+    };
+    let byte_rate = le32(&fmt, 8);
+
+    let mut properties = AudioProperties::new(codec, lossless);
+    properties.channels = Some(le16(&fmt, 2) as u8);
+    properties.sample_rate = Some(le32(&fmt, 4));
+    properties.bit_depth = Some(le16(&fmt, 14) as u8).filter(|b| *b > 0);
+    properties.bitrate = byte_rate.checked_mul(8).map(|b| b / 1000);
+    if let (Some((_, data_size)), true) = (find_chunk(&mut file, 12, size, b"data", false)?, byte_rate > 0) {
+        properties.duration = Some(data_size as f64 / byte_rate as f64);
+    }
+    Ok(properties)
+}
+
+//Atom header size and type, size includes header
+fn atom_header(data: &[u8], pos: usize) -> Option<(usize, usize, &[u8])> {
+    if pos + 8 > data.len() {
+        return None;
+    }
+    let (size, header) = match be32(data, pos) {
+        0 => (data.len() - pos, 8),
+        1 if pos + 16 <= data.len() => (be64(data, pos + 8) as usize, 16),
+        s => (s as usize, 8)
+    };
+    if size < header || pos.checked_add(size).map(|end| end > data.len()).unwrap_or(true) {
+        return None;
+    }
+    Some((size, header, &data[pos+4..pos+8]))
+}
+
+//Child atoms of atom data
+fn find_atom<'a>(data: &'a [u8], path: &[&[u8]]) -> Option<&'a [u8]> {
+    let mut pos = 0;
+    while let Some((size, header, name)) = atom_header(data, pos) {
+        if name == path[0] {
+            let inner = &data[pos+header..pos+size];
+            return match path.len() {
+                1 => Some(inner),
+                _ => find_atom(inner, &path[1..])
+            };
+        }
+        pos += size;
+    }
+    None
+}
+
+//mvhd for duration, stsd of audio track
+pub fn mp4(path: &str) -> Result<AudioProperties, Box<dyn std::error::Error>> {
+    let (moov, mdat_size) = mp4_moov(path)?;
+
+    //Audio sample entry in any track
+    let mut properties = AudioProperties::new("Unknown", false);
+    let mut pos = 0;
+    while let Some((atom_size, header, name)) = atom_header(&moov, pos) {
+        if name == b"trak" {
+            let stsd = find_atom(&moov[pos+header..pos+atom_size], &[b"mdia", b"minf", b"stbl", b"stsd"]);
+            if let Some(stsd) = stsd.filter(|s| s.len() >= 8 + 36) {
+                let entry = &stsd[8..];
+                let (codec, lossless) = match &entry[4..8] {
+                    b"mp4a" => ("AAC", false),
+                    b"alac" => ("ALAC", true),
+                    _ => ("", false)
+                };
+                if !codec.is_empty() {
+                    properties = AudioProperties::new(codec, lossless);
+                    properties.channels = Some(be16(entry, 24) as u8);
+                    properties.bit_depth = Some(be16(entry, 26) as u8).filter(|_| lossless);
+                    properties.sample_rate = Some(be32(entry, 32) >> 16);
+                    properties.vbr = lossless;
+                    break;
+                }
+            }
+        }
+        pos += atom_size;
+    }
+    //Duration in timescale units
+    if let Some(mvhd) = find_atom(&moov, &[b"mvhd"]) {
+        let (timescale, duration) = match mvhd.first() {
+            Some(1) if mvhd.len() >= 32 => (be32(mvhd, 20), be64(mvhd, 24)),
+            Some(0) if mvhd.len() >= 20 => (be32(mvhd, 12), be32(mvhd, 16) as u64),
+            _ => (0, 0)
+        };
+        if timescale > 0 {
+            properties.duration = Some(duration as f64 / timescale as f64);
+        }
+    }
+    properties.bitrate_from_size(mdat_size);
+    Ok(properties)
+}
+
+//Has sound track and no video track
+pub fn mp4_is_audio(path: &str) -> bool {
+    let moov = match mp4_moov(path) {
+        Ok((moov, _)) => moov,
+        Err(_) => return false
+    };
+    let mut handlers = vec![];
+    let mut pos = 0;
+    while let Some((atom_size, header, name)) = atom_header(&moov, pos) {
+        if name == b"trak" {
+            //Version, flags and predefined before handler type
+            if let Some(hdlr) = find_atom(&moov[pos+header..pos+atom_size], &[b"mdia", b"hdlr"]).filter(|h| h.len() >= 12) {
+                handlers.push(hdlr[8..12].to_vec());
+            }
+        }
+        pos += atom_size;
+    }
+    handlers.iter().any(|h| h == b"soun") && !handlers.iter().any(|h| h == b"vide")
+}
+
+//moov atom and total size of mdat atoms
+fn mp4_moov(path: &str) -> Result<(Vec<u8>, u64), Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    //Top level atoms, moov is loaded to memory
+    let mut pos = 0;
+    let mut moov = None;
+    let mut mdat_size = 0;
+    while pos + 8 <= size {
+        let header = read_at(&mut file, pos, 16)?;
+        let (atom_size, header_size) = match be32(&header, 0) {
+            0 => (size - pos, 8),
+            1 if header.len() >= 16 => (be64(&header, 8), 16),
+            s => (s as u64, 8)
+        };
+        if atom_size < header_size {
+            break;
+        }
+        match &header[4..8] {
+            b"moov" => moov = Some(read_at(&mut file, pos + header_size, (atom_size - header_size) as usize)?),
+            b"mdat" => mdat_size += atom_size - header_size,
+            _ => {}
+        }
+        pos = pos.saturating_add(atom_size);
+    }
+    Ok((moov.ok_or("Missing MP4 moov atom!")?, mdat_size))
+}
+
+//Identification header in first page, duration from granule position of last page
+pub fn ogg(path: &str) -> Result<AudioProperties, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let first = read_at(&mut file, 0, 4096)?;
+    if first.len() < 28 || &first[0..4] != b"OggS" || first.len() < 27 + first[26] as usize {
+        return Err("Not a valid Ogg!".into());
+    }
+    let packet = &first[27 + first[26] as usize..];
+
+    //Sample rate of granule position, pre-skip
+    let (mut properties, granule_rate, pre_skip) = if packet.len() >= 24 && packet.starts_with(b"\x01vorbis") {
+        let mut properties = AudioProperties::new("Vorbis", false);
+        properties.channels = Some(packet[11]);
+        properties.sample_rate = Some(le32(packet, 12));
+        properties.vbr = true;
+        (properties, le32(packet, 12), 0)
+    } else if packet.len() >= 16 && packet.starts_with(b"OpusHead") {
+        let mut properties = AudioProperties::new("Opus", false);
+        properties.channels = Some(packet[9]);
+        properties.sample_rate = Some(le32(packet, 12)).filter(|r| *r > 0);
+        properties.vbr = true;
+        //Always decoded at 48kHz
+        (properties, 48000, le16(packet, 10) as u64)
+    } else if packet.starts_with(b"\x7fFLAC") {
+        (AudioProperties::new("FLAC", true), 0, 0)
+    } else {
+        return Err("Unsupported Ogg codec!".into());
+    };
+
+    //Last page
+    let start = size.saturating_sub(65536);
+    let last = read_at(&mut file, start, 65536)?;
+    let page = (0..last.len().saturating_sub(14)).rev().find(|i| &last[*i..*i+4] == b"OggS");
+    if let (Some(page), true) = (page, granule_rate > 0) {
+        let granule = u64::from_le_bytes(last[page+6..page+14].try_into().unwrap());
+        properties.duration = Some(granule.saturating_sub(pre_skip) as f64 / granule_rate as f64);
+    }
+    properties.bitrate_from_size(size);
+    Ok(properties)
+}
+
+const WAVPACK_SAMPLE_RATES: [u32; 15] = [6000, 8000, 9600, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000, 64000, 88200, 96000, 192000];
+
+//WavPack block header or Monkey's Audio header
+pub fn ape(path: &str) -> Result<AudioProperties, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let data = read_at(&mut file, 0, 128)?;
+
+    let mut properties = if data.len() >= 32 && data.starts_with(b"wvpk") {
+        let flags = le32(&data, 24);
+        let mut properties = AudioProperties::new("WavPack", flags & 8 == 0);
+        properties.bit_depth = Some(((flags & 3) as u8 + 1) * 8);
+        properties.channels = Some(if flags & 4 != 0 { 1 } else { 2 });
+        properties.sample_rate = WAVPACK_SAMPLE_RATES.get(((flags >> 23) & 0xF) as usize).copied();
+        let total_samples = le32(&data, 12);
+        if let (Some(sample_rate), true) = (properties.sample_rate, total_samples != u32::MAX) {
+            properties.duration = Some(total_samples as f64 / sample_rate as f64);
+        }
+        properties
+    } else if data.len() >= 32 && data.starts_with(b"MAC ") {
+        let version = le16(&data, 4);
+        //(bits, channels, sample rate, total frames, blocks per frame, final frame blocks)
+        let info = if version >= 3980 {
+            let header = le32(&data, 8) as usize;
+            if data.len() < header + 24 {
+                return Err("Invalid Monkey's Audio header!".into());
+            }
+            (le16(&data, header + 16), le16(&data, header + 18), le32(&data, header + 20), le32(&data, header + 12), le32(&data, header + 4), le32(&data, header + 8))
+        } else {
+            let flags = le16(&data, 8);
+            let bits = if flags & 1 != 0 { 8 } else if flags & 8 != 0 { 24 } else { 16 };
+            let blocks_per_frame = match (version, le16(&data, 6)) {
+                (v, _) if v >= 3950 => 73728 * 4,
+                (v, c) if v >= 3900 || (v >= 3800 && c == 4000) => 73728,
+                _ => 9216
+            };
+            (bits, le16(&data, 10), le32(&data, 12), le32(&data, 24), blocks_per_frame, le32(&data, 28))
+        };
+        let (bits, channels, sample_rate, total_frames, blocks_per_frame, final_blocks) = info;
+        let mut properties = AudioProperties::new("Monkey's Audio", true);
+        properties.bit_depth = Some(bits as u8);
+        properties.channels = Some(channels as u8);
+        properties.sample_rate = Some(sample_rate);
+        if total_frames > 0 && sample_rate > 0 {
+            let samples = (total_frames as u64 - 1) * blocks_per_frame as u64 + final_blocks as u64;
+            properties.duration = Some(samples as f64 / sample_rate as f64);
+        }
+        properties
+    } else {
+        return Err("Unsupported APE file!".into());
+    };
+    properties.vbr = true;
+    properties.bitrate_from_size(size);
+    Ok(properties)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    //Ogg page with single packet
+    fn ogg_page(granule: u64, packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\0\0".to_vec();
+        page.extend(&granule.to_le_bytes());
+        page.extend(&[0u8; 12]);
+        page.push(1);
+        page.push(packet.len() as u8);
+        page.extend(packet);
+        page
+    }
+
+    fn atom(name: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut out = ((data.len() + 8) as u32).to_be_bytes().to_vec();
+        out.extend(name);
+        out.extend(data);
+        out
+    }
+
+    #[test]
+    fn atom_header_bounds() {
+        let data = atom(b"moov", &atom(b"mvhd", &[0u8; 4]));
+        assert_eq!(atom_header(&data, 0).map(|(s, h, n)| (s, h, n.to_vec())), Some((20, 8, b"moov".to_vec())));
+        assert_eq!(find_atom(&data, &[b"moov", b"mvhd"]), Some(&[0u8; 4][..]));
+        assert_eq!(find_atom(&data, &[b"moov", b"trak"]), None);
+        //Longer than data
+        assert!(atom_header(&data[..19], 0).is_none());
+        //Smaller than header
+        assert!(atom_header(&[0, 0, 0, 4, b'f', b'r', b'e', b'e'], 0).is_none());
+        //64 bit size overflowing position
+        let mut large = vec![0, 0, 0, 1];
+        large.extend(b"mdat");
+        large.extend(&u64::MAX.to_be_bytes());
+        assert!(atom_header(&large, 0).is_none());
+    }
+
+    #[test]
+    fn ogg_opus() {
+        let mut head = b"OpusHead\x01\x02".to_vec();
+        head.extend(&312u16.to_le_bytes());
+        head.extend(&44100u32.to_le_bytes());
+        head.extend(&[0, 0, 0]);
+        let mut data = ogg_page(0, &head);
+        data.extend(ogg_page(48000 * 10 + 312, &[0u8; 100]));
+        let dir = TempDir::new("properties");
+        let properties = ogg(&dir.write("opus.ogg", &data)).unwrap();
+        assert_eq!(properties.codec, "Opus");
+        assert_eq!(properties.channels, Some(2));
+        assert_eq!(properties.sample_rate, Some(44100));
+        assert_eq!(properties.duration, Some(10.0));
+        assert!(!properties.lossless);
+    }
+
+    #[test]
+    fn ogg_truncated() {
+        let mut data = ogg_page(0, b"OpusHead\x01\x02\0\0\0\0\0\0\0\0\0");
+        //Segment table longer than file
+        data[26] = 255;
+        let dir = TempDir::new("properties");
+        assert!(ogg(&dir.write("truncated.ogg", &data)).is_err());
+        assert!(ogg(&dir.write("header.ogg", b"OggS")).is_err());
+    }
+
+    fn wav_file(sample_rate: u32, byte_rate: u32) -> Vec<u8> {
+        let mut fmt = 1u16.to_le_bytes().to_vec();
+        fmt.extend(&2u16.to_le_bytes());
+        fmt.extend(&sample_rate.to_le_bytes());
+        fmt.extend(&byte_rate.to_le_bytes());
+        fmt.extend(&4u16.to_le_bytes());
+        fmt.extend(&16u16.to_le_bytes());
+        let mut data = b"RIFF\0\0\0\0WAVEfmt ".to_vec();
+        data.extend(&16u32.to_le_bytes());
+        data.extend(fmt);
+        data.extend(b"data");
+        data.extend(&8u32.to_le_bytes());
+        data.extend(&[0u8; 8]);
+        data
+    }
+
+    fn aiff_file(bits: u16) -> Vec<u8> {
+        let mut comm = 2u16.to_be_bytes().to_vec();
+        comm.extend(&44100u32.to_be_bytes());
+        comm.extend(&bits.to_be_bytes());
+        //44100 as 80 bit extended float
+        comm.extend(&[0x40, 0x0e, 0xac, 0x44, 0, 0, 0, 0, 0, 0]);
+        let mut data = b"FORM\0\0\0\0AIFFCOMM".to_vec();
+        data.extend(&18u32.to_be_bytes());
+        data.extend(comm);
+        data
+    }
+
+    #[test]
+    fn pcm_bitrate() {
+        let dir = TempDir::new("properties");
+        let properties = wav(&dir.write("song.wav", wav_file(44100, 176400))).unwrap();
+        assert_eq!(properties.bitrate, Some(1411));
+        assert_eq!(properties.duration, Some(8.0 / 176400.0));
+        assert_eq!(aiff(&dir.write("song.aiff", aiff_file(16))).unwrap().bitrate, Some(1411));
+    }
+
+    #[test]
+    fn pcm_bitrate_overflow() {
+        let dir = TempDir::new("properties");
+        assert_eq!(wav(&dir.write("song.wav", wav_file(44100, u32::MAX))).unwrap().bitrate, None);
+        let properties = aiff(&dir.write("song.aiff", aiff_file(u16::MAX))).unwrap();
+        assert_eq!(properties.sample_rate, Some(44100));
+        assert_eq!(properties.bitrate, None);
+    }
+}
@@ -0,0 +1,481 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use serde_json::{json, Value};
+use chrono::Local;
+
+use crate::properties::AudioProperties;
+use crate::runner::{Tagged, TagError};
+use crate::skip::SkipCounts;
+use crate::state::{self, StateEntry};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    Json,
+    Csv,
+    //Single page with inline styles
+    Html
+}
+
+impl ReportFormat {
+    //None = don't write report
+    pub fn parse(value: &str) -> Result<Option<ReportFormat>, String> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "none" => Ok(None),
+            "json" => Ok(Some(ReportFormat::Json)),
+            "csv" => Ok(Some(ReportFormat::Csv)),
+            "html" => Ok(Some(ReportFormat::Html)),
+            v => Err(format!("Invalid report format: {}", v))
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ReportFormat::Json => "json",
+            ReportFormat::Csv => "csv",
+            ReportFormat::Html => "html"
+        }
+    }
+}
+
+//Why file failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCategory {
+    NoMatch,
+    //Discogs request failed
+    Network,
+    //Unexpected Discogs response
+    Matching,
+    Write,
+    //Audio data changed by writing tag
+    Integrity,
+    //File or tag couldn't be read
+    Invalid
+}
+
+impl ErrorCategory {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorCategory::NoMatch => "no-match",
+            ErrorCategory::Network => "network",
+            ErrorCategory::Matching => "matching",
+            ErrorCategory::Write => "write",
+            ErrorCategory::Integrity => "integrity",
+            ErrorCategory::Invalid => "invalid"
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<ErrorCategory> {
+        match value.trim().to_lowercase().as_str() {
+            "no-match" | "nomatch" => Some(ErrorCategory::NoMatch),
+            "network" => Some(ErrorCategory::Network),
+            "matching" => Some(ErrorCategory::Matching),
+            "write" => Some(ErrorCategory::Write),
+            "integrity" => Some(ErrorCategory::Integrity),
+            "invalid" => Some(ErrorCategory::Invalid),
+            _ => None
+        }
+    }
+
+    //Comma separated list
+    pub fn parse_list(value: &str) -> Result<Vec<ErrorCategory>, String> {
+        value.split(',').map(|c| c.trim()).filter(|c| !c.is_empty() && *c != "all").map(|c| {
+            ErrorCategory::parse(c).ok_or(format!("Invalid error category: {}", c))
+        }).collect()
+    }
+}
+
+//Result of single file
+#[derive(Debug, Clone)]
+pub struct ReportEntry {
+    pub path: String,
+    pub ok: bool,
+    pub category: Option<ErrorCategory>,
+    pub error: Option<String>,
+    pub release_id: Option<i64>,
+    pub release_url: Option<String>,
+    pub position: Option<String>,
+    pub score: Option<u8>,
+    //Names of changed fields
+    pub changed: Vec<String>,
+    //None = file couldn't be read
+    pub properties: Option<AudioProperties>
+}
+
+impl ReportEntry {
+    //Path = after organizing
+    pub fn new(path: &str, result: &Result<Tagged, TagError>, properties: Option<&AudioProperties>) -> ReportEntry {
+        let properties = properties.cloned();
+        match result {
+            Ok(tagged) => {
+                let mut changed: Vec<String> = tagged.diff.changes.iter().filter(|c| c.changed()).map(|c| c.field.to_owned()).collect();
+                if tagged.diff.art_before != tagged.diff.art_after || tagged.diff.art_changed {
+                    changed.push(String::from("Art"));
+                }
+                ReportEntry {
+                    path: path.to_owned(),
+                    ok: true,
+                    category: None,
+                    error: None,
+                    release_id: Some(tagged.release.id),
+                    release_url: Some(tagged.release.full_url()),
+                    position: tagged.track.as_ref().map(|t| t.position.to_owned()),
+                    score: Some(tagged.score),
+                    changed,
+                    properties
+                }
+            },
+            Err(e) => ReportEntry {
+                path: path.to_owned(),
+                ok: false,
+                category: Some(e.category),
+                error: Some(e.message.to_owned()),
+                release_id: None,
+                release_url: None,
+                position: None,
+                score: None,
+                changed: vec![],
+                properties
+            }
+        }
+    }
+
+    fn from_state(entry: &StateEntry) -> ReportEntry {
+        ReportEntry {
+            path: entry.path.to_owned(),
+            ok: entry.ok,
+            category: entry.category,
+            error: entry.error.to_owned(),
+            release_id: entry.release_id,
+            release_url: entry.release_url.to_owned(),
+            position: None,
+            score: None,
+            changed: vec![],
+            properties: None
+        }
+    }
+
+    fn from_json(json: &Value) -> Option<ReportEntry> {
+        Some(ReportEntry {
+            path: json["path"].as_str()?.to_owned(),
+            ok: json["status"].as_str()? == "ok",
+            category: json["category"].as_str().and_then(ErrorCategory::parse),
+            error: json["error"].as_str().map(String::from),
+            release_id: json["release"].as_i64(),
+            release_url: json["url"].as_str().map(String::from),
+            position: json["position"].as_str().map(String::from),
+            score: json["score"].as_u64().map(|s| s as u8),
+            changed: json["changed"].as_array().map(|a| a.iter().filter_map(|c| c.as_str().map(String::from)).collect()).unwrap_or_default(),
+            properties: json["codec"].as_str().map(|codec| AudioProperties {
+                duration: json["duration"].as_f64(),
+                sample_rate: json["sampleRate"].as_u64().map(|v| v as u32),
+                bit_depth: json["bitDepth"].as_u64().map(|v| v as u8),
+                bitrate: json["bitrate"].as_u64().map(|v| v as u32),
+                ..AudioProperties::new(codec, json["lossless"].as_bool().unwrap_or(false))
+            })
+        })
+    }
+
+    fn status(&self) -> &'static str {
+        if self.ok { "ok" } else { "fail" }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "path": self.path,
+            "status": self.status(),
+            "category": self.category.map(|c| c.name()),
+            "error": self.error,
+            "release": self.release_id,
+            "url": self.release_url,
+            "position": self.position,
+            "score": self.score,
+            "changed": self.changed,
+            "codec": self.properties.as_ref().map(|p| p.codec.to_owned()),
+            "duration": self.properties.as_ref().and_then(|p| p.duration),
+            "sampleRate": self.properties.as_ref().and_then(|p| p.sample_rate),
+            "bitDepth": self.properties.as_ref().and_then(|p| p.bit_depth),
+            "bitrate": self.properties.as_ref().and_then(|p| p.bitrate),
+            "lossless": self.properties.as_ref().map(|p| p.lossless)
+        })
+    }
+
+    fn to_csv(&self) -> String {
+        let values = [
+            self.path.to_owned(),
+            self.status().to_owned(),
+            self.category.map(|c| c.name().to_owned()).unwrap_or_default(),
+            self.error.to_owned().unwrap_or_default(),
+            self.release_id.map(|i| i.to_string()).unwrap_or_default(),
+            self.release_url.to_owned().unwrap_or_default(),
+            self.position.to_owned().unwrap_or_default(),
+            self.score.map(|s| s.to_string()).unwrap_or_default(),
+            self.changed.join("; "),
+            self.properties.as_ref().map(|p| p.codec.to_owned()).unwrap_or_default(),
+            self.properties.as_ref().and_then(|p| p.duration).map(|d| format!("{:.3}", d)).unwrap_or_default(),
+            self.properties.as_ref().and_then(|p| p.sample_rate).map(|s| s.to_string()).unwrap_or_default(),
+            self.properties.as_ref().and_then(|p| p.bit_depth).map(|b| b.to_string()).unwrap_or_default(),
+            self.properties.as_ref().and_then(|p| p.bitrate).map(|b| b.to_string()).unwrap_or_default(),
+            self.properties.as_ref().map(|p| p.lossless.to_string()).unwrap_or_default()
+        ];
+        values.iter().map(|v| csv_escape(v)).collect::<Vec<String>>().join(",")
+    }
+}
+
+//Results of whole run
+pub struct Report {
+    //Tagged folder
+    pub folder: String,
+    entries: Vec<ReportEntry>,
+    date: String
+}
+
+impl Report {
+    pub fn new(folder: &str) -> Report {
+        Report { folder: folder.to_owned(), entries: vec![], date: Local::now().format("%Y-%m-%d %H:%M:%S").to_string() }
+    }
+
+    //JSON report or state file of previous run
+    pub fn load(path: &str) -> Result<Report, Box<dyn std::error::Error>> {
+        //Reports from older versions don't have folder
+        let parent = Path::new(path).parent().and_then(|p| p.to_str()).filter(|p| !p.is_empty()).unwrap_or(".");
+        let mut report = Report::new(parent);
+        let data = std::fs::read_to_string(path)?;
+        match serde_json::from_str::<Value>(&data) {
+            Ok(json) if json["files"].is_array() => {
+                if let Some(folder) = json["folder"].as_str() {
+                    report.folder = folder.to_owned();
+                }
+                report.entries = json["files"].as_array().unwrap().iter().filter_map(ReportEntry::from_json).collect();
+            },
+            _ => {
+                report.entries = state::load(Path::new(path))?.entries.iter().map(ReportEntry::from_state).collect();
+            }
+        }
+        Ok(report)
+    }
+
+    //Result of file processed again replaces previous one
+    pub fn add(&mut self, previous_path: &str, entry: ReportEntry) {
+        match self.entries.iter_mut().find(|e| e.path == previous_path) {
+            Some(e) => *e = entry,
+            None => self.entries.push(entry)
+        }
+    }
+
+    //Paths of failed files, categories empty = all
+    pub fn failed(&self, categories: &[ErrorCategory]) -> Vec<String> {
+        self.entries.iter()
+            .filter(|e| !e.ok && (categories.is_empty() || e.category.map(|c| categories.contains(&c)).unwrap_or(false)))
+            .map(|e| e.path.to_owned())
+            .collect()
+    }
+
+    //Output without extension gets one by format, None = timestamped file in tagged folder, returns written path
+    pub fn save(&self, format: ReportFormat, output: Option<&str>, skipped: &SkipCounts) -> Result<String, Box<dyn std::error::Error>> {
+        let path = match output {
+            Some(output) if Path::new(output).extension().is_some() => output.to_owned(),
+            Some(output) => format!("{}.{}", output, format.extension()),
+            None => {
+                let name = format!("discogstagger-report-{}.{}", Local::now().format("%Y%m%d-%H%M%S"), format.extension());
+                Path::new(&self.folder).join(name).to_str().ok_or("Invalid report path!")?.to_owned()
+            }
+        };
+        let data = match format {
+            ReportFormat::Json => serde_json::to_string_pretty(&self.to_json(skipped))?,
+            ReportFormat::Csv => self.to_csv(),
+            ReportFormat::Html => self.to_html(skipped)
+        };
+        let mut file = File::create(&path)?;
+        file.write_all(data.as_bytes())?;
+        Ok(path)
+    }
+
+    fn count(&self, ok: bool) -> usize {
+        self.entries.iter().filter(|e| e.ok == ok).count()
+    }
+
+    fn to_json(&self, skipped: &SkipCounts) -> Value {
+        json!({
+            "date": self.date,
+            "folder": self.folder,
+            "ok": self.count(true),
+            "fail": self.count(false),
+            "skipped": skipped.total(),
+            "files": self.entries.iter().map(|e| e.to_json()).collect::<Vec<Value>>()
+        })
+    }
+
+    fn to_csv(&self) -> String {
+        let mut out = String::from("path,status,category,error,release,url,position,score,changed,codec,duration,sample_rate,bit_depth,bitrate,lossless\n");
+        for entry in &self.entries {
+            out.push_str(&entry.to_csv());
+            out.push('\n');
+        }
+        out
+    }
+
+    fn to_html(&self, skipped: &SkipCounts) -> String {
+        let mut rows = String::new();
+        for e in &self.entries {
+            let release = match (e.release_id, e.release_url.as_ref()) {
+                (Some(id), Some(url)) => format!("<a href=\"{}\">{}</a>", html_escape(url), id),
+                _ => String::new()
+            };
+            let result = match e.ok {
+                true => String::from("OK"),
+                false => format!("{}<br><small>{}</small>", e.category.map(|c| c.name()).unwrap_or(""), html_escape(e.error.as_deref().unwrap_or("")))
+            };
+            rows.push_str(&format!(
+                "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                e.status(), html_escape(&e.path), result, release,
+                html_escape(e.position.as_deref().unwrap_or("")),
+                e.score.map(|s| s.to_string()).unwrap_or_default(),
+                html_escape(&e.changed.join(", ")),
+                html_escape(&e.properties.as_ref().map(|p| format!("{}{}", p, if p.lossless { ", lossless" } else { "" })).unwrap_or_default())
+            ));
+        }
+        let skipped = match skipped.total() {
+            0 => String::new(),
+            n => format!(", Skipped: {} ({})", n, skipped)
+        };
+        format!(r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Discogs Tagger Report</title>
+<style>
+body {{ font-family: sans-serif; background-color: #333; color: #eee; font-size: 13px; }}
+table {{ border-collapse: collapse; width: 100%; }}
+th, td {{ text-align: left; padding: 4px 8px; border-bottom: 1px solid #555; vertical-align: top; }}
+a {{ color: #ffa500; }}
+tr.ok td:nth-child(2) {{ color: #00b000; }}
+tr.fail td:nth-child(2) {{ color: #ff4040; }}
+small {{ color: #aaa; }}
+</style></head><body>
+<h2>Discogs Tagger Report</h2>
+<p>{}<br>OK: {}, Failed: {}{}</p>
+<table><tr><th>File</th><th>Result</th><th>Release</th><th>Position</th><th>Score</th><th>Changed</th><th>Audio</th></tr>
+{}</table>
+</body></html>
+"#, self.date, self.count(true), self.count(false), skipped, rows)
+    }
+}
+
+fn csv_escape(value: &str) -> String {
+    match value.contains(&[',', '"', '\n', '\r'][..]) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_owned()
+    }
+}
+
+fn html_escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn entry(path: &str, category: Option<ErrorCategory>) -> ReportEntry {
+        ReportEntry {
+            path: path.to_owned(),
+            ok: category.is_none(),
+            category,
+            error: category.map(|_| String::from("Error, \"quoted\"")),
+            release_id: Some(1).filter(|_| category.is_none()),
+            release_url: None,
+            position: None,
+            score: Some(100).filter(|_| category.is_none()),
+            changed: vec![String::from("Title"), String::from("Art")],
+            properties: None
+        }
+    }
+
+    fn flac() -> AudioProperties {
+        AudioProperties {
+            duration: Some(61.5),
+            sample_rate: Some(44100),
+            bit_depth: Some(16),
+            channels: Some(2),
+            bitrate: Some(900),
+            ..AudioProperties::new("FLAC", true)
+        }
+    }
+
+    #[test]
+    fn csv() {
+        assert_eq!(csv_escape("plain"), "plain");
+        assert_eq!(csv_escape("a,b"), "\"a,b\"");
+        assert_eq!(csv_escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_escape("line\nbreak"), "\"line\nbreak\"");
+        assert_eq!(entry("a.mp3", Some(ErrorCategory::Write)).to_csv(), "a.mp3,fail,write,\"Error, \"\"quoted\"\"\",,,,,Title; Art,,,,,,");
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(ReportFormat::parse("HTML"), Ok(Some(ReportFormat::Html)));
+        assert_eq!(ReportFormat::parse("none"), Ok(None));
+        assert!(ReportFormat::parse("xml").is_err());
+        assert_eq!(ErrorCategory::parse_list("no-match, network"), Ok(vec![ErrorCategory::NoMatch, ErrorCategory::Network]));
+        assert_eq!(ErrorCategory::parse_list("all"), Ok(vec![]));
+        assert_eq!(ErrorCategory::parse("integrity").map(|c| c.name()), Some("integrity"));
+        assert!(ErrorCategory::parse_list("timeout").is_err());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = TempDir::new("report");
+        let mut report = Report::new(dir.path().to_str().unwrap());
+        report.add("a.mp3", entry("a.mp3", Some(ErrorCategory::NoMatch)));
+        report.add("b.mp3", entry("b.mp3", Some(ErrorCategory::Network)));
+        report.add("c.mp3", entry("c.mp3", None));
+        //Processed again
+        report.add("b.mp3", entry("b.mp3", None));
+        let path = report.save(ReportFormat::Json, None, &SkipCounts::default()).unwrap();
+        assert!(path.contains("discogstagger-report-") && path.ends_with(".json"));
+        let loaded = Report::load(&path).unwrap();
+        assert_eq!(loaded.folder, report.folder);
+        assert_eq!(loaded.failed(&[]), vec![String::from("a.mp3")]);
+        assert!(loaded.failed(&[ErrorCategory::Network]).is_empty());
+        assert_eq!(loaded.entries[0].changed.len(), 2);
+    }
+
+    #[test]
+    fn failed_by_category() {
+        let mut report = Report::new("/music");
+        report.add("a.mp3", entry("a.mp3", Some(ErrorCategory::NoMatch)));
+        report.add("b.mp3", entry("b.mp3", Some(ErrorCategory::Network)));
+        report.add("c.mp3", entry("c.mp3", Some(ErrorCategory::Integrity)));
+        report.add("d.mp3", entry("d.mp3", None));
+        assert_eq!(report.failed(&[]), vec![String::from("a.mp3"), String::from("b.mp3"), String::from("c.mp3")]);
+        assert_eq!(report.failed(&[ErrorCategory::Network]), vec![String::from("b.mp3")]);
+        assert_eq!(report.failed(&[ErrorCategory::NoMatch, ErrorCategory::Integrity]), vec![String::from("a.mp3"), String::from("c.mp3")]);
+        assert!(report.failed(&[ErrorCategory::Write]).is_empty());
+    }
+
+    #[test]
+    fn html_is_escaped() {
+        let mut report = Report::new("/music");
+        report.add("<b>.mp3", entry("<b>.mp3", Some(ErrorCategory::Invalid)));
+        let html = report.to_html(&SkipCounts::default());
+        assert!(html.contains("&lt;b&gt;.mp3"));
+        assert!(html.contains("Error, &quot;quoted&quot;"));
+    }
+
+    #[test]
+    fn audio_properties() {
+        let mut e = entry("a.flac", None);
+        e.properties = Some(flac());
+        assert!(e.to_csv().ends_with(",Title; Art,FLAC,61.500,44100,16,900,true"));
+        let loaded = ReportEntry::from_json(&e.to_json()).unwrap();
+        let properties = loaded.properties.unwrap();
+        assert_eq!(properties.codec, "FLAC");
+        assert_eq!(properties.duration, Some(61.5));
+        assert_eq!(properties.sample_rate, Some(44100));
+        assert_eq!(properties.bit_depth, Some(16));
+        assert_eq!(properties.bitrate, Some(900));
+        assert!(properties.lossless);
+        let mut report = Report::new("/music");
+        report.add("a.flac", e);
+        assert!(report.to_html(&SkipCounts::default()).contains("<td>FLAC, 44100 Hz, 16 bit, 2 ch, 900 kbps, 1:02, lossless</td>"));
+    }
+}
@@ -0,0 +1,256 @@
+use std::fs::File;
+use std::io::Write;
+use std::time::{SystemTime, Duration};
+
+use crate::tagger::{self, TaggerConfig, MusicFileInfo};
+use crate::discogs::{Discogs, ReleaseMaster, Track};
+use crate::organize::Organizer;
+use crate::cue::CueCache;
+use crate::diff::{self, FileDiff};
+use crate::journal::Journal;
+use crate::state::{self, RunState, StateEntry};
+use crate::report::{ErrorCategory, Report, ReportEntry};
+use crate::skip::SkipCounts;
+use crate::integrity::IntegrityError;
+use crate::ui;
+
+//Create Discogs instance with validated token
+pub fn create_discogs(token: Option<&str>) -> Result<Discogs, String> {
+    let mut discogs = Discogs::new().map_err(|_| String::from("Failed initializing Discogs!"))?;
+    //Authorize if token available
+    match token {
+        Some(v) => {
+            if v.len() > 6 {
+                discogs.authorize_token(String::from(v));
+            }
+        },
+        None => {
+            return Err(String::from("Enter token!"));
+        }
+    }
+    //Set rate limiting
+    discogs.rate_limit(true);
+    //Check token
+    let token_state = discogs.validate_token();
+    if token_state.is_none() {
+        return Err(String::from("Invalid token!"));
+    }
+    //Save token
+    if let Ok(mut f) = File::create(".discogstoken") {
+        f.write_all(discogs.token.as_ref().unwrap().as_bytes()).ok();
+    }
+    Ok(discogs)
+}
+
+//File to process, error = couldn't be loaded
+type Input = Result<MusicFileInfo, (String, TagError)>;
+
+fn invalid(path: &str, error: &str) -> Input {
+    Err((path.to_owned(), TagError::new(ErrorCategory::Invalid, &format!("Invalid track! {}", error))))
+}
+
+//Tag all files in path, callback(ok, fail, skipped, total, changes) after every file
+pub fn run(discogs: &mut Discogs, config: &TaggerConfig, path: &str, progress: impl FnMut(i32, i32, usize, usize, Option<&FileDiff>)) {
+    //Load files, skip ones done by interrupted run
+    let (mut files, invalid_files, mut skipped) = tagger::get_files(path, config);
+    let previous = match config.resume {
+        Some(true) => state::unfinished(path).unwrap_or_default(),
+        _ => vec![]
+    };
+    //Failed files are tried again
+    let previous = state::by_path(previous);
+    let count = files.len();
+    files.retain(|f| !previous.get(&f.path).map(|e| e.ok && e.unchanged()).unwrap_or(false));
+    skipped.resumed = count - files.len();
+    if skipped.total() > 0 {
+        println!("Skipped {} files: {}\n", skipped.total(), skipped);
+    }
+    let files = files.into_iter().map(Ok).chain(invalid_files.iter().map(|(p, e)| invalid(p, e))).collect();
+    process(discogs, config, path, files, skipped, Report::new(path), progress);
+}
+
+//Tag again failed files from JSON report or state file, with categories from config (empty = all)
+pub fn retry(discogs: &mut Discogs, config: &TaggerConfig, source: &str, progress: impl FnMut(i32, i32, usize, usize, Option<&FileDiff>)) -> Result<(), String> {
+    let report = Report::load(source).map_err(|e| format!("Failed reading {}! {}", source, e))?;
+    //Skip policies don't apply, files were requested explicitly
+    let mut cues = CueCache::default();
+    let files: Vec<Input> = report.failed(&config.retry_categories).iter().map(|path| match tagger::load_file_info(path, config, &mut cues) {
+        Ok(info) => Ok(info),
+        Err(e) => invalid(path, &e.to_string())
+    }).collect();
+    if files.is_empty() {
+        return Err(String::from("No failed files to retry!"));
+    }
+    println!("Retrying {} failed files from: {}\n", files.len(), source);
+    let folder = report.folder.to_owned();
+    process(discogs, config, &folder, files, SkipCounts::default(), report, progress);
+    Ok(())
+}
+
+//Tag files in folder, results are added to report
+fn process(discogs: &mut Discogs, config: &TaggerConfig, path: &str, files: Vec<Input>, skipped: SkipCounts, mut report: Report, mut progress: impl FnMut(i32, i32, usize, usize, Option<&FileDiff>)) {
+    let mut ok = 0;
+    let mut fail = 0;
+    let ts_start = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_else(|_| Duration::from_millis(0)).as_secs();
+    let mut run_state = match config.dry_run {
+        false => match RunState::create(path, config.resume == Some(true)) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                ui::print_warning(&format!("Failed creating state file, run can't be resumed! {}", e));
+                None
+            }
+        },
+        true => None
+    };
+    let total = files.len();
+    progress(0, 0, skipped.total(), total, None);
+    let mut diffs = vec![];
+    let mut journal = match config.journal && !config.dry_run {
+        true => match Journal::create(&config.journal_folder(path)) {
+            Ok(j) => Some(j),
+            Err(e) => {
+                ui::print_warning(&format!("Failed creating undo journal, changes can't be undone! {}", e));
+                None
+            }
+        },
+        false => None
+    };
+    let mut organizer = Organizer::new(config, path);
+    for input in files {
+        let (file_path, result, properties) = match input {
+            Ok(file) => {
+                let result = tag_file(discogs, config, &file, journal.as_mut());
+                (file.path, result, Some(file.properties))
+            },
+            Err((path, e)) => (path, Err(e), None)
+        };
+        match result {
+            Ok(_) => ok += 1,
+            Err(_) => fail += 1
+        }
+        let new_path = organize(organizer.as_mut(), journal.as_mut(), config, &file_path, &result);
+        if let Some(run_state) = run_state.as_mut() {
+            run_state.add(&StateEntry::new(&new_path, &result)).ok();
+        }
+        report.add(&file_path, ReportEntry::new(&new_path, &result, properties.as_ref()));
+        let result = result.map(|tagged| FileDiff {
+            moved_to: Some(new_path.to_owned()).filter(|p| *p != file_path),
+            ..tagged.diff
+        });
+        ui::print_console(&file_path, result.as_ref().map(|_| ()).map_err(|e| e.message.to_owned()), ok, fail, total as i32);
+        if let (Ok(diff), true) = (&result, config.dry_run) {
+            ui::print_diff(diff);
+        }
+        progress(ok, fail, skipped.total(), total, result.as_ref().ok());
+        if let Ok(diff) = result {
+            diffs.push(diff);
+        }
+    }
+    //Done
+    if let Some(mut run_state) = run_state {
+        run_state.finish().ok();
+    }
+    ui::print_console_done(ok, fail, &skipped, total as i32, ts_start);
+    if let Some(format) = config.report {
+        match report.save(format, config.report_output.as_deref(), &skipped) {
+            Ok(path) => println!("Report saved to: {}", path),
+            Err(e) => ui::print_warning(&format!("Failed saving report! {}", e))
+        }
+    }
+    if config.dry_run {
+        match diff::save_json(&diffs, &config.dry_run_output) {
+            Ok(_) => println!("Dry run, no files were changed. Changes saved to: {}", config.dry_run_output),
+            Err(e) => ui::print_warning(&format!("Failed saving dry run changes! {}", e))
+        }
+    }
+    if let Some(journal) = journal {
+        println!("Original tags saved to: {} (undo with --undo {})", journal.path, journal.path);
+    }
+}
+
+//Successfully tagged file
+pub struct Tagged {
+    pub diff: FileDiff,
+    pub release: ReleaseMaster,
+    //None = single file album
+    pub track: Option<Track>,
+    pub score: u8
+}
+
+//Failed file, message for console
+#[derive(Debug, Clone)]
+pub struct TagError {
+    pub category: ErrorCategory,
+    pub message: String
+}
+
+impl TagError {
+    pub fn new(category: ErrorCategory, message: &str) -> TagError {
+        TagError { category, message: message.to_owned() }
+    }
+}
+
+//Match and write single file
+pub fn tag_file(discogs: &mut Discogs, config: &TaggerConfig, file: &MusicFileInfo, journal: Option<&mut Journal>) -> Result<Tagged, TagError> {
+    let no_match = || TagError::new(ErrorCategory::NoMatch, "No match!");
+    let matching = |e: Box<dyn std::error::Error>| TagError::new(matching_category(e.as_ref()), &format!("Error matching! {}", e));
+    let writing = |e: Box<dyn std::error::Error>| TagError::new(writing_category(e.as_ref()), &format!("Failed writing tag to file! {}", e));
+
+    //Whole album with cue sheet
+    if file.cue.is_some() {
+        let (release, score) = tagger::match_album(discogs, file, config).map_err(matching)?.ok_or_else(no_match)?;
+        return tagger::write_album(discogs, config, file, &release, journal)
+            .map(|diff| Tagged { diff, release, track: None, score })
+            .map_err(writing);
+    }
+
+    let (track, release, score) = tagger::match_track(discogs, file, config).map_err(matching)?.ok_or_else(no_match)?;
+    tagger::write_tag(discogs, config, file, &release, &track, journal)
+        .map(|diff| Tagged { diff, release, track: Some(track), score })
+        .map_err(writing)
+}
+
+//Connection errors can be retried, invalid responses need different matching
+fn matching_category(e: &(dyn std::error::Error + 'static)) -> ErrorCategory {
+    match e.downcast_ref::<reqwest::Error>() {
+        Some(e) if e.is_decode() => ErrorCategory::Matching,
+        Some(_) => ErrorCategory::Network,
+        None if e.is::<std::io::Error>() => ErrorCategory::Network,
+        None => ErrorCategory::Matching
+    }
+}
+
+fn writing_category(e: &(dyn std::error::Error + 'static)) -> ErrorCategory {
+    match e.is::<IntegrityError>() {
+        true => ErrorCategory::Integrity,
+        false => ErrorCategory::Write
+    }
+}
+
+//Move tagged file by template or unmatched to quarantine, returns new path
+pub fn organize(organizer: Option<&mut Organizer>, journal: Option<&mut Journal>, config: &TaggerConfig, path: &str, result: &Result<Tagged, TagError>) -> String {
+    let organizer = match organizer {
+        Some(o) => o,
+        None => return path.to_owned()
+    };
+    let moved = match result {
+        Ok(Tagged { release, track: Some(track), .. }) => organizer.organize(path, release, track, config),
+        //Cue sheet references file by name
+        Ok(_) => Ok(None),
+        Err(e) if e.category == ErrorCategory::NoMatch => organizer.quarantine(path),
+        Err(_) => Ok(None)
+    };
+    match moved {
+        Ok(Some(new_path)) => {
+            if let (Some(journal), false) = (journal, config.dry_run) {
+                journal.record_move(path, &new_path).ok();
+            }
+            new_path
+        },
+        Ok(None) => path.to_owned(),
+        Err(e) => {
+            ui::print_warning(&format!("Failed moving file {}: {}", path, e));
+            path.to_owned()
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::backend::{AudioTag, Field};
+use crate::tagger::TaggerConfig;
+use crate::state::StateEntry;
+
+//Already tagged files which shouldn't be processed again
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SkipPolicy {
+    //Has release ID written by this tagger
+    Provenance,
+    //All required fields are filled
+    Fields,
+    //Tagged in previous run and not modified since
+    Unchanged
+}
+
+impl SkipPolicy {
+    //Comma separated list
+    pub fn parse_list(value: &str) -> Result<Vec<SkipPolicy>, String> {
+        value.split(',').map(|p| p.trim().to_lowercase()).filter(|p| !p.is_empty() && p != "none").map(|p| match p.as_str() {
+            "provenance" => Ok(SkipPolicy::Provenance),
+            "fields" => Ok(SkipPolicy::Fields),
+            "unchanged" => Ok(SkipPolicy::Unchanged),
+            _ => Err(format!("Invalid skip policy: {}", p))
+        }).collect()
+    }
+}
+
+//Discogs release ID, written to every tagged file
+pub fn provenance_field() -> Field {
+    Field::Custom { id3: Some(String::from("TXXX:DISCOGS_RELEASE_ID")), vorbis: Some(String::from("DISCOGS_RELEASE_ID")) }
+}
+
+//Comma separated field names, unknown ones are custom tags
+pub fn parse_fields(value: &str) -> Vec<Field> {
+    value.split(',').map(|f| f.trim()).filter(|f| !f.is_empty()).map(|f| match f.to_lowercase().as_str() {
+        "title" => Field::Title,
+        "artist" => Field::Artist,
+        "album" => Field::Album,
+        "label" => Field::Label,
+        "date" => Field::Date,
+        "track" => Field::Track,
+        "genre" => Field::Genre,
+        "style" => Field::Style,
+        _ => Field::Custom { id3: Some(format!("TXXX:{}", f.to_uppercase())), vorbis: Some(f.to_uppercase()) }
+    }).collect()
+}
+
+//Policy by which file should be skipped based on its tag
+pub fn check_tag(tag: &dyn AudioTag, config: &TaggerConfig) -> Option<SkipPolicy> {
+    let filled = |field: &Field| tag.get(field).map(|v| v.iter().any(|v| !v.trim().is_empty())).unwrap_or(false);
+    config.skip.iter().copied().find(|policy| match policy {
+        SkipPolicy::Provenance => filled(&provenance_field()),
+        SkipPolicy::Fields => !config.required_fields.is_empty() && config.required_fields.iter().all(filled),
+        SkipPolicy::Unchanged => false
+    })
+}
+
+//Tagged successfully according to state file and not modified since
+pub fn check_state(path: &str, entries: &HashMap<String, StateEntry>) -> bool {
+    entries.get(path).map(|e| e.ok && e.unchanged()).unwrap_or(false)
+}
+
+//Skipped files are reported separately from ok/fail
+#[derive(Debug, Clone, Default)]
+pub struct SkipCounts {
+    pub lossy: usize,
+    pub provenance: usize,
+    pub fields: usize,
+    pub unchanged: usize,
+    //Done by interrupted run
+    pub resumed: usize
+}
+
+impl SkipCounts {
+    pub fn add(&mut self, policy: SkipPolicy) {
+        match policy {
+            SkipPolicy::Provenance => self.provenance += 1,
+            SkipPolicy::Fields => self.fields += 1,
+            SkipPolicy::Unchanged => self.unchanged += 1
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.lossy + self.provenance + self.fields + self.unchanged + self.resumed
+    }
+}
+
+impl fmt::Display for SkipCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let counts = [
+            (self.provenance, "already tagged"),
+            (self.fields, "fields filled"),
+            (self.unchanged, "unchanged since last run"),
+            (self.resumed, "done by interrupted run"),
+            (self.lossy, "lossy")
+        ];
+        let parts: Vec<String> = counts.iter().filter(|(c, _)| *c > 0).map(|(c, n)| format!("{} {}", c, n)).collect();
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn parse() {
+        assert_eq!(SkipPolicy::parse_list("Provenance, unchanged"), Ok(vec![SkipPolicy::Provenance, SkipPolicy::Unchanged]));
+        assert_eq!(SkipPolicy::parse_list("none"), Ok(vec![]));
+        assert!(SkipPolicy::parse_list("fields,tagged").is_err());
+        let fields = parse_fields("title, isrc,");
+        assert_eq!(fields.len(), 2);
+        assert!(matches!(&fields[1], Field::Custom { vorbis: Some(v), .. } if v == "ISRC"));
+    }
+
+    #[test]
+    fn state_requires_ok_and_unchanged() {
+        let dir = TempDir::new("skip");
+        let path = dir.write("song.mp3", b"");
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
+        let entry = |ok: bool, mtime: u64| StateEntry {
+            path: path.to_owned(), mtime, ok, error: None, category: None, release_id: None, release_url: None
+        };
+        let mut entries = HashMap::new();
+        entries.insert(path.to_owned(), entry(true, mtime));
+        let ok = check_state(&path, &entries);
+        entries.insert(path.to_owned(), entry(false, mtime));
+        let failed = check_state(&path, &entries);
+        entries.insert(path.to_owned(), entry(true, mtime - 1));
+        let modified = check_state(&path, &entries);
+        assert!(ok);
+        assert!(!failed);
+        assert!(!modified);
+        assert!(!check_state("missing.mp3", &entries));
+    }
+
+    #[test]
+    fn counts() {
+        let mut counts = SkipCounts::default();
+        counts.add(SkipPolicy::Provenance);
+        counts.add(SkipPolicy::Provenance);
+        counts.lossy = 1;
+        assert_eq!(counts.total(), 3);
+        assert_eq!(counts.to_string(), "2 already tagged, 1 lossy");
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use serde_json::{json, Value};
+
+use crate::runner::{Tagged, TagError};
+use crate::report::ErrorCategory;
+
+const STATE_FILE: &str = ".discogstagger-state.jsonl";
+
+//Result of single processed file
+#[derive(Debug, Clone)]
+pub struct StateEntry {
+    pub path: String,
+    //After processing, ms
+    pub mtime: u64,
+    pub ok: bool,
+    pub error: Option<String>,
+    //Not in state files from older versions
+    pub category: Option<ErrorCategory>,
+    pub release_id: Option<i64>,
+    pub release_url: Option<String>
+}
+
+impl StateEntry {
+    //Path = after organizing
+    pub fn new(path: &str, result: &Result<Tagged, TagError>) -> StateEntry {
+        let mut entry = StateEntry {
+            path: path.to_owned(), mtime: mtime(path).unwrap_or(0), ok: result.is_ok(),
+            error: None, category: None, release_id: None, release_url: None
+        };
+        match result {
+            Ok(tagged) => {
+                entry.release_id = Some(tagged.release.id);
+                entry.release_url = Some(tagged.release.full_url());
+            },
+            Err(e) => {
+                entry.error = Some(e.message.to_owned());
+                entry.category = Some(e.category);
+            }
+        }
+        entry
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "path": self.path,
+            "mtime": self.mtime,
+            "result": if self.ok { "ok" } else { "fail" },
+            "error": self.error,
+            "category": self.category.map(|c| c.name()),
+            "release": self.release_id,
+            "url": self.release_url
+        })
+    }
+
+    fn from_json(json: &Value) -> Option<StateEntry> {
+        Some(StateEntry {
+            path: json["path"].as_str()?.to_owned(),
+            mtime: json["mtime"].as_u64().unwrap_or(0),
+            ok: json["result"].as_str()? == "ok",
+            error: json["error"].as_str().map(String::from),
+            category: json["category"].as_str().and_then(ErrorCategory::parse),
+            release_id: json["release"].as_i64(),
+            release_url: json["url"].as_str().map(String::from)
+        })
+    }
+
+    //File wasn't touched since it was processed
+    pub fn unchanged(&self) -> bool {
+        mtime(&self.path).map(|m| m == self.mtime).unwrap_or(false)
+    }
+}
+
+//Progress of run in folder, JSON per line, written as files are processed
+pub struct RunState {
+    file: File
+}
+
+impl RunState {
+    //Resume = continue previous run, otherwise only latest result of every file is kept from previous runs
+    pub fn create(folder: &str, resume: bool) -> Result<RunState, Box<dyn std::error::Error>> {
+        let path = state_path(folder);
+        let previous = load(&path).map(|s| s.entries).unwrap_or_default();
+        let mut state = match resume {
+            true => RunState { file: OpenOptions::new().create(true).append(true).open(&path)? },
+            false => {
+                let mut state = RunState { file: File::create(&path)? };
+                for entry in &previous {
+                    state.add(entry)?;
+                }
+                state
+            }
+        };
+        state.write(json!({ "action": "start", "resume": resume }))?;
+        Ok(state)
+    }
+
+    pub fn add(&mut self, entry: &StateEntry) -> Result<(), Box<dyn std::error::Error>> {
+        self.write(entry.to_json())
+    }
+
+    //Whole folder was processed
+    pub fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.write(json!({ "action": "done" }))
+    }
+
+    fn write(&mut self, value: Value) -> Result<(), Box<dyn std::error::Error>> {
+        writeln!(self.file, "{}", value)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+pub fn state_path(folder: &str) -> PathBuf {
+    Path::new(folder).join(STATE_FILE)
+}
+
+pub struct StateFile {
+    //Latest result of every file from all runs
+    pub entries: Vec<StateEntry>,
+    //Files processed by last run, including resumed parts
+    pub last_run: Vec<StateEntry>,
+    pub finished: bool
+}
+
+pub fn load(path: &Path) -> Result<StateFile, Box<dyn std::error::Error>> {
+    let (mut entries, mut last_run) = (Latest::default(), Latest::default());
+    let mut finished = false;
+    //Last line can be incomplete after crash
+    for line in std::fs::read_to_string(path)?.lines().filter_map(|l| serde_json::from_str::<Value>(l).ok()) {
+        match line["action"].as_str() {
+            Some("start") => {
+                if !line["resume"].as_bool().unwrap_or(false) {
+                    last_run = Latest::default();
+                }
+                finished = false;
+            },
+            Some("done") => finished = true,
+            _ => if let Some(entry) = StateEntry::from_json(&line) {
+                entries.insert(entry.clone());
+                last_run.insert(entry);
+            }
+        }
+    }
+    Ok(StateFile { entries: entries.into_vec(), last_run: last_run.into_vec(), finished })
+}
+
+//Latest entry of every file, in order of processing
+#[derive(Default)]
+struct Latest {
+    index: HashMap<String, usize>,
+    entries: Vec<Option<StateEntry>>
+}
+
+impl Latest {
+    fn insert(&mut self, entry: StateEntry) {
+        if let Some(i) = self.index.insert(entry.path.to_owned(), self.entries.len()) {
+            self.entries[i] = None;
+        }
+        self.entries.push(Some(entry));
+    }
+
+    fn into_vec(self) -> Vec<StateEntry> {
+        self.entries.into_iter().flatten().collect()
+    }
+}
+
+//For lookups of many files
+pub fn by_path(entries: Vec<StateEntry>) -> HashMap<String, StateEntry> {
+    entries.into_iter().map(|e| (e.path.to_owned(), e)).collect()
+}
+
+//Files processed by interrupted run in folder
+pub fn unfinished(folder: &str) -> Option<Vec<StateEntry>> {
+    match load(&state_path(folder)) {
+        Ok(state) if !state.finished && !state.last_run.is_empty() => Some(state.last_run),
+        _ => None
+    }
+}
+
+fn mtime(path: &str) -> Option<u64> {
+    let modified = std::fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(SystemTime::UNIX_EPOCH).ok()?.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn entry(path: &str, ok: bool) -> StateEntry {
+        StateEntry {
+            path: path.to_owned(), mtime: 1, ok,
+            error: Some(String::from("No match!")).filter(|_| !ok),
+            category: Some(ErrorCategory::NoMatch).filter(|_| !ok),
+            release_id: Some(1).filter(|_| ok),
+            release_url: None
+        }
+    }
+
+
+    #[test]
+    fn truncated_last_line() {
+        let dir = TempDir::new("state");
+        let folder = dir.path().to_str().unwrap();
+        let mut state = RunState::create(folder, false).unwrap();
+        state.add(&entry("a.mp3", false)).unwrap();
+        state.add(&entry("b.mp3", true)).unwrap();
+        state.add(&entry("a.mp3", true)).unwrap();
+        drop(state);
+        //Crash while writing
+        let mut file = OpenOptions::new().append(true).open(state_path(folder)).unwrap();
+        write!(file, "{{\"path\":\"c.mp3\",\"mti").unwrap();
+
+        let loaded = load(&state_path(folder)).unwrap();
+        assert!(!loaded.finished);
+        let paths: Vec<&str> = loaded.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["b.mp3", "a.mp3"]);
+        assert!(loaded.entries.iter().all(|e| e.ok));
+        assert_eq!(unfinished(folder).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn resume_keeps_last_run() {
+        let dir = TempDir::new("state");
+        let folder = dir.path().to_str().unwrap();
+        let mut state = RunState::create(folder, false).unwrap();
+        state.add(&entry("a.mp3", true)).unwrap();
+        drop(state);
+        let mut state = RunState::create(folder, true).unwrap();
+        state.add(&entry("b.mp3", false)).unwrap();
+        state.finish().unwrap();
+        drop(state);
+        let resumed = load(&state_path(folder)).unwrap();
+        //New run starts with latest results of previous ones
+        let mut state = RunState::create(folder, false).unwrap();
+        state.add(&entry("c.mp3", true)).unwrap();
+        drop(state);
+        let new_run = load(&state_path(folder)).unwrap();
+        assert!(resumed.finished);
+        assert_eq!(resumed.last_run.len(), 2);
+        assert_eq!(resumed.last_run[1].category, Some(ErrorCategory::NoMatch));
+        assert_eq!(new_run.entries.len(), 3);
+        assert_eq!(new_run.last_run.len(), 1);
+        assert!(by_path(new_run.entries).contains_key("b.mp3"));
+    }
+}
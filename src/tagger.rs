@@ -1,27 +1,35 @@
-extern crate metaflac;
 extern crate strsim;
 extern crate regex;
 
+use std::path::Path;
 use walkdir::WalkDir;
 use regex::Regex;
 use strsim::normalized_levenshtein;
-use id3::{Tag, Version, Timestamp};
-use chrono::{NaiveDate, Datelike};
-use metaflac::block::PictureType as FLACPictureType;
-use id3::frame::PictureType as ID3PictureType;
-use id3::frame::Picture;
-use std::fs::File;
-use std::io::prelude::*;
-use std::io::SeekFrom;
+use serde_json::Value;
 
 use crate::discogs::{Discogs, Track, ReleaseMaster, ReleaseType};
+use crate::mapping::{self, TagKeys, TagMapping, TemplateData};
+use crate::art::{self, Art, ArtConfig};
+use crate::backend::{self, AudioTag, Field, GenreMode};
+use crate::ape::ApeMode;
+use crate::properties::AudioProperties;
+use crate::cue::{CueCache, CueSheet};
+use crate::diff::{DiffTag, FileDiff};
+use crate::journal::Journal;
+use crate::skip::{self, SkipPolicy, SkipCounts};
+use crate::state;
+use crate::report::{ErrorCategory, ReportFormat};
 use crate::ui;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MusicFileType {
     AIFF,
     MP3,
-    FLAC
+    FLAC,
+    MP4,
+    OGG,
+    WAV,
+    APE
 }
 
 #[derive(Debug, Clone)]
@@ -29,42 +37,272 @@ pub struct MusicFileInfo {
     pub path: String,
     pub title: String,
     pub artists: Vec<String>,
-    pub tag: MusicFileType 
+    pub tag: MusicFileType,
+    pub properties: AudioProperties,
+    //Single file album
+    pub cue: Option<CueSheet>,
+    //Skip policy matching the tag
+    pub skip: Option<SkipPolicy>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WritePolicy {
+    Never,
+    FillEmpty,
+    Overwrite,
+    //Multi-value fields only, single value fields behave as FillEmpty
+    Merge
+}
+
+impl WritePolicy {
+    //Parse from config value, bool = old config with global overwrite flag
+    pub fn from_json(value: &Value, overwrite: bool) -> Result<WritePolicy, String> {
+        match value {
+            Value::Null => Ok(WritePolicy::Never),
+            Value::Bool(false) => Ok(WritePolicy::Never),
+            Value::Bool(true) => match overwrite {
+                true => Ok(WritePolicy::Overwrite),
+                false => Ok(WritePolicy::FillEmpty)
+            },
+            Value::String(s) => WritePolicy::parse(s),
+            _ => Err(format!("Invalid write policy: {}", value))
+        }
+    }
+
+    pub fn parse(value: &str) -> Result<WritePolicy, String> {
+        match value.to_lowercase().as_str() {
+            "never" => Ok(WritePolicy::Never),
+            "fill" => Ok(WritePolicy::FillEmpty),
+            "overwrite" => Ok(WritePolicy::Overwrite),
+            "merge" => Ok(WritePolicy::Merge),
+            _ => Err(format!("Invalid write policy: {}", value))
+        }
+    }
+
+    //Single value field
+    pub fn should_write(&self, has_value: bool) -> bool {
+        match self {
+            WritePolicy::Never => false,
+            WritePolicy::FillEmpty | WritePolicy::Merge => !has_value,
+            WritePolicy::Overwrite => true
+        }
+    }
+
+    //Multi value field, returns values to write or None to keep existing
+    pub fn apply(&self, existing: Vec<String>, new: Vec<String>) -> Option<Vec<String>> {
+        match self {
+            WritePolicy::Never => None,
+            WritePolicy::FillEmpty => match existing.is_empty() {
+                true => Some(new),
+                false => None
+            },
+            WritePolicy::Overwrite => Some(new),
+            WritePolicy::Merge => {
+                let mut out: Vec<String> = existing.iter().map(|v| v.trim().to_owned()).filter(|v| !v.is_empty()).collect();
+                for value in new {
+                    if !out.iter().any(|v| v.to_lowercase() == value.trim().to_lowercase()) {
+                        out.push(value);
+                    }
+                }
+                Some(out)
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct TaggerConfig {
     //Tags
-    pub title: bool,
-    pub artist: bool,
-    pub album: bool,
-    pub label: bool,
-    pub date: bool,
-    pub track: bool,
-    pub art: bool,
-
-    //Genres
-    // 0 No Style/Genre
-    // 1 Only Style
-    // 2 Only Genre
-    // 3 Merge Genre + Style
-    pub id3_genre: i8,
-
-    // 0 No Style/Genre
-    // 1 Both
-    // 2 Only Style (in Genre tag)
-    // 3 Only Genre
-    // 4 Merge Genre + Style
-    pub flac_genre: i8,
+    pub title: WritePolicy,
+    pub artist: WritePolicy,
+    pub album: WritePolicy,
+    pub label: WritePolicy,
+    pub date: WritePolicy,
+    pub track: WritePolicy,
+    pub genre: WritePolicy,
+    pub art: WritePolicy,
+
+    //Genres, for ID3 and for formats with multiple values
+    pub id3_genre: GenreMode,
+    pub flac_genre: GenreMode,
+
+    //Custom frame/key names and template tags
+    pub keys: TagKeys,
+    pub mapping: Vec<TagMapping>,
+
+    //Album art processing
+    pub art_options: ArtConfig,
+
+    //Labels
+    pub primary_label_only: bool,
+    //None = multiple values
+    pub label_separator: Option<String>,
+    pub drop_placeholder_labels: bool,
+    pub strip_label_suffix: bool,
+
+    //ID3 multiple values, null separated only in ID3v2.4
+    pub id3_multi_value: bool,
+    pub id3_separator: String,
+    //Mirror ID3 to RIFF INFO in WAV
+    pub wav_info: bool,
+    //APEv2 at end of MP3
+    pub ape_mp3: ApeMode,
+    //Convert ID3 in FLAC to Vorbis comments instead of skipping the file
+    pub repair_flac_id3: bool,
+    //Mirror ID3v2 to ID3v1 in MP3
+    pub id3v1: bool,
+    //Convert existing frames to the written ID3 version
+    pub id3_migrate: bool,
 
     //Other
     pub artist_separator: String,
     pub fuzziness: u8,
-    pub overwrite: bool,
+    //Max difference from Discogs track duration in seconds, 0 = ignore duration
+    pub duration_tolerance: u32,
+    //Skip lossy formats
+    pub lossless_only: bool,
+    //Match single file albums using cue sheet, optionally embed it to FLAC
+    pub cue: bool,
+    pub cue_embed: bool,
+    //Only show changes, diffs are saved as JSON
+    pub dry_run: bool,
+    pub dry_run_output: String,
+    //Original values of every written file, for undo
+    pub journal: bool,
+    //None = in tagged folder
+    pub journal_dir: Option<String>,
+    //Skip files done by interrupted run, None = ask
+    pub resume: Option<bool>,
+    //Incremental runs, provenance = write release ID to every file
+    pub skip: Vec<SkipPolicy>,
+    pub required_fields: Vec<Field>,
+    pub provenance: WritePolicy,
+    //Watch mode, seconds between scans and without changes before tagging
+    pub watch_interval: u64,
+    pub watch_settle: u64,
+    pub watch_log: String,
+    //Rename / move after tagging, None = keep
+    pub organize: Option<String>,
+    pub organize_root: Option<String>,
+    pub quarantine: Option<String>,
+    //Keep modification and access time of written files
+    pub preserve_times: bool,
+    //Compare audio data before and after writing
+    pub verify_audio: bool,
+    //Per file results written after run, None = disabled
+    pub report: Option<ReportFormat>,
+    //None = timestamped file in tagged folder
+    pub report_output: Option<String>,
+    //Failed files to tag again in retry mode, empty = all
+    pub retry_categories: Vec<ErrorCategory>,
     pub id3v23: bool
 }
 
-pub fn match_track(discogs: &mut Discogs, info: &MusicFileInfo, fuzziness: u8) -> Result<Option<(Track, ReleaseMaster)>, Box<dyn std::error::Error>> {
+impl TaggerConfig {
+    //Load from UI / config file JSON, missing values = defaults
+    pub fn from_json(json: &Value) -> Result<TaggerConfig, String> {
+        //Old configs had single overwrite flag
+        let overwrite = json["overwrite"].as_bool().unwrap_or(false);
+        let genre = match json.get("genre") {
+            Some(v) => WritePolicy::from_json(v, overwrite)?,
+            None => WritePolicy::from_json(&Value::Bool(true), overwrite)?
+        };
+        //Preset, user mappings replace preset ones with same key
+        let (mut keys, mut mappings) = mapping::preset(json["preset"].as_str().unwrap_or(""))?;
+        keys.load_json(&json["keys"]);
+        let user_mappings = mapping::parse_mappings(&json["mapping"])?;
+        mappings.retain(|m| !user_mappings.iter().any(|u| u.vorbis.is_some() && u.vorbis == m.vorbis));
+        mappings.extend(user_mappings);
+        //Release ID is only needed for skipping by default
+        let skip = SkipPolicy::parse_list(json["skip"].as_str().unwrap_or(""))?;
+        let provenance = match json.get("provenance") {
+            Some(v) => WritePolicy::from_json(v, overwrite)?,
+            None => WritePolicy::from_json(&Value::Bool(skip.contains(&SkipPolicy::Provenance)), overwrite)?
+        };
+
+        Ok(TaggerConfig {
+            title: WritePolicy::from_json(&json["title"], overwrite)?,
+            artist: WritePolicy::from_json(&json["artist"], overwrite)?,
+            album: WritePolicy::from_json(&json["album"], overwrite)?,
+            label: WritePolicy::from_json(&json["label"], overwrite)?,
+            date: WritePolicy::from_json(&json["date"], overwrite)?,
+            track: WritePolicy::from_json(&json["track"], overwrite)?,
+            art: WritePolicy::from_json(&json["art"], overwrite)?,
+            genre,
+            id3_genre: GenreMode::from_id3(json_range(&json["id3Genre"], "ID3 genre mode", 0, 0, 4)?),
+            flac_genre: GenreMode::from_flac(json_range(&json["flacGenre"], "FLAC genre mode", 0, 0, 4)?),
+            keys,
+            mapping: mappings,
+            art_options: ArtConfig::from_json(json)?,
+            primary_label_only: json["labels"].as_str().unwrap_or("primary") == "primary",
+            label_separator: json["labelSeparator"].as_str().filter(|s| !s.is_empty()).map(String::from),
+            drop_placeholder_labels: json["dropPlaceholderLabels"].as_bool().unwrap_or(false),
+            strip_label_suffix: json["stripLabelSuffix"].as_bool().unwrap_or(true),
+            id3_multi_value: json["id3MultiValue"].as_bool().unwrap_or(false),
+            id3_separator: json["id3Separator"].as_str().filter(|s| !s.is_empty()).unwrap_or(", ").to_owned(),
+            wav_info: json["wavInfo"].as_bool().unwrap_or(false),
+            ape_mp3: ApeMode::parse(json["apeMp3"].as_str().unwrap_or(""))?,
+            repair_flac_id3: json["repairFlacId3"].as_bool().unwrap_or(false),
+            id3v1: json["id3v1"].as_bool().unwrap_or(false),
+            id3_migrate: json["id3Migrate"].as_bool().unwrap_or(false),
+            artist_separator: json["separator"].as_str().unwrap_or("; ").to_owned(),
+            fuzziness: json_range(&json["fuzziness"], "fuzziness", 80, 0, 100)? as u8,
+            duration_tolerance: json_range(&json["durationTolerance"], "duration tolerance", 0, 0, u32::MAX as i64)? as u32,
+            lossless_only: json["losslessOnly"].as_bool().unwrap_or(false),
+            cue: json["cue"].as_bool().unwrap_or(true),
+            cue_embed: json["cueEmbed"].as_bool().unwrap_or(false),
+            dry_run: json["dryRun"].as_bool().unwrap_or(false),
+            dry_run_output: json["dryRunOutput"].as_str().filter(|s| !s.is_empty()).unwrap_or("dry-run.json").to_owned(),
+            journal: json["journal"].as_bool().unwrap_or(true),
+            journal_dir: json["journalDir"].as_str().filter(|s| !s.is_empty()).map(String::from),
+            resume: json["resume"].as_bool(),
+            skip,
+            required_fields: skip::parse_fields(json["requiredFields"].as_str().unwrap_or("title,artist,album,label,date,genre")),
+            provenance,
+            watch_interval: json_i64(&json["watchInterval"]).unwrap_or(5).max(1) as u64,
+            watch_settle: json_i64(&json["watchSettle"]).unwrap_or(10).max(0) as u64,
+            organize: json["organize"].as_str().filter(|s| !s.trim().is_empty()).map(String::from),
+            organize_root: json["organizeRoot"].as_str().filter(|s| !s.is_empty()).map(String::from),
+            quarantine: json["quarantine"].as_str().filter(|s| !s.is_empty()).map(String::from),
+            watch_log: json["watchLog"].as_str().filter(|s| !s.is_empty()).unwrap_or("watch.log").to_owned(),
+            preserve_times: json["preserveTimes"].as_bool().unwrap_or(false),
+            verify_audio: json["verifyAudio"].as_bool().unwrap_or(true),
+            report: ReportFormat::parse(json["report"].as_str().unwrap_or("json"))?,
+            report_output: json["reportOutput"].as_str().filter(|s| !s.is_empty()).map(String::from),
+            retry_categories: ErrorCategory::parse_list(json["retryCategories"].as_str().unwrap_or(""))?,
+            id3v23: json["id3v23"].as_bool().unwrap_or(false)
+        })
+    }
+
+    //Undo journals of tagged folder
+    pub fn journal_folder(&self, folder: &str) -> String {
+        match self.journal_dir.as_ref() {
+            Some(dir) => dir.to_owned(),
+            None => Path::new(folder).join(".discogstagger-journal").to_string_lossy().to_string()
+        }
+    }
+}
+
+//Number can be string (UI input) or number (config file)
+pub fn json_i64(value: &Value) -> Option<i64> {
+    match value {
+        Value::String(s) => s.trim().parse().ok(),
+        _ => value.as_i64()
+    }
+}
+
+//Number within bounds, out of range is error instead of wrapping
+pub fn json_range(value: &Value, name: &str, default: i64, min: i64, max: i64) -> Result<i64, String> {
+    match json_i64(value).unwrap_or(default) {
+        v if v >= min && v <= max => Ok(v),
+        v => Err(format!("Invalid {}: {} (allowed {} - {})", name, v, min, max))
+    }
+}
+
+//Matched track, release and match score (0 - 100)
+type TrackMatch = (Track, ReleaseMaster, u8);
+
+pub fn match_track(discogs: &mut Discogs, info: &MusicFileInfo, config: &TaggerConfig) -> Result<Option<TrackMatch>, Box<dyn std::error::Error>> {
     //Search
     let mut results = discogs.search(Some("release,master"), Some(&format!("{} {}", clean_title(&info.title, false), &info.artists.first().unwrap())), None, None)?;
     //Fallback
@@ -88,28 +326,87 @@ pub fn match_track(discogs: &mut Discogs, info: &MusicFileInfo, fuzziness: u8) -
         if (&release).is_none() || release.as_ref().unwrap().tracks.is_none() {
             continue;
         }
-        //Match track
-        let mut fuzzy_tracks: Vec<(u8, Track)> = vec![];
-        for t in release.as_ref().unwrap().tracks.as_ref().unwrap() {
-            //Exact match = return
-            if clean_title(&t.title, true) == clean_title(&info.title, true) {
-                return Ok(Some((t.to_owned(), release.unwrap())));
+        //Match track, exact title = 100, tracks with different duration are ignored
+        let title = clean_title(&info.title, true);
+        let mut fuzzy_tracks: Vec<(u8, Track)> = release.as_ref().unwrap().tracks.as_ref().unwrap().iter()
+            .filter(|t| duration_matches(info, t, config.duration_tolerance))
+            .map(|t| {
+                let track_title = clean_title(&t.title, true);
+                match track_title == title {
+                    true => (100, t.clone()),
+                    false => ((normalized_levenshtein(&track_title, &title) * 100_f64) as u8, t.clone())
+                }
+            }).collect();
+        //Sort fuzzy results, first exact match stays first
+        fuzzy_tracks.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+        if let Some((score, track)) = fuzzy_tracks.first() {
+            if *score >= config.fuzziness {
+                return Ok(Some((track.clone(), release.unwrap(), *score)));
             }
-            //Fuzzy
-            fuzzy_tracks.push(((normalized_levenshtein(&clean_title(&t.title, true), &clean_title(&info.title, true)) * 100_f64) as u8, t.clone()));
         }
-        let mut fuzzy_tracks: Vec<(u8, Track)> = release.as_ref().unwrap().tracks.as_ref().unwrap().iter().map(|t| {
-            ((normalized_levenshtein(&clean_title(&t.title, true), &clean_title(&info.title, true)) * 100_f64) as u8, t.clone())
-        }).collect();
-        //Sort fuzzy results
-        fuzzy_tracks.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
-        if fuzzy_tracks.first().unwrap().0 >= fuzziness {
-            return Ok(Some((fuzzy_tracks.first().unwrap().1.clone(), release.unwrap())));
+    }
+    Ok(None)
+}
+
+//Match whole release by cue sheet tracks, returns release and match score
+pub fn match_album(discogs: &mut Discogs, info: &MusicFileInfo, config: &TaggerConfig) -> Result<Option<(ReleaseMaster, u8)>, Box<dyn std::error::Error>> {
+    let cue_tracks = match info.cue.as_ref().and_then(|c| c.file(&info.path)) {
+        Some(file) => file.tracks.clone(),
+        None => return Ok(None)
+    };
+    //Search
+    let mut results = discogs.search(Some("release,master"), Some(&format!("{} {}", clean_title(&info.title, false), &info.artists.first().unwrap())), None, None)?;
+    if results.as_ref().map(|r| r.releases.is_empty()).unwrap_or(true) {
+        results = discogs.search(Some("release,master"), None, Some(&info.title), Some(info.artists.first().unwrap()))?;
+    }
+    let mut r = match results {
+        Some(r) => r,
+        None => return Ok(None)
+    };
+    r.releases.truncate(3);
+    r.masters.truncate(2);
+    for release_data in [r.masters, r.releases].concat() {
+        let release = match release_data.rtype {
+            ReleaseType::Release => discogs.release(release_data.id)?,
+            ReleaseType::Master => discogs.master(release_data.id, release_data.label)?
+        };
+        let release = match release {
+            Some(r) => r,
+            None => continue
+        };
+        //Same tracklist length
+        match release.tracks.as_ref() {
+            Some(tracks) if tracks.len() == cue_tracks.len() => {},
+            _ => continue
+        };
+        //Album title, or track titles if cue has them
+        let score = |a: &str, b: &str| (normalized_levenshtein(&clean_title(a, true), &clean_title(b, true)) * 100_f64) as u8;
+        let titles: Vec<u8> = cue_tracks.iter().zip(release.tracks.as_ref().unwrap())
+            .filter_map(|(c, t)| c.title.as_ref().map(|c| score(c, &t.title)))
+            .collect();
+        let track_score = match titles.is_empty() {
+            true => 0,
+            false => (titles.iter().map(|s| *s as u32).sum::<u32>() / titles.len() as u32) as u8
+        };
+        let album_score = score(&release.title, &info.title);
+        if album_score >= config.fuzziness || track_score >= config.fuzziness {
+            return Ok(Some((release, album_score.max(track_score))));
         }
     }
     Ok(None)
 }
 
+//Compare file duration with Discogs duration (m:ss), missing durations always match
+fn duration_matches(info: &MusicFileInfo, track: &Track, tolerance: u32) -> bool {
+    let discogs_duration = track.duration.split(':')
+        .try_fold(0u32, |acc, p| p.trim().parse::<u32>().ok().map(|v| acc * 60 + v))
+        .filter(|d| *d > 0);
+    match (info.properties.duration, discogs_duration, tolerance) {
+        (Some(duration), Some(discogs_duration), t) if t > 0 => (duration - discogs_duration as f64).abs() <= t as f64,
+        _ => true
+    }
+}
+
 //Matching = if matching remove also symbols
 fn clean_title(title: &str, matching: bool) -> String {
     let lowercase = title.to_lowercase();
@@ -126,13 +423,21 @@ fn clean_title(title: &str, matching: bool) -> String {
     out.to_string()
 }
 
-pub fn get_files(path: &str) -> Vec<MusicFileInfo> {
-    //Supported extensions
-    let supported_extensions = vec![".mp3", ".flac", ".aif", ".aiff"];
+//Skipped files are counted separately
+//Returns loaded files, (path, error) of files which couldn't be loaded and skipped files
+pub fn get_files(path: &str, config: &TaggerConfig) -> (Vec<MusicFileInfo>, Vec<(String, String)>, SkipCounts) {
+    let mut skipped = SkipCounts::default();
+    let mut invalid = vec![];
+    //Previous runs for unchanged files
+    let state = match config.skip.contains(&SkipPolicy::Unchanged) {
+        true => state::by_path(state::load(&state::state_path(path)).map(|s| s.entries).unwrap_or_default()),
+        false => Default::default()
+    };
+    let mut cues = CueCache::default();
     //List of filenames of supported formats for path
-    WalkDir::new(path).into_iter().filter(
-        |e| e.is_ok() && 
-        supported_extensions.iter().any(|&i| e.as_ref().unwrap().path().to_str().unwrap().to_ascii_lowercase().ends_with(i))
+    let files = WalkDir::new(path).into_iter().filter(
+        |e| e.is_ok() && e.as_ref().unwrap().file_type().is_file() &&
+        backend::is_supported(e.as_ref().unwrap().path().to_str().unwrap())
     ).map(|e| e.unwrap().path().to_str().unwrap().to_owned())
     //Load info
     .filter_map(|f| {
@@ -140,290 +445,288 @@ pub fn get_files(path: &str) -> Vec<MusicFileInfo> {
         #[cfg(debug_assertions)]
         println!("Loading track: {}", f);
 
-        match load_file_info(&f) {
-            Ok(i) => Some(i),
-            Err(_) => {
-                ui::print_warning(&format!("Invalid track: {}", path));
+        match load_file_info(&f, config, &mut cues) {
+            //Filter
+            Ok(i) if config.lossless_only && !i.properties.lossless => {
+                println!("Skipping lossy file ({}): {}", i.properties, i.path);
+                skipped.lossy += 1;
+                None
+            },
+            Ok(i) => {
+                let policy = i.skip.or_else(|| match skip::check_state(&i.path, &state) {
+                    true => Some(SkipPolicy::Unchanged),
+                    false => None
+                });
+                match policy {
+                    Some(policy) => {
+                        #[cfg(debug_assertions)]
+                        println!("Skipping ({:?}): {}", policy, i.path);
+                        skipped.add(policy);
+                        None
+                    },
+                    None => Some(i)
+                }
+            },
+            Err(e) => {
+                ui::print_warning(&format!("Invalid track: {}", f));
+                invalid.push((f, e.to_string()));
                 None
             }
         }
-    }).collect()
+    }).collect();
+    (files, invalid, skipped)
 }
 
-//Wrapper to load by format
-pub fn load_file_info(path: &str) -> Result<MusicFileInfo, Box<dyn std::error::Error>> {
-    if path.to_ascii_lowercase().ends_with(".flac") {
-        return load_flac_info(path);
-    }
-    load_id3_info(path)   
-}
-
-//Load ID3 metadata from MP3
-fn load_id3_info(path: &str) -> Result<MusicFileInfo, Box<dyn std::error::Error>> {
-    let mut tag_type = MusicFileType::MP3;
-    let tag = if path.ends_with(".aif") || path.ends_with(".aiff") {
-        tag_type = MusicFileType::AIFF;
-        Tag::read_from_aiff(path)?
-    } else {
-        Tag::read_from_path(path)?
+//Load title and artists using format backend
+pub fn load_file_info(path: &str, config: &TaggerConfig, cues: &mut CueCache) -> Result<MusicFileInfo, Box<dyn std::error::Error>> {
+    let backend = backend::detect(path).ok_or("Unsupported format!")?;
+    let tag = backend.read(path, config)?;
+    //Album with cue sheet is matched by album title
+    let cue = match config.cue {
+        true => cues.find_for(path),
+        false => None
     };
-
-    Ok(MusicFileInfo {
-        path: path.to_owned(),
-        title: tag.title().ok_or("Missing title tag!")?.to_owned(),
-        artists: parse_artist_tag(tag.artist().ok_or("Missing artist tag!")?),
-        tag: tag_type
-    })
-}
-
-//Load FLAC meta
-fn load_flac_info(path: &str) -> Result<MusicFileInfo, Box<dyn std::error::Error>> {
-    //Load header
-    let mut file = File::open(path)?;
-    let mut header: [u8; 4] = [0; 4];
-    file.read_exact(&mut header)?;
-    //Check for FLAC with ID3
-    if &header[0..3] == b"ID3" {
-        ui::print_warning(&format!("FLAC with ID3 tags are not supported, and should not be used. Consider converting this track metadata to Vorbis! {}", path));
-        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "FLAC ID3 not supported!").into());
-    }
-    //Check if FLAC
-    if &header != b"fLaC" {
-        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Not a valid FLAC!").into());
-    }
-    file.seek(SeekFrom::Start(0))?;
-    //Load tag
-    let tag = metaflac::Tag::read_from(&mut file)?;
-    let vorbis = tag.vorbis_comments().ok_or("Missing Vorbis Comments!")?;
-    //Parse artists
-    let artists = match vorbis.artist().ok_or("Missing artists!")?.len() {
-        //No artists
-        0 => None.ok_or("Missing artists!")?,
-        //Single artist tag - manually parse
-        1 => parse_artist_tag(vorbis.artist().unwrap().first().unwrap()),
-        //Multiple artist tags = don't parse
-        _ => vorbis.artist().unwrap().to_owned()
+    let (title, artists) = match cue.as_ref() {
+        Some(cue) => (
+            cue.title.to_owned().or_else(|| tag.get(&Field::Album).and_then(|t| t.into_iter().next())).ok_or("Missing album title in cue sheet!")?,
+            match cue.performer.as_ref() {
+                Some(performer) => parse_artist_tag(performer),
+                None => split_artists(tag.get(&Field::Artist).unwrap_or_default())
+            }
+        ),
+        None => (
+            tag.get(&Field::Title).and_then(|t| t.into_iter().next()).ok_or("Missing title tag!")?,
+            split_artists(tag.get(&Field::Artist).unwrap_or_default())
+        )
     };
+    if artists.is_empty() {
+        return Err("Missing artist tag!".into());
+    }
+    //Broken headers shouldn't prevent tagging, only MP3 is always lossy so others aren't skipped by --losslessOnly
+    let properties = backend.properties(path).unwrap_or_else(|e| {
+        eprintln!("Failed reading audio properties of {}: {}", path, e);
+        AudioProperties::new("Unknown", backend.file_type() != MusicFileType::MP3)
+    });
 
     Ok(MusicFileInfo {
         path: path.to_owned(),
-        title: vorbis.title().ok_or("Missing title!")?.first().ok_or("Missing title!")?.to_owned(),
+        title,
         artists,
-        tag: MusicFileType::FLAC
+        tag: backend.file_type(),
+        properties,
+        cue,
+        skip: skip::check_tag(tag.as_ref(), config)
     })
 }
 
-//Wrapper to write tags by format
-pub fn write_tag(discogs: &mut Discogs, config: &TaggerConfig, info: &MusicFileInfo, release: &ReleaseMaster, track: &Track) -> Result<(), Box<dyn std::error::Error>> {
-    //Get tag by type
-    let mut tag = match info.tag {
-        MusicFileType::FLAC => return write_flac_tag(discogs, config, &info.path, release, track),
-        MusicFileType::MP3 => {
-            Tag::read_from_path(&info.path)?
-        },
-        MusicFileType::AIFF => {
-            Tag::read_from_aiff(&info.path)?
+//Single file album, fills cue sheet and album fields of file
+pub fn write_album(discogs: &mut Discogs, config: &TaggerConfig, info: &MusicFileInfo, release: &ReleaseMaster, mut journal: Option<&mut Journal>) -> Result<FileDiff, Box<dyn std::error::Error>> {
+    let mut cue = info.cue.clone().ok_or("Missing cue sheet!")?;
+    cue.fill(&info.path, release, config);
+    //Codepage of older cue sheets is unknown, new values couldn't be encoded
+    if !cue.utf8 {
+        ui::print_warning(&format!("Cue sheet isn't UTF-8, not updating it: {}", cue.path));
+    } else if !config.dry_run {
+        if let Some(journal) = journal.as_mut() {
+            journal.record(&cue.path)?;
         }
-    };
-    //Write
-    write_id3_tag(&mut tag, discogs, config, release, track)?;
-    let version = match config.id3v23 {
-        true => Version::Id3v23,
-        false => Version::Id3v24
-    };
-    //Save
-    match info.tag {
-        MusicFileType::MP3 => tag.write_to_path(&info.path, version)?,
-        MusicFileType::AIFF => tag.write_to_aiff(&info.path, version)?,
-        //Shouldn't happen
-        MusicFileType::FLAC => {}
-    };
+        cue.save(config)?;
+        if let Some(journal) = journal.as_mut() {
+            journal.done(&cue.path)?;
+        }
+    }
 
-    Ok(())
+    let mut tag = DiffTag::new(backend::for_type(&info.tag).read(&info.path, config)?);
+    tag.migrate();
+    if let Some(artists) = release.artists.as_ref() {
+        write_artists(&mut tag, config, artists);
+    }
+    write_album_fields(&mut tag, discogs, config, release);
+    //Embedded cue sheet with per track comments
+    if let (Some(file), true) = (cue.file(&info.path), config.cue_embed) {
+        for track in &file.tracks {
+            let values = vec![("TITLE", track.title.as_ref()), ("PERFORMER", track.performer.as_ref())];
+            for (name, value) in values.into_iter().filter_map(|(n, v)| v.map(|v| (n, v))) {
+                let field = Field::Custom { id3: None, vorbis: Some(format!("CUE_TRACK{:02}_{}", track.number, name)) };
+                tag.set(&field, vec![value.to_owned()]);
+            }
+        }
+        if !tag.set_cue(file) {
+            eprintln!("Embedded CUESHEET is supported only in FLAC: {}", info.path);
+        }
+    }
+    if !config.dry_run {
+        save_tag(&mut tag, &info.path, config, journal)?;
+    }
+    Ok(tag.into_diff(&info.path))
 }
 
-fn write_flac_tag(discogs: &mut Discogs, config: &TaggerConfig, path: &str, release: &ReleaseMaster, track: &Track) -> Result<(), Box<dyn std::error::Error>> {
-    let mut tag = metaflac::Tag::read_from_path(path)?;
-    let vorbis = tag.vorbis_comments_mut();
+//Returns changed fields, dry run = don't save
+pub fn write_tag(discogs: &mut Discogs, config: &TaggerConfig, info: &MusicFileInfo, release: &ReleaseMaster, track: &Track, journal: Option<&mut Journal>) -> Result<FileDiff, Box<dyn std::error::Error>> {
+    let mut tag = DiffTag::new(backend::for_type(&info.tag).read(&info.path, config)?);
+    tag.migrate();
+    write_fields(&mut tag, discogs, config, release, track);
+    if !config.dry_run {
+        save_tag(&mut tag, &info.path, config, journal)?;
+    }
+    Ok(tag.into_diff(&info.path))
+}
 
-    //Tags
-    if config.title && config.overwrite {
-        vorbis.set_title(vec![track.title.to_owned()]);
+//Original tags go to journal before anything is written
+fn save_tag(tag: &mut DiffTag, path: &str, config: &TaggerConfig, journal: Option<&mut Journal>) -> Result<(), Box<dyn std::error::Error>> {
+    match journal {
+        Some(journal) => {
+            journal.record(path)?;
+            backend::save(tag, path, config)?;
+            journal.done(path)
+        },
+        None => backend::save(tag, path, config)
     }
-    if config.album && (config.overwrite || vorbis.album().is_none()) {
-        vorbis.set_album(vec![release.title.to_owned()]);
+}
+
+//Write Discogs data according to policies, same for every format
+fn write_fields(tag: &mut dyn AudioTag, discogs: &mut Discogs, config: &TaggerConfig, release: &ReleaseMaster, track: &Track) {
+    //Tags
+    write_single(tag, Field::Title, config.title, track.title.to_owned());
+    write_artists(tag, config, track.artists.as_ref().unwrap_or_else(|| release.artists.as_ref().unwrap()));
+    write_album_fields(tag, discogs, config, release);
+    write_single(tag, Field::Track, config.track, track.position_int.to_string());
+    //Custom
+    let data = TemplateData::new(release, track, config);
+    for mapping in &config.mapping {
+        if let Some(value) = data.render(&mapping.template) {
+            let field = Field::Custom { id3: mapping.id3.to_owned(), vorbis: mapping.vorbis.to_owned() };
+            write_single(tag, field, mapping.policy, value);
+        }
     }
-    if config.artist && config.overwrite {
-        vorbis.set_artist(track.artists.as_ref().unwrap_or_else(|| release.artists.as_ref().unwrap())
-            .iter().map(|a| clean_discogs_artist(a)).collect::<Vec<String>>());
+}
+
+fn write_artists(tag: &mut dyn AudioTag, config: &TaggerConfig, artists: &[String]) {
+    let artists = artists.iter().map(|a| clean_discogs_artist(a)).collect::<Vec<String>>();
+    if let Some(artists) = config.artist.apply(split_artists(tag.get(&Field::Artist).unwrap_or_default()), artists) {
+        tag.set(&Field::Artist, artists);
     }
-    if config.label && release.label.is_some() && !release.label.as_ref().unwrap().is_empty() && (config.overwrite || vorbis.get("LABEL").is_none()) {
-        vorbis.set("LABEL", vec![clean_discogs_artist(release.label.as_ref().unwrap().first().unwrap())]);
+}
+
+//Fields shared by all tracks of release
+fn write_album_fields(tag: &mut dyn AudioTag, discogs: &mut Discogs, config: &TaggerConfig, release: &ReleaseMaster) {
+    write_single(tag, Field::Album, config.album, release.title.to_owned());
+    write_single(tag, skip::provenance_field(), config.provenance, release.id.to_string());
+    let labels = release_labels(release, config);
+    if !labels.is_empty() {
+        let labels = match config.label_separator.as_ref() {
+            Some(separator) => vec![labels.join(separator)],
+            None => labels
+        };
+        write_multi(tag, Field::Label, config.label, labels);
     }
-    if config.date && (config.overwrite || vorbis.get("DATE").is_none()) {
-        if release.released.is_some() {
-            vorbis.set("DATE", vec![release.released.to_owned().unwrap()]);
-        } else {
-            if release.year.is_some() {
-                vorbis.set("DATE", vec![release.year.unwrap().to_string()]);
-            }
-        }
+    if let Some(date) = release.released.to_owned().or_else(|| release.year.map(|y| y.to_string())) {
+        write_single(tag, Field::Date, config.date, date);
     }
-    if config.flac_genre > 0 {
-        //Sort
-        let mut genres = release.genres.clone();
-        let mut styles = release.styles.clone();
-        genres.sort();
-        styles.sort();
-        //Write
-        //Genre
-        if (config.flac_genre == 1 || config.flac_genre == 3) && (config.overwrite || vorbis.get("GENRE").is_none()) {
-            vorbis.set("GENRE", genres.clone());
-        }
-        //Styles in genre
-        if config.flac_genre == 2 && (config.overwrite || vorbis.get("GENRE").is_none()) {
-            vorbis.set("GENRE", styles.clone());
-        }
-        //Style
-        if config.flac_genre == 1 && (config.overwrite || vorbis.get("STYLE").is_none()) {
-            vorbis.set("STYLE", styles.clone());
-        }
-        //Both
-        if config.flac_genre == 4 && (config.overwrite || vorbis.get("GENRE").is_none()) {
+
+    //Sort
+    let mut genres = release.genres.clone();
+    let mut styles = release.styles.clone();
+    genres.sort();
+    styles.sort();
+    let values = match tag.genre_mode(config) {
+        GenreMode::None => vec![],
+        GenreMode::Style => vec![(Field::Genre, styles)],
+        GenreMode::Genre => vec![(Field::Genre, genres)],
+        GenreMode::Merge => {
             genres.append(&mut styles);
             genres.sort();
-            vorbis.set("GENRE", genres);
-        }
-    }
-
-    if config.track && (config.overwrite || vorbis.track().is_none()) {
-        vorbis.set_track(track.position_int as u32);
+            vec![(Field::Genre, genres)]
+        },
+        GenreMode::Both => vec![(Field::Genre, genres), (Field::Style, styles)]
+    };
+    for (field, values) in values {
+        write_multi(tag, field, config.genre, values);
     }
     //Art
-    if config.art && release.art_url.is_some() && (config.overwrite || tag.pictures().count() == 0) {
-        match discogs.download_art(release.art_url.as_ref().unwrap()) {
-            Ok(data) => {
-                tag.remove_picture_type(FLACPictureType::CoverFront);
-                tag.add_picture("image/jpeg", FLACPictureType::CoverFront, data);
-            },
-            Err(_) => eprintln!("Error downloading album art, ignoring!")
+    if let Some(url) = release.art_url.as_ref() {
+        if config.art.should_write(tag.has_art()) {
+            if let Some(art) = download_art(discogs, url, config) {
+                tag.set_art(art);
+            }
         }
     }
+}
 
-    //Save
-    tag.save()?;
-    Ok(())
+//Single value field
+fn write_single(tag: &mut dyn AudioTag, field: Field, policy: WritePolicy, value: String) {
+    if policy.should_write(tag.get(&field).is_some()) {
+        tag.set(&field, vec![value]);
+    }
 }
 
-fn write_id3_tag(tag: &mut Tag, discogs: &mut Discogs, config: &TaggerConfig, release: &ReleaseMaster, track: &Track) -> Result<(), Box<dyn std::error::Error>> {
-    //Tags
-    if config.title && config.overwrite {
-        tag.set_title(&track.title);
-    }
-    if config.album && (config.overwrite || tag.album().is_none()) {
-        tag.set_album(&release.title);
-    }
-    if config.artist && config.overwrite {
-        tag.set_artist(track.artists.as_ref().unwrap_or_else(|| release.artists.as_ref().unwrap())
-            .iter().map(|a| clean_discogs_artist(a)).collect::<Vec<String>>().join(&config.artist_separator));
-    }
-    if config.label && release.label.is_some() && !release.label.as_ref().unwrap().is_empty() && (config.overwrite || tag.get("TPUB").is_none()) {
-        tag.set_text("TPUB", clean_discogs_artist(release.label.as_ref().unwrap().first().unwrap()));
-    }
-    if config.date && release.year.is_some() {
-        //Parse date
-        let mut month = None;
-        let mut day = None;
-        if release.released.is_some() && release.released.as_ref().unwrap().len() == 10 {
-            if let Ok(d) = NaiveDate::parse_from_str(release.released.as_ref().unwrap(), "%Y-%m-%d") {
-                month = Some(d.month() as u8);
-                day = Some(d.day() as u8);
-            }
+//Multi value field
+fn write_multi(tag: &mut dyn AudioTag, field: Field, policy: WritePolicy, values: Vec<String>) {
+    if let Some(values) = policy.apply(tag.get(&field).unwrap_or_default(), values) {
+        tag.set(&field, values);
+    }
+}
+
+//Download and process album art, None on error
+fn download_art(discogs: &mut Discogs, url: &str, config: &TaggerConfig) -> Option<Art> {
+    let data = match discogs.download_art(url) {
+        Ok(data) => data,
+        Err(_) => {
+            eprintln!("Error downloading album art, ignoring!");
+            return None;
         }
-        //ID3v2.4
-        if !config.id3v23 && (config.overwrite || tag.date_recorded().is_none()) {
-            //Remove ID3v2.3
-            tag.remove("TDAT");
-            tag.remove("TYER");
-
-            tag.set_date_recorded(Timestamp {
-                year: release.year.unwrap() as i32,
-                month,
-                day,
-                hour: None,
-                minute: None,
-                second: None
-            });
+    };
+    match art::process_art(data, &config.art_options) {
+        Ok(Some(art)) => Some(art),
+        Ok(None) => {
+            eprintln!("Album art is too small, ignoring!");
+            None
+        },
+        Err(e) => {
+            eprintln!("Error processing album art, ignoring! {}", e);
+            None
         }
+    }
+}
 
-        //ID3v2.3
-        if config.id3v23 {
-            //Date
-            if (config.overwrite || tag.get("TDAT").is_none()) && (month.is_some() && day.is_some()) {
-                tag.remove_date_recorded();
-                tag.set_text("TDAT", &format!("{:02}{:02}", day.unwrap(), month.unwrap()));
-            }
-            //Year
-            if config.overwrite || tag.get("TYER").is_none() {
-                tag.remove_date_recorded();
-                tag.set_text("TYER", release.year.unwrap().to_string());
-            }
+//Get cleaned up labels
+pub fn release_labels(release: &ReleaseMaster, config: &TaggerConfig) -> Vec<String> {
+    let placeholder = Regex::new(r"(?i)^(not on label|no label|none$|self[- ]released)").unwrap();
+    let mut labels: Vec<String> = vec![];
+    for label in release.label.as_deref().unwrap_or(&[]) {
+        let label = match config.strip_label_suffix {
+            true => clean_discogs_artist(label),
+            false => label.to_owned()
+        };
+        if config.drop_placeholder_labels && placeholder.is_match(&label) {
+            continue;
         }
-
-    }
-    if config.id3_genre > 0 && (config.overwrite || tag.genre().is_none()) {
-        match config.id3_genre {
-            //Only style
-            1 => {
-                let mut styles = release.styles.clone();
-                styles.sort();
-                tag.set_genre(styles.join(", "));
-            },
-            //Only genre
-            2 => {
-                let mut genres = release.genres.clone();
-                genres.sort();
-                tag.set_genre(genres.join(", "));
-            },
-            //Merge 
-            3 => {
-                let mut styles = release.styles.clone();
-                styles.append(&mut release.genres.clone());
-                styles.sort();
-                tag.set_genre(styles.join(", "));
-            },
-            _ => {}
+        //Same label is listed for each catalog number
+        if !labels.contains(&label) {
+            labels.push(label);
         }
     }
-    if config.track && (config.overwrite || tag.track().is_none()) {
-        tag.set_track(track.position_int as u32);
+    if config.primary_label_only {
+        labels.truncate(1);
     }
-    //Art
-    if config.art && release.art_url.is_some() && (config.overwrite || tag.pictures().count() == 0) {
-        match discogs.download_art(release.art_url.as_ref().unwrap()) {
-            Ok(data) => {
-                tag.remove_picture_by_type(ID3PictureType::CoverFront);
-                tag.add_picture(Picture {
-                    mime_type: "image/jpeg".to_string(),
-                    picture_type: ID3PictureType::CoverFront,
-                    description: "Cover".to_string(),
-                    data
-                });
-            },
-            Err(_) => eprintln!("Error downloading album art, ignoring!")
-        }
-    }
-
-    Ok(())
+    labels
 }
 
-fn clean_discogs_artist(name: &str) -> String {
+pub fn clean_discogs_artist(name: &str) -> String {
     let re = Regex::new(r" \(\d{1,2}\)$").unwrap();
     re.replace(name, "").to_string()
 }
 
+//Single value = try to split, multiple values = don't parse
+fn split_artists(values: Vec<String>) -> Vec<String> {
+    match values.len() {
+        1 => parse_artist_tag(&values[0]),
+        _ => values
+    }
+}
+
 //Try to split artist string with common separators
-fn parse_artist_tag(src: &str) -> Vec<String> {
+pub fn parse_artist_tag(src: &str) -> Vec<String> {
     if src.contains(';') {
         return src.split(';').collect::<Vec<&str>>().into_iter().map(|v| v.to_owned()).collect();
     }
@@ -435,3 +738,122 @@ fn parse_artist_tag(src: &str) -> Vec<String> {
     }
     vec![src.to_owned()]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::test_util::{MemoryTag, values};
+
+    //Changes recorded by writing Genre and Date with policy
+    fn write_with(policy: WritePolicy) -> Vec<(String, Vec<String>, Vec<String>)> {
+        let mut tag = DiffTag::new(Box::new(MemoryTag::new(&[("Genre", &["Pop"])])));
+        write_multi(&mut tag, Field::Genre, policy, values(&["pop", "Rock"]));
+        write_single(&mut tag, Field::Date, policy, String::from("2001"));
+        tag.into_diff("song.mp3").changes.into_iter().map(|c| (c.field, c.before, c.after)).collect()
+    }
+
+    #[test]
+    fn write_policy_should_write() {
+        assert!(!WritePolicy::Never.should_write(false));
+        assert!(WritePolicy::FillEmpty.should_write(false));
+        assert!(!WritePolicy::FillEmpty.should_write(true));
+        assert!(!WritePolicy::Merge.should_write(true));
+        assert!(WritePolicy::Overwrite.should_write(true));
+    }
+
+    #[test]
+    fn write_policy_apply() {
+        assert_eq!(WritePolicy::Never.apply(vec![], values(&["Rock"])), None);
+        assert_eq!(WritePolicy::FillEmpty.apply(vec![], values(&["Rock"])), Some(values(&["Rock"])));
+        assert_eq!(WritePolicy::FillEmpty.apply(values(&["Pop"]), values(&["Rock"])), None);
+        assert_eq!(WritePolicy::Overwrite.apply(values(&["Pop"]), values(&["Rock"])), Some(values(&["Rock"])));
+        //Case insensitive, empty existing values dropped
+        assert_eq!(
+            WritePolicy::Merge.apply(values(&["Pop ", ""]), values(&["pop", "Rock"])),
+            Some(values(&["Pop", "Rock"]))
+        );
+    }
+
+    #[test]
+    fn write_policy_from_json() {
+        assert_eq!(WritePolicy::from_json(&json!(true), false), Ok(WritePolicy::FillEmpty));
+        assert_eq!(WritePolicy::from_json(&json!(true), true), Ok(WritePolicy::Overwrite));
+        assert_eq!(WritePolicy::from_json(&json!(null), true), Ok(WritePolicy::Never));
+        assert_eq!(WritePolicy::from_json(&json!("Merge"), false), Ok(WritePolicy::Merge));
+        assert!(WritePolicy::from_json(&json!("always"), false).is_err());
+        assert!(WritePolicy::from_json(&json!(1), false).is_err());
+    }
+
+    #[test]
+    fn config_rejects_out_of_range_numbers() {
+        assert_eq!(TaggerConfig::from_json(&json!({"fuzziness": "60"})).unwrap().fuzziness, 60);
+        assert!(TaggerConfig::from_json(&json!({"fuzziness": 300})).is_err());
+        assert!(TaggerConfig::from_json(&json!({"fuzziness": -1})).is_err());
+        assert!(TaggerConfig::from_json(&json!({"durationTolerance": -5})).is_err());
+        assert!(TaggerConfig::from_json(&json!({"id3Genre": 9})).is_err());
+    }
+
+    #[test]
+    fn provenance_policy() {
+        let provenance = |j: Value| TaggerConfig::from_json(&j).unwrap().provenance;
+        assert_eq!(provenance(json!({})), WritePolicy::Never);
+        assert_eq!(provenance(json!({"skip": "provenance,unchanged"})), WritePolicy::FillEmpty);
+        assert_eq!(provenance(json!({"skip": "provenance", "overwrite": true})), WritePolicy::Overwrite);
+        assert_eq!(provenance(json!({"skip": "provenance", "provenance": false})), WritePolicy::Never);
+        assert_eq!(provenance(json!({"provenance": "overwrite"})), WritePolicy::Overwrite);
+    }
+
+    fn release(json: Value) -> ReleaseMaster {
+        ReleaseMaster::from_json(json, ReleaseType::Release, None).unwrap()
+    }
+
+    #[test]
+    fn release_labels_options() {
+        let full = release(json!({"title": "Album", "id": 1, "labels": [
+            {"name": "Warp (2)", "catno": "WARP1"},
+            {"name": "Warp (2)", "catno": "WARP1X"},
+            {"name": "Not On Label (Artist Self-released)", "catno": "none"},
+            {"name": "Bleep", "catno": "B1"}
+        ]}));
+        let labels = |config: Value| release_labels(&full, &TaggerConfig::from_json(&config).unwrap());
+        //Primary by default
+        assert_eq!(labels(json!({})), values(&["Warp"]));
+        //Same label with multiple catalog numbers is listed once
+        assert_eq!(labels(json!({"labels": "all"})), values(&["Warp", "Not On Label (Artist Self-released)", "Bleep"]));
+        assert_eq!(labels(json!({"labels": "all", "dropPlaceholderLabels": true})), values(&["Warp", "Bleep"]));
+        assert_eq!(labels(json!({"stripLabelSuffix": false})), values(&["Warp (2)"]));
+        //Placeholder isn't primary
+        let placeholder_first = release(json!({"title": "Album", "id": 1, "labels": [{"name": "No Label", "catno": "none"}, {"name": "Bleep", "catno": "B1"}]}));
+        assert_eq!(release_labels(&placeholder_first, &TaggerConfig::from_json(&json!({"dropPlaceholderLabels": true})).unwrap()), values(&["Bleep"]));
+        let no_labels = release(json!({"title": "Album", "id": 1}));
+        assert!(release_labels(&no_labels, &TaggerConfig::from_json(&json!({})).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn release_catno() {
+        //Full release = catalog number of first label
+        let full = release(json!({"title": "Album", "id": 1, "labels": [{"name": "Warp", "catno": "WARP1"}, {"name": "Bleep", "catno": "B1"}]}));
+        assert_eq!(full.catno.as_deref(), Some("WARP1"));
+        //Search result has label names and catno directly
+        let search = release(json!({"title": "Album", "id": 1, "label": ["Warp", "Bleep"], "catno": "WARP1X"}));
+        assert_eq!(search.catno.as_deref(), Some("WARP1X"));
+        assert_eq!(release_labels(&search, &TaggerConfig::from_json(&json!({"labels": "all"})).unwrap()), values(&["Warp", "Bleep"]));
+    }
+
+    #[test]
+    fn recorded_changes() {
+        assert!(write_with(WritePolicy::Never).is_empty());
+        assert_eq!(write_with(WritePolicy::FillEmpty), vec![
+            (String::from("Date"), vec![], values(&["2001"]))
+        ]);
+        assert_eq!(write_with(WritePolicy::Merge), vec![
+            (String::from("Genre"), values(&["Pop"]), values(&["Pop", "Rock"])),
+            (String::from("Date"), vec![], values(&["2001"]))
+        ]);
+        assert_eq!(write_with(WritePolicy::Overwrite), vec![
+            (String::from("Genre"), values(&["Pop"]), values(&["pop", "Rock"])),
+            (String::from("Date"), vec![], values(&["2001"]))
+        ]);
+    }
+}
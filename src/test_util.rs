@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::art::Art;
+use crate::backend::{AudioTag, Field};
+use crate::diff::FieldChange;
+
+//Tests run in parallel, every folder has own number
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+//Folder in system temp for test files, deleted when dropped
+pub struct TempDir {
+    path: PathBuf
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let path = std::env::temp_dir().join(format!("discogstagger-{}-{}-{}", std::process::id(), n, name));
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    //Path of file in folder
+    pub fn file(&self, name: &str) -> String {
+        self.path.join(name).to_str().unwrap().to_owned()
+    }
+
+    //Create file with data, returns path
+    pub fn write(&self, name: &str, data: impl AsRef<[u8]>) -> String {
+        let path = self.file(name);
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.path).ok();
+    }
+}
+
+pub fn values(v: &[&str]) -> Vec<String> {
+    v.iter().map(|s| s.to_string()).collect()
+}
+
+//Tag kept in memory by field name, migrate renames YEAR to Date like ID3 version conversion
+#[derive(Default)]
+pub struct MemoryTag {
+    pub values: HashMap<String, Vec<String>>,
+    pub art: bool
+}
+
+impl MemoryTag {
+    pub fn new(fields: &[(&str, &[&str])]) -> MemoryTag {
+        MemoryTag { values: fields.iter().map(|(k, v)| (k.to_string(), values(v))).collect(), art: false }
+    }
+}
+
+impl AudioTag for MemoryTag {
+    fn get(&self, field: &Field) -> Option<Vec<String>> {
+        self.values.get(&field.name()).cloned()
+    }
+
+    fn set(&mut self, field: &Field, values: Vec<String>) {
+        match values.is_empty() {
+            true => self.values.remove(&field.name()),
+            false => self.values.insert(field.name(), values)
+        };
+    }
+
+    fn has_art(&self) -> bool {
+        self.art
+    }
+
+    fn set_art(&mut self, _art: Art) {
+        self.art = true;
+    }
+
+    fn remove_art(&mut self) {
+        self.art = false;
+    }
+
+    fn save(&mut self, _path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn migrate(&mut self) -> Vec<FieldChange> {
+        match self.values.remove("YEAR") {
+            Some(year) => {
+                self.values.insert(String::from("Date"), year.clone());
+                vec![
+                    FieldChange { field: String::from("YEAR"), before: year.clone(), after: vec![] },
+                    FieldChange { field: String::from("Date"), before: vec![], after: year }
+                ]
+            },
+            None => vec![]
+        }
+    }
+}
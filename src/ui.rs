@@ -1,20 +1,24 @@
 extern crate web_view;
 extern crate tinyfiledialogs;
 
-use serde_json::Value;
+use serde_json::{json, Value};
 use std::path::Path;
 use std::net::TcpListener;
 use std::thread;
 use tungstenite::server::accept;
 use tungstenite::Message;
-use std::io::{stdout, Write};
+use std::io::{stdout, Write, ErrorKind};
 use crossterm::execute;
 use crossterm::style::{Color, Print, ResetColor, SetForegroundColor, SetAttribute, Attribute};
 use std::time::{SystemTime, Duration};
-use std::fs::File;
 
 use crate::tagger;
-use crate::discogs::Discogs;
+use crate::runner;
+use crate::journal;
+use crate::state;
+use crate::diff::FileDiff;
+use crate::skip::SkipCounts;
+use crate::watch::Watcher;
 
 pub fn start_ui() {
     //Check if token is saved
@@ -84,106 +88,107 @@ fn process_message(text: &str, websocket: &mut tungstenite::WebSocket<std::net::
         "start" => {
             println!("Starting...\n");
             
-            let config_data = json["config"].as_object().unwrap();
+            let config_data = &json["config"];
             //Check path
-            let path = config_data["path"].as_str().unwrap();
+            let path = config_data["path"].as_str().ok_or("Missing path!")?;
             if !Path::new(path).is_dir() {
                 return Err(String::from("Invalid path!"));
             }
             //Load config
-            let config = tagger::TaggerConfig {
-                title: config_data["title"].as_bool().unwrap(),
-                artist: config_data["artist"].as_bool().unwrap(),
-                track: config_data["track"].as_bool().unwrap(),
-                album: config_data["album"].as_bool().unwrap(),
-                date: config_data["date"].as_bool().unwrap(),
-                label: config_data["label"].as_bool().unwrap(),
-                artist_separator: String::from(config_data["separator"].as_str().unwrap()),
-                fuzziness: config_data["fuzziness"].as_str().unwrap_or("80").parse().unwrap_or(80) as u8,
-                art: config_data["art"].as_bool().unwrap(),
-                overwrite: config_data["overwrite"].as_bool().unwrap(),
-                id3v23: config_data["id3v23"].as_bool().unwrap(),
-                id3_genre: config_data["id3Genre"].as_i64().unwrap() as i8,
-                flac_genre: config_data["flacGenre"].as_i64().unwrap() as i8,
-            };
+            let config = tagger::TaggerConfig::from_json(config_data)?;
+            //Ask UI to resume interrupted run, it starts again with resume set
+            if let (None, Some(entries)) = (config.resume, state::unfinished(path)) {
+                let msg = json!({ "action": "resume", "done": entries.len() });
+                websocket.write_message(Message::from(msg.to_string())).ok();
+                return Ok(());
+            }
             //Create discogs
-            match Discogs::new() {
-                Ok(d) => {
-                    let mut discogs = d;
-                    //Authorize if token available
-                    match config_data["token"].as_str() {
-                        Some(v) => {
-                            if v.len() > 6 {
-                                discogs.authorize_token(String::from(v));
-                            }
-                        },
-                        None => {
-                            return Err(String::from("Enter token!"));
-                        }
-                    }
-                    //Set rate limiting
-                    discogs.rate_limit(true);
-                    //Check token
-                    let token_state = discogs.validate_token();
-                    if token_state.is_none() {
-                        return Err(String::from("Invalid token!"));
-                    }
-                    //Save token
-                    if let Ok(mut f) = File::create(".discogstoken") {
-                        f.write_all(discogs.token.as_ref().unwrap().as_bytes()).ok();
-                    }
+            let mut discogs = runner::create_discogs(config_data["token"].as_str())?;
 
-                    //Toggle button
-                    websocket.write_message(Message::from(r#"{"action": "button"}"#)).ok();
+            //Toggle button
+            websocket.write_message(Message::from(r#"{"action": "button"}"#)).ok();
 
-                    let mut ok = 0;
-                    let mut fail = 0;
-                    let ts_start = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_else(|_| Duration::from_millis(0)).as_secs();
+            runner::run(&mut discogs, &config, path, |ok, fail, skipped, total, diff| {
+                send_progress(websocket, &config, ok, fail, skipped, total, diff);
+            });
 
-                    //Load files
-                    let files = tagger::get_files(path);
-                    let total = files.len();
-                    for file in files {
-                        match tagger::match_track(&mut discogs, &file, config.fuzziness) {
-                            Ok(o) => {
-                                match o {
-                                    Some((track, release)) => {
-                                        //Write tag
-                                        match tagger::write_tag(&mut discogs, &config, &file, &release, &track) {
-                                            Ok(_) => {
-                                                ok += 1;
-                                                print_console(&file.path, Ok(()), ok, fail, total as i32);
-                                            },
-                                            Err(e) => {
-                                                fail += 1;
-                                                print_console(&file.path, Err(format!("Failed writing tag to file! {}", e)), ok, fail, total as i32);
-                                            }
-                                        }
-                                        
-                                    },
-                                    None => {
-                                        fail += 1;
-                                        print_console(&file.path, Err(String::from("No match!")), ok, fail, total as i32);
-                                    }
-                                }
-                            },
-                            Err(e) => {
-                                fail += 1;
-                                print_console(&file.path, Err(format!("Error matching! {}", e)), ok, fail, total as i32);
-                            }
-                        }
+            //Toggle button
+            websocket.write_message(Message::from(r#"{"action": "button"}"#)).ok();
+        },
+        //Tag again failed files of previous run, empty source = state file in folder
+        "retry" => {
+            let config_data = &json["config"];
+            let config = tagger::TaggerConfig::from_json(config_data)?;
+            let source = match config_data["retry"].as_str().filter(|s| !s.trim().is_empty()) {
+                Some(s) => s.to_owned(),
+                None => {
+                    let path = config_data["path"].as_str().ok_or("Missing report or state file to retry!")?;
+                    state::state_path(path).to_str().ok_or("Invalid state file path!")?.to_owned()
+                }
+            };
+            let mut discogs = runner::create_discogs(config_data["token"].as_str())?;
 
-                        //Update progress in UI
-                        let msg = format!(r#"{{"action": "progress", "total": {}, "ok": {}, "fail": {}}}"#, total, ok, fail);
-                        websocket.write_message(Message::from(msg)).ok();
+            println!("Starting...\n");
+            websocket.write_message(Message::from(r#"{"action": "button"}"#)).ok();
+            let result = runner::retry(&mut discogs, &config, &source, |ok, fail, skipped, total, diff| {
+                send_progress(websocket, &config, ok, fail, skipped, total, diff);
+            });
+            websocket.write_message(Message::from(r#"{"action": "button"}"#)).ok();
+            result?;
+        },
+        //Tag new files in folder until stopped
+        "watch" => {
+            let config_data = &json["config"];
+            let path = config_data["path"].as_str().map(|p| p.trim()).filter(|p| !p.is_empty()).ok_or("Missing folders to watch!")?;
+            if !Path::new(path).is_dir() {
+                return Err(String::from("Invalid path!"));
+            }
+            let config = tagger::TaggerConfig::from_json(config_data)?;
+            let mut discogs = runner::create_discogs(config_data["token"].as_str())?;
+
+            println!("Watching: {}\n", path);
+            let mut watcher = Watcher::new(vec![path.to_owned()], &config)?;
+            let (mut ok, mut fail) = (0, 0);
+            //Wait for stop message between scans
+            websocket.get_mut().set_read_timeout(Some(Duration::from_secs(config.watch_interval))).ok();
+            loop {
+                watcher.poll(&mut discogs, &config, |path, result| {
+                    match result {
+                        Ok(_) => ok += 1,
+                        Err(_) => fail += 1
                     }
-                    //Done
-                    print_console_done(ok, fail, total as i32, ts_start);
-                    //Toggle button
-                    websocket.write_message(Message::from(r#"{"action": "button"}"#)).ok();
+                    print_console(path, result.as_ref().map(|_| ()).map_err(String::from), ok, fail, ok + fail);
+                    let msg = json!({
+                        "action": "watch",
+                        "path": path,
+                        "ok": ok,
+                        "fail": fail,
+                        "error": result.as_ref().err(),
+                        "diff": result.as_ref().ok().map(|d| d.to_json())
+                    });
+                    websocket.write_message(Message::from(msg.to_string())).ok();
+                });
+                match websocket.read_message() {
+                    Ok(msg) => if serde_json::from_str::<Value>(msg.to_text().unwrap_or("")).map(|j| j["action"] == "stopWatch").unwrap_or(false) {
+                        break;
+                    },
+                    Err(tungstenite::Error::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {},
+                    Err(_) => break
                 }
-                Err(_) => return Err(String::from("Failed initializing Discogs!"))
-            };
+            }
+            websocket.get_mut().set_read_timeout(None).ok();
+            println!("Stopped watching: {}", path);
+            websocket.write_message(Message::from(r#"{"action": "watchStopped"}"#)).ok();
+        },
+        //Restore last run from journal
+        "undo" => {
+            let config = tagger::TaggerConfig::from_json(&json["config"])?;
+            let folder = json["config"]["path"].as_str().ok_or("Missing path!")?;
+            let path = journal::latest(&config.journal_folder(folder)).ok_or("No journal found!")?;
+            println!("Restoring tags from: {}\n", path);
+            let (ok, fail) = journal::undo(&path, &[], false, &config).map_err(|e| format!("Failed reading journal! {}", e))?;
+            let msg = json!({ "action": "alert", "msg": format!("Restored: {}, failed or skipped: {}", ok, fail) });
+            websocket.write_message(Message::from(msg.to_string())).ok();
         },
         _ => {}
     };
@@ -191,6 +196,16 @@ fn process_message(text: &str, websocket: &mut tungstenite::WebSocket<std::net::
     Ok(())
 }
 
+//Update progress in UI, with dry run changes
+fn send_progress(websocket: &mut tungstenite::WebSocket<std::net::TcpStream>, config: &tagger::TaggerConfig, ok: i32, fail: i32, skipped: usize, total: usize, diff: Option<&FileDiff>) {
+    if let (Some(diff), true) = (diff, config.dry_run) {
+        let msg = format!(r#"{{"action": "diff", "diff": {}}}"#, diff.to_json());
+        websocket.write_message(Message::from(msg)).ok();
+    }
+    let msg = format!(r#"{{"action": "progress", "total": {}, "ok": {}, "fail": {}, "skipped": {}}}"#, total, ok, fail, skipped);
+    websocket.write_message(Message::from(msg)).ok();
+}
+
 //Print warning into console
 pub fn print_warning(text: &str) {
     execute!(
@@ -206,7 +221,7 @@ pub fn print_warning(text: &str) {
 }
 
 //Pretty print done messange
-fn print_console_done(ok: i32, fail: i32, total: i32, ts_start: u64) {
+pub fn print_console_done(ok: i32, fail: i32, skipped: &SkipCounts, total: i32, ts_start: u64) {
     let took = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::from_millis(0)).as_secs() - ts_start;
     let mut percent = 0;
     if total > 0 {
@@ -235,6 +250,16 @@ fn print_console_done(ok: i32, fail: i32, total: i32, ts_start: u64) {
         Print(format!("{}\n", fail)),
         SetAttribute(Attribute::Reset),
         ResetColor,
+        Print("Skipped: "),
+        SetForegroundColor(Color::DarkGrey),
+        SetAttribute(Attribute::Bold),
+        Print(format!("{}", skipped.total())),
+        SetAttribute(Attribute::Reset),
+        Print(match skipped.total() {
+            0 => String::from("\n"),
+            _ => format!(" ({})\n", skipped)
+        }),
+        ResetColor,
         Print("Total: "),
         SetForegroundColor(Color::Blue),
         SetAttribute(Attribute::Bold),
@@ -261,7 +286,7 @@ fn print_console_done(ok: i32, fail: i32, total: i32, ts_start: u64) {
 }
 
 //Pretty print in console
-fn print_console(path: &str, result: Result<(), String>, ok: i32, fail: i32, total: i32) {
+pub fn print_console(path: &str, result: Result<(), String>, ok: i32, fail: i32, total: i32) {
     //Calculate percent
     let mut percent = 0;
     if total > 0 {
@@ -348,3 +373,28 @@ fn print_console(path: &str, result: Result<(), String>, ok: i32, fail: i32, tot
         Print("\n")
     ).ok();
 }
+
+//Print before/after of changed fields
+pub fn print_diff(diff: &FileDiff) {
+    if !diff.has_changes() {
+        println!("    No changes");
+        return;
+    }
+    for change in diff.changes.iter().filter(|c| c.changed()) {
+        execute!(
+            stdout(),
+            Print(format!("    {}: ", change.field)),
+            SetForegroundColor(Color::Red),
+            Print(change.before.join(", ")),
+            ResetColor,
+            Print(" -> "),
+            SetForegroundColor(Color::Green),
+            Print(change.after.join(", ")),
+            ResetColor,
+            Print("\n")
+        ).ok();
+    }
+    if diff.art_before != diff.art_after {
+        println!("    Art: {} -> {}", if diff.art_before { "yes" } else { "no" }, if diff.art_after { "yes" } else { "no" });
+    }
+}
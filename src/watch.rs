@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::time::{Duration, Instant, SystemTime};
+use walkdir::WalkDir;
+use chrono::Local;
+
+use crate::tagger::{self, TaggerConfig};
+use crate::discogs::Discogs;
+use crate::diff::FileDiff;
+use crate::journal::Journal;
+use crate::backend;
+use crate::runner::{self, Tagged, TagError};
+use crate::report::{ErrorCategory, Report, ReportEntry};
+use crate::state::{RunState, StateEntry};
+use crate::skip::SkipCounts;
+use crate::properties::AudioProperties;
+use crate::organize::Organizer;
+use crate::cue::CueCache;
+use crate::ui;
+
+//Size and modification time
+type FileState = (u64, SystemTime);
+
+//Tags new or changed files in folders once they are copied
+pub struct Watcher {
+    folders: Vec<String>,
+    pending: Pending,
+    //State after processing, files present on start are ignored
+    done: HashMap<String, FileState>,
+    journal: Option<Journal>,
+    organizer: Option<Organizer>,
+    log: Option<File>,
+    run_state: Option<RunState>,
+    report: Report,
+    //Same report is rewritten after every file
+    report_path: Option<String>,
+    skipped: SkipCounts
+}
+
+impl Watcher {
+    pub fn new(folders: Vec<String>, config: &TaggerConfig) -> Result<Watcher, String> {
+        if folders.is_empty() {
+            return Err(String::from("Missing folders to watch!"));
+        }
+        let journal = match config.journal && !config.dry_run {
+            true => Journal::create(&config.journal_folder(&folders[0]))
+                .map_err(|e| ui::print_warning(&format!("Failed creating undo journal, changes can't be undone! {}", e)))
+                .ok(),
+            false => None
+        };
+        let log = OpenOptions::new().create(true).append(true).open(&config.watch_log)
+            .map_err(|e| ui::print_warning(&format!("Failed opening watch log! {}", e)))
+            .ok();
+        //Quarantine paths are relative to first folder
+        let organizer = Organizer::new(config, &folders[0]);
+        let run_state = match config.dry_run {
+            false => RunState::create(&folders[0], false)
+                .map_err(|e| ui::print_warning(&format!("Failed creating state file! {}", e)))
+                .ok(),
+            true => None
+        };
+        let report = Report::new(&folders[0]);
+        let mut watcher = Watcher {
+            folders, pending: Pending::default(), done: HashMap::new(), journal, organizer, log,
+            run_state, report, report_path: config.report_output.to_owned(), skipped: SkipCounts::default()
+        };
+        for path in watcher.scan() {
+            if let Some(state) = file_state(&path) {
+                watcher.done.insert(path, state);
+            }
+        }
+        Ok(watcher)
+    }
+
+    //Process files which didn't change for settle time, callback(path, result) for every processed file
+    pub fn poll(&mut self, discogs: &mut Discogs, config: &TaggerConfig, mut on_result: impl FnMut(&str, &Result<FileDiff, String>)) {
+        let settle = Duration::from_secs(config.watch_settle);
+        for path in self.scan() {
+            let state = match file_state(&path) {
+                Some(s) => s,
+                None => continue
+            };
+            if self.done.get(&path) == Some(&state) {
+                continue;
+            }
+            //Wait until file stops changing (copy finished)
+            if !self.pending.settled(&path, state, Instant::now(), settle) {
+                continue;
+            }
+
+            let (result, properties) = match self.process(discogs, config, &path) {
+                Some(r) => r,
+                None => {
+                    self.done.insert(path, state);
+                    continue;
+                }
+            };
+            let new_path = runner::organize(self.organizer.as_mut(), self.journal.as_mut(), config, &path, &result);
+            //Writing tag changes the file too, moved file shouldn't be processed again
+            for path in [&path, &new_path].iter() {
+                if let Some(state) = file_state(path) {
+                    self.done.insert(path.to_string(), state);
+                }
+            }
+            self.record(config, &path, &new_path, &result, properties.as_ref());
+            let result = result.map(|tagged| tagged.diff).map_err(|e| e.message);
+            self.write_log(&new_path, &result);
+            on_result(&new_path, &result);
+        }
+    }
+
+    //None = skipped
+    fn process(&mut self, discogs: &mut Discogs, config: &TaggerConfig, path: &str) -> Option<(Result<Tagged, TagError>, Option<AudioProperties>)> {
+        let info = match tagger::load_file_info(path, config, &mut CueCache::default()) {
+            Ok(info) => info,
+            Err(e) => return Some((Err(TagError::new(ErrorCategory::Invalid, &format!("Invalid track! {}", e))), None))
+        };
+        if config.lossless_only && !info.properties.lossless {
+            println!("Skipping lossy file ({}): {}", info.properties, path);
+            self.skipped.lossy += 1;
+            return None;
+        }
+        if let Some(policy) = info.skip {
+            println!("Skipping ({:?}): {}", policy, path);
+            self.skipped.add(policy);
+            return None;
+        }
+        Some((runner::tag_file(discogs, config, &info, self.journal.as_mut()), Some(info.properties)))
+    }
+
+    //State file and report, same as normal run
+    fn record(&mut self, config: &TaggerConfig, path: &str, new_path: &str, result: &Result<Tagged, TagError>, properties: Option<&AudioProperties>) {
+        if let Some(run_state) = self.run_state.as_mut() {
+            run_state.add(&StateEntry::new(new_path, result)).ok();
+        }
+        self.report.add(path, ReportEntry::new(new_path, result, properties));
+        if let Some(format) = config.report {
+            match self.report.save(format, self.report_path.as_deref(), &self.skipped) {
+                Ok(path) => self.report_path = Some(path),
+                Err(e) => ui::print_warning(&format!("Failed saving report! {}", e))
+            }
+        }
+    }
+
+    fn write_log(&mut self, path: &str, result: &Result<FileDiff, String>) {
+        if let Some(log) = self.log.as_mut() {
+            let status = match result {
+                Ok(_) => String::from("OK"),
+                Err(e) => e.to_owned()
+            };
+            writeln!(log, "{}\t{}\t{}", Local::now().format("%Y-%m-%d %H:%M:%S"), path, status).ok();
+        }
+    }
+
+    fn scan(&self) -> Vec<String> {
+        self.folders.iter()
+            .flat_map(|f| WalkDir::new(f).into_iter().filter_map(|e| e.ok()))
+            .filter(|e| e.file_type().is_file())
+            .filter_map(|e| e.path().to_str().map(String::from))
+            .filter(|p| backend::is_supported(p))
+            .collect()
+    }
+}
+
+//Files being copied and since when they are unchanged
+#[derive(Default)]
+struct Pending {
+    files: HashMap<String, (FileState, Instant)>
+}
+
+impl Pending {
+    //File didn't change for settle time, state change restarts waiting
+    fn settled(&mut self, path: &str, state: FileState, now: Instant, settle: Duration) -> bool {
+        match self.files.get(path) {
+            Some((pending, since)) if *pending == state => if now.duration_since(*since) < settle {
+                return false;
+            },
+            _ => {
+                self.files.insert(path.to_owned(), (state, now));
+                return false;
+            }
+        }
+        self.files.remove(path);
+        true
+    }
+}
+
+fn file_state(path: &str) -> Option<FileState> {
+    let meta = std::fs::metadata(path).ok()?;
+    Some((meta.len(), meta.modified().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn settle_time() {
+        let mut pending = Pending::default();
+        let start = Instant::now();
+        let settle = Duration::from_secs(10);
+        let state = (100, SystemTime::UNIX_EPOCH);
+        //First seen
+        assert!(!pending.settled("a.mp3", state, start, settle));
+        assert!(!pending.settled("a.mp3", state, start + Duration::from_secs(9), settle));
+        //Still being copied, waiting starts again
+        let copied = (200, SystemTime::UNIX_EPOCH);
+        assert!(!pending.settled("a.mp3", copied, start + Duration::from_secs(9), settle));
+        assert!(!pending.settled("a.mp3", copied, start + Duration::from_secs(18), settle));
+        assert!(pending.settled("a.mp3", copied, start + Duration::from_secs(19), settle));
+        //Processed file is forgotten
+        assert!(pending.files.is_empty());
+        //No settle time still waits for one scan
+        assert!(!pending.settled("b.mp3", state, start, Duration::from_secs(0)));
+        assert!(pending.settled("b.mp3", state, start, Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn no_folders() {
+        let config = TaggerConfig::from_json(&json!({})).unwrap();
+        assert!(Watcher::new(vec![], &config).is_err());
+    }
+}
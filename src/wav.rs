@@ -0,0 +1,183 @@
+use id3::{Tag, Version};
+use std::convert::TryInto;
+
+//RIFF subchunk
+struct Chunk {
+    id: [u8; 4],
+    data: Vec<u8>
+}
+
+//Read all chunks of RIFF/WAVE file
+fn read_chunks(path: &str) -> Result<Vec<Chunk>, Box<dyn std::error::Error>> {
+    let data = std::fs::read(path)?;
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err("Not a valid WAV!".into());
+    }
+    Ok(parse_chunks(&data[12..]))
+}
+
+//Parse chunks, broken size of last chunk is clamped to end of data
+fn parse_chunks(data: &[u8]) -> Vec<Chunk> {
+    let mut chunks = vec![];
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let id = data[pos..pos+4].try_into().unwrap();
+        let size = u32::from_le_bytes(data[pos+4..pos+8].try_into().unwrap()) as usize;
+        let end = (pos + 8 + size).min(data.len());
+        chunks.push(Chunk { id, data: data[pos+8..end].to_vec() });
+        //Chunks are padded to even size
+        pos = end + (size % 2);
+    }
+    chunks
+}
+
+fn write_chunks(path: &str, chunks: &[Chunk]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut body = b"WAVE".to_vec();
+    for chunk in chunks {
+        serialize_chunk(&mut body, &chunk.id, &chunk.data);
+    }
+    let mut out = b"RIFF".to_vec();
+    out.extend(&(body.len() as u32).to_le_bytes());
+    out.extend(body);
+    std::fs::write(path, out)?;
+    Ok(())
+}
+
+fn serialize_chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend(id);
+    out.extend(&(data.len() as u32).to_le_bytes());
+    out.extend(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+fn is_id3_chunk(chunk: &Chunk) -> bool {
+    &chunk.id == b"id3 " || &chunk.id == b"ID3 "
+}
+
+fn is_info_chunk(chunk: &Chunk) -> bool {
+    &chunk.id == b"LIST" && chunk.data.starts_with(b"INFO")
+}
+
+//Read ID3 tag from `id3 ` chunk, None if there is no tag
+pub fn read_id3(path: &str) -> Result<Option<Tag>, Box<dyn std::error::Error>> {
+    match read_chunks(path)?.iter().find(|c| is_id3_chunk(c)) {
+        Some(chunk) => Ok(Some(Tag::read_from(&chunk.data[..])?)),
+        None => Ok(None)
+    }
+}
+
+//New ID3 tag with fields from RIFF INFO, for files without ID3
+pub fn info_tag(path: &str) -> Result<Tag, Box<dyn std::error::Error>> {
+    let chunks = read_chunks(path)?;
+    let info = chunks.iter().find(|c| is_info_chunk(c)).map(|c| parse_chunks(&c.data[4..])).unwrap_or_default();
+    let mut tag = Tag::new();
+    if let Some(title) = info_text(&info, b"INAM") {
+        tag.set_title(title);
+    }
+    if let Some(artist) = info_text(&info, b"IART") {
+        tag.set_artist(artist);
+    }
+    if let Some(album) = info_text(&info, b"IPRD") {
+        tag.set_album(album);
+    }
+    Ok(tag)
+}
+
+//Save ID3 into `id3 ` chunk, optionally mirror basic fields to RIFF INFO
+pub fn write_wav(path: &str, tag: &Tag, version: Version, write_info: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut chunks = read_chunks(path)?;
+    let mut id3_data = vec![];
+    tag.write_to(&mut id3_data, version)?;
+    chunks.retain(|c| !is_id3_chunk(c));
+    chunks.push(Chunk { id: *b"id3 ", data: id3_data });
+
+    if write_info {
+        //Keep unknown INFO fields
+        let mut info = match chunks.iter().position(is_info_chunk) {
+            Some(i) => parse_chunks(&chunks.remove(i).data[4..]),
+            None => vec![]
+        };
+        let genre = tag.genre().map(|g| g.replace('\0', ", "));
+        let date = tag.date_recorded().map(|d| d.year.to_string()).or_else(|| tag.get("TYER").and_then(|f| f.content().text()).map(String::from));
+        let values = vec![
+            (b"INAM", tag.title().map(String::from)),
+            (b"IART", tag.artist().map(String::from)),
+            (b"IPRD", tag.album().map(String::from)),
+            (b"ICRD", date),
+            (b"IGNR", genre),
+            (b"ITRK", tag.track().map(|t| t.to_string()))
+        ];
+        for (id, value) in values {
+            if let Some(value) = value {
+                info.retain(|c| &c.id != id);
+                //Null terminated
+                let mut data = value.into_bytes();
+                data.push(0);
+                info.push(Chunk { id: *id, data });
+            }
+        }
+        let mut data = b"INFO".to_vec();
+        for chunk in info {
+            serialize_chunk(&mut data, &chunk.id, &chunk.data);
+        }
+        chunks.push(Chunk { id: *b"LIST", data });
+    }
+
+    write_chunks(path, &chunks)
+}
+
+fn info_text(info: &[Chunk], id: &[u8; 4]) -> Option<String> {
+    info.iter().find(|c| &c.id == id)
+        .map(|c| String::from_utf8_lossy(&c.data).trim_end_matches('\0').trim().to_owned())
+        .filter(|v| !v.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_round_trip() {
+        let mut data = vec![];
+        serialize_chunk(&mut data, b"fmt ", &[1, 2, 3, 4]);
+        //Odd size is padded
+        serialize_chunk(&mut data, b"id3 ", &[5, 6, 7]);
+        serialize_chunk(&mut data, b"data", &[8, 9]);
+        assert_eq!(data.len(), 3 * 8 + 4 + 4 + 2);
+        let chunks = parse_chunks(&data);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(&chunks[1].id, b"id3 ");
+        assert!(is_id3_chunk(&chunks[1]));
+        assert_eq!(chunks[1].data, vec![5, 6, 7]);
+        assert_eq!(chunks[2].data, vec![8, 9]);
+    }
+
+    #[test]
+    fn truncated_chunk_is_clamped() {
+        let mut data = vec![];
+        serialize_chunk(&mut data, b"fmt ", &[1, 2]);
+        data.extend(b"data");
+        data.extend(&100u32.to_le_bytes());
+        data.extend(&[1, 2, 3]);
+        let chunks = parse_chunks(&data);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].data, vec![1, 2, 3]);
+        //Incomplete header is ignored
+        assert_eq!(parse_chunks(&data[..14]).len(), 1);
+    }
+
+    #[test]
+    fn info_text_trims_null() {
+        let mut data = b"INFO".to_vec();
+        serialize_chunk(&mut data, b"INAM", b"Title\0");
+        serialize_chunk(&mut data, b"IART", b"\0");
+        let chunk = Chunk { id: *b"LIST", data };
+        assert!(is_info_chunk(&chunk));
+        let info = parse_chunks(&chunk.data[4..]);
+        assert_eq!(info_text(&info, b"INAM").as_deref(), Some("Title"));
+        assert_eq!(info_text(&info, b"IART"), None);
+        assert_eq!(info_text(&info, b"IPRD"), None);
+    }
+}